@@ -1,5 +1,14 @@
 //! This module contains blocking equivalents of the core types defined in this
 //! crate.
 
+use std::future::Future;
+
 mod index;
-pub use index::tree;
+pub use index::{tree, Builder, Index, Transaction};
+
+fn block_on<F, T>(future: F) -> T
+where
+    F: Future<Output = T>,
+{
+    async_std::task::block_on(future)
+}
@@ -3,4 +3,780 @@
 //! In normal usage, it would not be required to use these underlying types.
 //! They are exposed here so that can be reused in other crates.
 
+use crate::{
+    blocking::block_on,
+    download::DownloadTemplate,
+    git::Repository,
+    index::{
+        Builder as AsyncBuilder, Index as AsyncIndex, OpenOptions, Transaction as AsyncTransaction,
+    },
+    storage::Storage,
+    tree::{CorruptionError, NotFoundError, Operation, Report, TransactionError},
+    validate::Error as ValidationError,
+    Error, Record, WrappedResult,
+};
+use semver::{Version, VersionReq};
+use std::path::{Path, PathBuf};
+use url::Url;
+
 pub mod tree;
+use tree::Tree;
+
+/// A representation of a crates registry, backed by both a directory and a git
+/// repository on the filesystem.
+///
+/// This struct is essentially a thin wrapper around both an index [`Tree`] and
+/// a git [`Repository`].
+///
+/// It functions exactly the same way as a [`Tree`], except that all changes to
+/// the crates index are also committed to the git repository, which allows this
+/// to be synced to a remote.
+#[derive(Debug)]
+pub struct Index {
+    async_index: AsyncIndex,
+}
+
+/// A builder for initialising a new [`Index`]
+#[derive(Debug)]
+#[must_use]
+pub struct Builder<'a> {
+    async_builder: AsyncBuilder<'a>,
+}
+
+impl<'a> Builder<'a> {
+    /// Set the Url for the registry API.
+    ///
+    /// The API should implement the REST interface as defined in
+    /// [the Cargo book](https://doc.rust-lang.org/cargo/reference/registries.html)
+    pub fn api(mut self, api: Url) -> Self {
+        self.async_builder = self.async_builder.api(api);
+        self
+    }
+
+    /// Add a remote to the repository
+    pub fn origin(mut self, remote: Url) -> Self {
+        self.async_builder = self.async_builder.origin(remote);
+        self
+    }
+
+    /// Add an allowed registry.
+    ///
+    /// Crates in this registry are only allowed to have dependencies which are
+    /// also in this registry, or in one of the allowed registries.
+    ///
+    /// Add multiple registries my calling this method multiple times.
+    pub fn allowed_registry(mut self, registry: Url) -> Self {
+        self.async_builder = self.async_builder.allowed_registry(registry);
+        self
+    }
+
+    /// Add crates.io as an allowed registry.
+    ///
+    /// You will almost always want this, so this exists as a handy shortcut.
+    pub fn allow_crates_io(mut self) -> Self {
+        self.async_builder = self.async_builder.allow_crates_io();
+        self
+    }
+
+    /// Check the dependencies of inserted crates.
+    ///
+    /// When enabled, a [`Record`] is rejected if any of its dependencies from
+    /// this registry cannot be satisfied by a non-yanked version in the index,
+    /// or if any of its dependencies are from a registry which is not allowed.
+    ///
+    /// This setting is not stored in the index. To keep it when the index is
+    /// reopened, use [`OpenOptions::validate_dependencies`](crate::OpenOptions::validate_dependencies).
+    pub fn validate_dependencies(mut self) -> Self {
+        self.async_builder = self.async_builder.validate_dependencies();
+        self
+    }
+
+    /// Set the name of the remote which is pulled from and pushed to.
+    ///
+    /// See [`Builder::remote_name`](crate::Builder::remote_name) for details.
+    pub fn remote_name(mut self, name: impl Into<String>) -> Self {
+        self.async_builder = self.async_builder.remote_name(name);
+        self
+    }
+
+    /// Set the name of the branch which the index is committed to.
+    ///
+    /// See [`Builder::branch`](crate::Builder::branch) for details.
+    pub fn branch(mut self, name: impl Into<String>) -> Self {
+        self.async_builder = self.async_builder.branch(name);
+        self
+    }
+
+    /// Optionally set the username and email for the git repository
+    pub fn identity(mut self, username: &'a str, email: &'a str) -> Self {
+        self.async_builder = self.async_builder.identity(username, email);
+        self
+    }
+
+    /// Keep the index in sync with its origin, retrying rejected pushes up to
+    /// `retries` times.
+    ///
+    /// See [`Builder::sync`](crate::Builder::sync) for details.
+    pub fn sync(mut self, retries: u32) -> Self {
+        self.async_builder = self.async_builder.sync(retries);
+        self
+    }
+
+    /// Construct the [`Index`] with the given parameters.
+    ///
+    /// # Errors
+    ///
+    /// This method can fail if the root path doesn't exist, the filesystem
+    /// cannot be written to, or the download template is not valid.
+    pub fn build(self) -> Result<Index, Error> {
+        let async_index = block_on(self.async_builder.build())?;
+        Ok(Index { async_index })
+    }
+}
+
+impl Index {
+    /// Create a new index.
+    ///
+    /// The root path, and the URL for downloading .crate files is required.
+    /// Additional options can be set using the builder API (see
+    /// [`Builder`] for options).
+    ///
+    /// # Example
+    ///
+    /// ## Basic Config
+    /// ```no_run
+    /// use crate_index::blocking::Index;
+    /// # use crate_index::Error;
+    /// # fn main() -> Result<(), Error> {
+    /// let root = "/index";
+    /// let download = "https://my-crates-server.com/api/v1/crates/{crate}/{version}/download";
+    ///
+    /// let index = Index::initialise(root, download).build()?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    /// ## More Options
+    ///
+    /// ```no_run
+    /// use crate_index::{blocking::Index, Url};
+    /// # use crate_index::Error;
+    /// # fn main() -> Result<(), Error> {
+    /// let root = "/index";
+    /// let download = "https://my-crates-server.com/api/v1/crates/{crate}/{version}/download";
+    /// let origin = Url::parse("https://github.com/crates/index.git").unwrap();
+    ///
+    /// let index = Index::initialise(root, download)
+    ///     .api(Url::parse("https://my-crates-server.com/").unwrap())
+    ///     .allowed_registry(Url::parse("https://my-intranet:8080/index").unwrap())
+    ///     .allow_crates_io()
+    ///     .origin(origin)
+    ///     .build()?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn initialise<'a>(root: impl Into<PathBuf>, download: impl Into<String>) -> Builder<'a> {
+        let async_builder = AsyncIndex::initialise(root.into(), download);
+        Builder { async_builder }
+    }
+
+    /// Open an existing index at the given root path.
+    ///
+    /// # Example
+    /// ```no_run
+    /// use crate_index::blocking::Index;
+    /// # use crate_index::Error;
+    /// # fn main() -> Result<(), Error> {
+    /// let index = Index::open("/index")?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// This method can return an error if the filepath doesn't exist, can't be
+    /// read from, if the index is malformed, or if another process holds a lock
+    /// on the index.
+    pub fn open(root: impl Into<PathBuf>) -> Result<Self, Error> {
+        let async_index = block_on(AsyncIndex::open(root.into()))?;
+        Ok(Self { async_index })
+    }
+
+    /// Open an existing index at the given root path, with the given options.
+    ///
+    /// See [`Index::open_with`](crate::Index::open_with) for details.
+    ///
+    /// # Errors
+    ///
+    /// This method can return an error if the filepath doesn't exist, can't be
+    /// read from, if the index is malformed, or if another process holds a
+    /// conflicting lock on the index for longer than the timeout.
+    pub fn open_with(
+        root: impl Into<PathBuf>,
+        options: impl Into<OpenOptions>,
+    ) -> Result<Self, Error> {
+        let async_index = block_on(AsyncIndex::open_with(root.into(), options))?;
+        Ok(Self { async_index })
+    }
+
+    /// Open an existing index at the given root path, skipping any lines of
+    /// the index files which cannot be parsed.
+    ///
+    /// The lines which couldn't be parsed are returned alongside the index.
+    ///
+    /// # Errors
+    ///
+    /// This method can return an error if the filepath doesn't exist, can't be
+    /// read from, or if the config file or git repository is malformed.
+    pub fn open_lenient(root: impl Into<PathBuf>) -> Result<(Self, Vec<CorruptionError>), Error> {
+        let (async_index, errors) = block_on(AsyncIndex::open_lenient(root.into()))?;
+        Ok((Self { async_index }, errors))
+    }
+
+    /// Insert a crate [`Record`] into the index.
+    ///
+    /// # Errors
+    ///
+    /// ## Outer Error
+    ///
+    /// A critical error is returned if the filesystem cannot be read, or a git
+    /// error occurs
+    ///
+    /// ## Inner Error
+    ///
+    /// A [`ValidationError`] is returned if the crate record contains invalid
+    /// data.
+    pub fn insert(&mut self, record: Record) -> WrappedResult<(), ValidationError, Error> {
+        block_on(self.async_index.insert(record))
+    }
+
+    /// Insert a crate [`Record`] into the index, and store its `.crate` file.
+    ///
+    /// The file is stored before the record is committed, so the index never
+    /// refers to a file which doesn't exist. If the record is rejected, the
+    /// stored file is rolled back.
+    ///
+    /// # Errors
+    ///
+    /// ## Outer Error
+    ///
+    /// A critical error is returned if the filesystem cannot be read, a git
+    /// error occurs, or the file cannot be stored
+    ///
+    /// ## Inner Error
+    ///
+    /// A [`ValidationError`] is returned if the checksum of the `.crate` file
+    /// doesn't match the record, or the crate record contains invalid data.
+    pub fn insert_with_artifact<S>(
+        &mut self,
+        record: Record,
+        crate_file: &[u8],
+        storage: &S,
+    ) -> WrappedResult<(), ValidationError, Error>
+    where
+        S: Storage + ?Sized,
+    {
+        block_on(
+            self.async_index
+                .insert_with_artifact(record, crate_file, storage),
+        )
+    }
+
+    /// 'Yank' a [`Record`] in the index.
+    ///
+    /// A 'yanked' crate version should *not* be used as a dependency.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use crate_index::{blocking::Index, Error, tree::NotFoundError};
+    /// #
+    /// # fn main() -> Result<(), Error> {
+    /// #    let mut index = Index::initialise("root", "download")
+    /// #        .identity("dummy username", "dummy@email.com")
+    /// #        .build()
+    /// #        .unwrap();
+    /// #
+    /// let crate_name = "some-crate";
+    /// let version = "0.1.0".parse().unwrap();
+    ///
+    /// match index.yank(crate_name, &version)? {
+    ///     Ok(()) => println!("crate yanked!"),
+    ///     Err(NotFoundError::Crate(e)) => println!("crate not found! ({})", e.crate_name()),
+    ///     Err(NotFoundError::Version(e)) => println!("version not found! ({})", e.version()),
+    /// }
+    /// #
+    /// #     Ok(())
+    /// # }
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// ## Outer Error
+    ///
+    /// A critical error is returned if the filesystem cannot be read, or a git
+    /// error occurs
+    ///
+    /// ## Inner Error
+    ///
+    /// A [`NotFoundError`] is returned if either the crate or the specified
+    /// version can not be found in the index
+    pub fn yank(
+        &mut self,
+        crate_name: impl Into<String>,
+        version: &Version,
+    ) -> WrappedResult<(), NotFoundError, Error> {
+        block_on(self.async_index.yank(crate_name, version))
+    }
+
+    /// 'Unyank' a [`Record`] in the index.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use crate_index::{blocking::Index, Error, tree::NotFoundError};
+    /// #
+    /// # fn main() -> Result<(), Error> {
+    /// #    let mut index = Index::initialise("root", "download")
+    /// #        .identity("dummy username", "dummy@email.com")
+    /// #        .build()
+    /// #        .unwrap();
+    /// #
+    /// let crate_name = "some-crate";
+    /// let version = "0.1.0".parse().unwrap();
+    ///
+    /// match index.unyank(crate_name, &version)? {
+    ///     Ok(()) => println!("crate unyanked!"),
+    ///     Err(NotFoundError::Crate(e)) => println!("crate not found! ({})", e.crate_name()),
+    ///     Err(NotFoundError::Version(e)) => println!("version not found! ({})", e.version()),
+    /// }
+    /// #
+    /// #     Ok(())
+    /// # }
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// ## Outer Error
+    ///
+    /// A critical error is returned if the filesystem cannot be read, or a git
+    /// error occurs
+    ///
+    /// ## Inner Error
+    ///
+    /// A [`NotFoundError`] is returned if either the crate or the specified
+    /// version can not be found in the index
+    pub fn unyank(
+        &mut self,
+        crate_name: impl Into<String>,
+        version: &Version,
+    ) -> WrappedResult<(), NotFoundError, Error> {
+        block_on(self.async_index.unyank(crate_name, version))
+    }
+
+    /// Start a [`Transaction`], which collects inserts, yanks and unyanks and
+    /// commits them all at once.
+    ///
+    /// See [`Index::transaction`](crate::Index::transaction) for details.
+    pub fn transaction(&mut self) -> Transaction<'_> {
+        Transaction {
+            async_transaction: self.async_index.transaction(),
+        }
+    }
+
+    /// Rewrite every file in the index into the canonical layout used by
+    /// crates.io, in a single commit.
+    ///
+    /// Returns the names of the crates whose files changed.
+    ///
+    /// # Errors
+    ///
+    /// A critical error is returned if the filesystem cannot be read, or a git
+    /// error occurs
+    pub fn normalise(&mut self) -> Result<Vec<String>, Error> {
+        block_on(self.async_index.normalise())
+    }
+
+    /// Replace the git history of the index with a single commit, keeping the
+    /// old history on a snapshot branch.
+    ///
+    /// See [`Index::squash_history`](crate::Index::squash_history) for
+    /// details.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if the snapshot branch already exists, the pull
+    /// or push fails, or another git error occurs
+    pub fn squash_history(
+        &mut self,
+        snapshot_branch_name: impl AsRef<str>,
+        push: bool,
+    ) -> Result<(), Error> {
+        block_on(self.async_index.squash_history(snapshot_branch_name, push))
+    }
+
+    /// Check every file in the index for consistency.
+    ///
+    /// See [`Tree::check`] for details.
+    ///
+    /// # Errors
+    ///
+    /// A critical error is returned if the filesystem cannot be read
+    pub fn check(&self) -> Result<Report, Error> {
+        block_on(self.async_index.check())
+    }
+
+    /// Check every file in the index for consistency, and commit repairs for
+    /// the problems which can be fixed without losing information.
+    ///
+    /// See [`Tree::repair`] for details.
+    ///
+    /// # Errors
+    ///
+    /// A critical error is returned if the filesystem cannot be read or
+    /// written to, or a git error occurs
+    pub fn repair(&mut self) -> Result<Report, Error> {
+        block_on(self.async_index.repair())
+    }
+
+    /// Get all of the [`Record`]s in the index for a given crate.
+    ///
+    /// The records are returned in ascending order of version.
+    ///
+    /// # Errors
+    ///
+    /// ## Outer Error
+    ///
+    /// A critical error is returned if the filesystem cannot be read
+    ///
+    /// ## Inner Error
+    ///
+    /// A [`NotFoundError`] is returned if the crate can not be found in the
+    /// index
+    pub fn get(
+        &self,
+        crate_name: impl Into<String>,
+    ) -> WrappedResult<Vec<Record>, NotFoundError, Error> {
+        block_on(self.async_index.get(crate_name))
+    }
+
+    /// Get the [`Record`] for a specific version of a crate.
+    ///
+    /// # Errors
+    ///
+    /// ## Outer Error
+    ///
+    /// A critical error is returned if the filesystem cannot be read
+    ///
+    /// ## Inner Error
+    ///
+    /// A [`NotFoundError`] is returned if either the crate or the specified
+    /// version can not be found in the index
+    pub fn get_version(
+        &self,
+        crate_name: impl Into<String>,
+        version: &Version,
+    ) -> WrappedResult<Record, NotFoundError, Error> {
+        block_on(self.async_index.get_version(crate_name, version))
+    }
+
+    /// Get the newest non-yanked version of a crate which matches a version
+    /// requirement, and can be built with the given version of `rustc`.
+    ///
+    /// Returns `None` if there is no such version.
+    ///
+    /// # Errors
+    ///
+    /// ## Outer Error
+    ///
+    /// A critical error is returned if the filesystem cannot be read
+    ///
+    /// ## Inner Error
+    ///
+    /// A [`NotFoundError`] is returned if the crate can not be found in the
+    /// index
+    pub fn latest_compatible(
+        &self,
+        crate_name: impl Into<String>,
+        requirement: &VersionReq,
+        rustc: &Version,
+    ) -> WrappedResult<Option<Record>, NotFoundError, Error> {
+        block_on(
+            self.async_index
+                .latest_compatible(crate_name, requirement, rustc),
+        )
+    }
+
+    /// Enable or disable dependency validation for subsequent inserts.
+    ///
+    /// See [`Builder::validate_dependencies`] for details.
+    pub fn set_validate_dependencies(&mut self, enabled: bool) {
+        self.async_index.set_validate_dependencies(enabled);
+    }
+
+    /// Enable sync mode with the given number of retries, or disable it with
+    /// `None`.
+    ///
+    /// See [`Builder::sync`] for details.
+    pub fn set_sync(&mut self, retries: Option<u32>) {
+        self.async_index.set_sync(retries);
+    }
+
+    /// The location on the filesystem of the root of the index
+    #[must_use]
+    pub fn root(&self) -> &Path {
+        self.async_index.root().as_ref()
+    }
+
+    /// The Url for downloading .crate files
+    #[must_use]
+    pub fn download(&self) -> &String {
+        self.async_index.download()
+    }
+
+    /// The template for the download URLs of .crate files
+    #[must_use]
+    pub fn download_template(&self) -> &DownloadTemplate {
+        self.async_index.download_template()
+    }
+
+    /// The Url of the API
+    #[must_use]
+    pub fn api(&self) -> Option<&Url> {
+        self.async_index.api()
+    }
+
+    /// The list of registries which crates in this index are allowed to have
+    /// dependencies on
+    #[must_use]
+    pub fn allowed_registries(&self) -> &Vec<Url> {
+        self.async_index.allowed_registries()
+    }
+
+    /// Split this [`Index`] into its constituent parts
+    #[must_use]
+    pub fn into_parts(self) -> (Tree, Repository) {
+        let (async_tree, repo) = self.async_index.into_parts();
+        (Tree::from_async(async_tree), repo)
+    }
+}
+
+/// A batch of changes to an [`Index`], committed together.
+///
+/// Create a `Transaction` with [`Index::transaction`].
+#[derive(Debug)]
+#[must_use]
+pub struct Transaction<'a> {
+    async_transaction: AsyncTransaction<'a>,
+}
+
+impl Transaction<'_> {
+    /// Insert a crate [`Record`] into the index.
+    pub fn insert(mut self, record: Record) -> Self {
+        self.async_transaction = self.async_transaction.insert(record);
+        self
+    }
+
+    /// 'Yank' a version of a crate in the index.
+    pub fn yank(mut self, crate_name: impl Into<String>, version: Version) -> Self {
+        self.async_transaction = self.async_transaction.yank(crate_name, version);
+        self
+    }
+
+    /// 'Unyank' a version of a crate in the index.
+    pub fn unyank(mut self, crate_name: impl Into<String>, version: Version) -> Self {
+        self.async_transaction = self.async_transaction.unyank(crate_name, version);
+        self
+    }
+
+    /// Add an [`Operation`] to the transaction.
+    pub fn operation(mut self, operation: Operation) -> Self {
+        self.async_transaction = self.async_transaction.operation(operation);
+        self
+    }
+
+    /// Apply every change in the transaction, and commit them to the git
+    /// repository in a single commit.
+    ///
+    /// Returns the names of the crates whose files changed, in alphabetical
+    /// order.
+    ///
+    /// # Errors
+    ///
+    /// ## Outer Error
+    ///
+    /// A critical error is returned if the filesystem cannot be read or
+    /// written to, or a git error occurs
+    ///
+    /// ## Inner Error
+    ///
+    /// A [`TransactionError`] is returned for the first change which is
+    /// rejected. In this case, the index is left untouched.
+    pub fn commit(self) -> WrappedResult<Vec<String>, TransactionError, Error> {
+        block_on(self.async_transaction.commit())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Index;
+    use crate::{Record, Url};
+    use semver::Version;
+    use std::path::PathBuf;
+    use test_case::test_case;
+
+    #[test]
+    fn get_and_set() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let root: PathBuf = temp_dir.path().into();
+        let origin = Url::parse("https://my-git-server.com/").unwrap();
+
+        let api = Url::parse("https://my-crates-server.com/").unwrap();
+
+        let download = "https://my-crates-server.com/api/v1/crates/{crate}/{version}/download";
+
+        let index = Index::initialise(root.clone(), download)
+            .origin(origin)
+            .api(api.clone())
+            .allowed_registry(Url::parse("https://my-intranet:8080/index").unwrap())
+            .allow_crates_io()
+            .identity("dummy username", "dummy@email.com")
+            .build()
+            .unwrap();
+
+        let expected_allowed_registries = vec![
+            Url::parse("https://my-intranet:8080/index").unwrap(),
+            Url::parse("https://github.com/rust-lang/crates.io-index").unwrap(),
+        ];
+
+        assert_eq!(index.root(), &root);
+        assert_eq!(index.download(), download);
+        assert_eq!(index.api(), Some(&api));
+        assert_eq!(index.allowed_registries(), &expected_allowed_registries);
+    }
+
+    #[test_case("Some-Name", "0.1.1" ; "when used properly")]
+    #[test_case("Some_Name", "0.1.1" => panics "invalid" ; "when crate names differ only by hyphens and underscores")]
+    #[test_case("some_name", "0.1.1" => panics "invalid" ; "when crate names differ only by capitalisation")]
+    #[test_case("other-name", "0.1.1" ; "when inserting a different crate")]
+    #[test_case("Some-Name", "0.1.0" => panics "invalid"; "when version is the same")]
+    #[test_case("Some-Name", "0.0.1" => panics "invalid"; "when version is lower")]
+    #[test_case("nul", "0.0.1" => panics "invalid"; "when name is reserved word")]
+    #[test_case("-start-with-hyphen", "0.0.1" => panics "invalid"; "when name starts with non-alphabetical character")]
+    fn insert(name: &str, version: &str) {
+        // create temporary directory
+        let temp_dir = tempfile::tempdir().unwrap();
+        let root = temp_dir.path();
+        let download = "https://my-crates-server.com/api/v1/crates/{crate}/{version}/download";
+        let origin = Url::parse("https://my-git-server.com/").unwrap();
+
+        let initial_metadata = metadata("Some-Name", "0.1.0");
+
+        // create index file and seed with initial metadata
+        let mut index = Index::initialise(root, download)
+            .origin(origin)
+            .identity("dummy username", "dummy@email.com")
+            .build()
+            .expect("couldn't create index");
+
+        index
+            .insert(initial_metadata)
+            .expect("critical error")
+            .expect("couldn't insert initial metadata");
+
+        // create and insert new metadata
+        let new_metadata = metadata(name, version);
+        index
+            .insert(new_metadata)
+            .expect("critical error")
+            .expect("invalid");
+    }
+
+    fn metadata(name: &str, version: &str) -> Record {
+        Record::new(name, Version::parse(version).unwrap(), "checksum")
+    }
+
+    #[test]
+    fn open() {
+        // create temporary directory
+        let temp_dir = tempfile::tempdir().unwrap();
+        let root = temp_dir.path();
+        let download = "https://my-crates-server.com/api/v1/crates/{crate}/{version}/download";
+
+        {
+            // create index file and seed with initial metadata
+            let mut index = Index::initialise(root, download)
+                .identity("dummy username", "dummy@email.com")
+                .build()
+                .expect("couldn't create index");
+
+            index
+                .insert(metadata("Some-Name", "0.1.0"))
+                .expect("critical error")
+                .expect("validation error");
+        }
+
+        // reopen the same index and check crate is there
+        let index = Index::open(root).expect("couldn't open index");
+        let (tree, _repo) = index.into_parts();
+        assert!(tree.contains_crate("Some-Name"));
+    }
+
+    #[test_case("Some-Name", "0.1.0"; "when crate exists and version exists")]
+    #[test_case("Some-Name", "0.2.0" => panics "not found"; "when crate exists but version doesn't exist")]
+    #[test_case("Other-Name", "0.2.0" => panics "not found"; "when crate doesn't exist")]
+    fn yank(crate_name: &str, version: &str) {
+        let version = Version::parse(version).unwrap();
+        let temp_dir = tempfile::tempdir().unwrap();
+        let root = temp_dir.path();
+        let download = "https://my-crates-server.com/api/v1/crates/{crate}/{version}/download";
+
+        let initial_metadata = metadata("Some-Name", "0.1.0");
+
+        // create index file and seed with initial metadata
+        let mut index = Index::initialise(root, download)
+            .identity("dummy username", "dummy@email.com")
+            .build()
+            .expect("couldn't create index");
+
+        index
+            .insert(initial_metadata)
+            .unwrap()
+            .expect("couldn't insert initial metadata");
+
+        assert!(
+            index.yank(crate_name, &version).unwrap().is_ok(),
+            "not found"
+        );
+
+        index.unyank(crate_name, &version).unwrap().unwrap();
+    }
+
+    #[test_case("Some-Name", "0.1.0"; "when crate exists and version exists")]
+    #[test_case("Some-Name", "0.2.0" => panics "not found"; "when crate exists but version doesn't exist")]
+    #[test_case("Other-Name", "0.1.0" => panics "not found"; "when crate doesn't exist")]
+    fn get_version(crate_name: &str, version: &str) {
+        let version = Version::parse(version).unwrap();
+        let temp_dir = tempfile::tempdir().unwrap();
+        let root = temp_dir.path();
+        let download = "https://my-crates-server.com/api/v1/crates/{crate}/{version}/download";
+
+        let initial_metadata = metadata("Some-Name", "0.1.0");
+
+        let mut index = Index::initialise(root, download)
+            .identity("dummy username", "dummy@email.com")
+            .build()
+            .expect("couldn't create index");
+
+        index
+            .insert(initial_metadata.clone())
+            .unwrap()
+            .expect("couldn't insert initial metadata");
+
+        let record = index
+            .get_version(crate_name, &version)
+            .unwrap()
+            .expect("not found");
+
+        assert_eq!(record, initial_metadata);
+    }
+}
@@ -1,25 +1,19 @@
 //! Abstractions over a filesystem directory containing an index.
 
 use crate::{
-    tree::{Builder as AsyncBuilder, NotFoundError, Tree as AsyncTree},
+    blocking::block_on,
+    download::DownloadTemplate,
+    tree::{
+        Builder as AsyncBuilder, CorruptionError, Error, LockOptions, NotFoundError, Operation,
+        Report, TransactionError, Tree as AsyncTree,
+    },
     validate::Error as ValidationError,
     Record, WrappedResult,
 };
-use semver::Version;
-use std::{
-    future::Future,
-    io::Error as IoError,
-    path::{Path, PathBuf},
-};
+use semver::{Version, VersionReq};
+use std::path::{Path, PathBuf};
 use url::Url;
 
-fn block_on<F, T>(future: F) -> T
-where
-    F: Future<Output = T>,
-{
-    async_std::task::block_on(future)
-}
-
 /// An interface to a crate index directory on the filesystem
 #[derive(Debug)]
 pub struct Tree {
@@ -62,13 +56,25 @@ impl Builder {
         self
     }
 
+    /// Check the dependencies of inserted crates.
+    ///
+    /// When enabled, a [`Record`] is rejected if any of its dependencies from
+    /// this registry cannot be satisfied by a non-yanked version in the tree,
+    /// or if any of its dependencies are from a registry which is not allowed.
+    pub fn validate_dependencies(mut self) -> Self {
+        self.async_builder = self.async_builder.validate_dependencies();
+        self
+    }
+
     /// Construct the [`Tree`] with the given parameters.
     ///
     /// # Errors
     ///
     /// This method can fail if the root path doesn't exist, or the filesystem
     /// cannot be written to.
-    pub fn build(self) -> Result<Tree, IoError> {
+    ///
+    /// An invalid download template is returned as [`Error::Template`].
+    pub fn build(self) -> Result<Tree, Error> {
         let async_tree = block_on(self.async_builder.build())?;
         Ok(Tree { async_tree })
     }
@@ -120,21 +126,61 @@ impl Tree {
         Builder { async_builder }
     }
 
+    pub(crate) fn from_async(async_tree: AsyncTree) -> Self {
+        Self { async_tree }
+    }
+
     /// Open an existing index tree at the given root path.
     ///
     /// # Errors
     ///
-    /// This method can fail if the given path does not exist, or the config
-    /// file cannot be read.
-    pub fn open(root: impl Into<PathBuf>) -> Result<Self, IoError> {
+    /// This method can fail if the given path does not exist, the config file
+    /// cannot be read, or another process holds a lock on the root.
+    pub fn open(root: impl Into<PathBuf>) -> Result<Self, Error> {
         let async_tree = block_on(AsyncTree::open(root.into()))?;
         let tree = Self { async_tree };
 
         Ok(tree)
     }
 
+    /// Open an existing index tree at the given root path, locking the root
+    /// with the given options.
+    ///
+    /// A tree opened with a [shared](LockOptions::shared) lock is read-only.
+    ///
+    /// # Errors
+    ///
+    /// This method can fail if the given path does not exist, the config file
+    /// cannot be read, or another process holds a conflicting lock on the root
+    /// for longer than the timeout.
+    pub fn open_with(root: impl Into<PathBuf>, lock_options: LockOptions) -> Result<Self, Error> {
+        let async_tree = block_on(AsyncTree::open_with(root.into(), lock_options))?;
+        let tree = Self { async_tree };
+
+        Ok(tree)
+    }
+
+    /// Open an existing index tree at the given root path, skipping any
+    /// lines of the index files which cannot be parsed.
+    ///
+    /// The lines which couldn't be parsed are returned alongside the tree.
+    ///
+    /// # Errors
+    ///
+    /// This method can fail if the given path does not exist, or the config
+    /// file cannot be read or parsed.
+    pub fn open_lenient(root: impl Into<PathBuf>) -> Result<(Self, Vec<CorruptionError>), Error> {
+        let (async_tree, errors) = block_on(AsyncTree::open_lenient(root.into()))?;
+        let tree = Self { async_tree };
+
+        Ok((tree, errors))
+    }
+
     /// Insert crate [`Record`] into the index.
     ///
+    /// Returns the path of the index file which was written, relative to the
+    /// root of the index.
+    ///
     /// # Errors
     ///
     /// This method can fail if the metadata is deemed to be invalid, or if the
@@ -142,12 +188,15 @@ impl Tree {
     pub fn insert(
         &mut self,
         crate_metadata: Record,
-    ) -> WrappedResult<(), ValidationError, IoError> {
-        block_on(self.async_tree.insert(crate_metadata))
+    ) -> WrappedResult<PathBuf, ValidationError, Error> {
+        Ok(block_on(self.async_tree.insert(crate_metadata))?.map(Into::into))
     }
 
     /// Mark a selected version of a crate as 'yanked'.
     ///
+    /// Returns the path of the index file which was written, relative to the
+    /// root of the index.
+    ///
     /// # Example
     ///
     /// ```no_run
@@ -162,7 +211,7 @@ impl Tree {
     /// let version = "0.1.0".parse().unwrap();
     ///
     /// match tree.yank(crate_name, &version)? {
-    ///     Ok(()) => println!("crate yanked!"),
+    ///     Ok(path) => println!("crate yanked! ({:?} changed)", path),
     ///     Err(NotFoundError::Crate(e)) => println!("crate not found! ({})", e.crate_name()),
     ///     Err(NotFoundError::Version(e)) => println!("version not found! ({})", e.version()),
     /// }
@@ -175,7 +224,7 @@ impl Tree {
     ///
     /// ## Outer Error
     ///
-    /// an [`IoError`] is returned if the filesystem cannot be read or written
+    /// an [`Error`] is returned if the filesystem cannot be read or written
     /// to.
     ///
     /// ## Inner Error
@@ -186,12 +235,15 @@ impl Tree {
         &mut self,
         crate_name: impl Into<String>,
         version: &Version,
-    ) -> WrappedResult<(), NotFoundError, IoError> {
-        block_on(self.async_tree.yank(crate_name, version))
+    ) -> WrappedResult<PathBuf, NotFoundError, Error> {
+        Ok(block_on(self.async_tree.yank(crate_name, version))?.map(Into::into))
     }
 
     /// Mark a selected version of a crate as 'unyanked'.
     ///
+    /// Returns the path of the index file which was written, relative to the
+    /// root of the index.
+    ///
     /// # Example
     ///
     /// ```no_run
@@ -206,7 +258,7 @@ impl Tree {
     /// let version = "0.1.0".parse().unwrap();
     ///
     /// match tree.unyank(crate_name, &version)? {
-    ///     Ok(()) => println!("crate unyanked!"),
+    ///     Ok(path) => println!("crate unyanked! ({:?} changed)", path),
     ///     Err(NotFoundError::Crate(e)) => println!("crate not found! ({})", e.crate_name()),
     ///     Err(NotFoundError::Version(e)) => println!("version not found! ({})", e.version()),
     /// }
@@ -219,7 +271,7 @@ impl Tree {
     ///
     /// ## Outer Error
     ///
-    /// an [`IoError`] is returned if the filesystem cannot be read or written
+    /// an [`Error`] is returned if the filesystem cannot be read or written
     /// to.
     ///
     /// ## Inner Error
@@ -230,8 +282,167 @@ impl Tree {
         &mut self,
         crate_name: impl Into<String>,
         version: &Version,
-    ) -> WrappedResult<(), NotFoundError, IoError> {
-        block_on(self.async_tree.unyank(crate_name, version))
+    ) -> WrappedResult<PathBuf, NotFoundError, Error> {
+        Ok(block_on(self.async_tree.unyank(crate_name, version))?.map(Into::into))
+    }
+
+    /// Apply a batch of [`Operation`]s to the index.
+    ///
+    /// If any operation is rejected, nothing is written. See
+    /// [`Tree::apply`](crate::tree::Tree::apply) for details.
+    ///
+    /// Returns the names of the crates whose files changed, in alphabetical
+    /// order.
+    ///
+    /// # Errors
+    ///
+    /// ## Outer Error
+    ///
+    /// an [`Error`] is returned if the filesystem cannot be read or written
+    /// to.
+    ///
+    /// ## Inner Error
+    ///
+    /// a [`TransactionError`] is returned for the first operation which is
+    /// rejected.
+    pub fn apply(
+        &mut self,
+        operations: impl IntoIterator<Item = Operation>,
+    ) -> WrappedResult<Vec<String>, TransactionError, Error> {
+        block_on(self.async_tree.apply(operations))
+    }
+
+    /// Rewrite every file in the index into the canonical layout used by
+    /// crates.io.
+    ///
+    /// Returns the names of the crates whose files changed.
+    ///
+    /// # Errors
+    ///
+    /// an [`Error`] is returned if the filesystem cannot be read or written
+    /// to.
+    pub fn normalise(&mut self) -> Result<Vec<String>, Error> {
+        block_on(self.async_tree.normalise())
+    }
+
+    /// Check every file in the index for consistency.
+    ///
+    /// See [`Problem`](crate::tree::Problem) for the full list of checks.
+    ///
+    /// # Errors
+    ///
+    /// an [`Error`] is returned if the filesystem cannot be read.
+    pub fn check(&self) -> Result<Report, Error> {
+        block_on(self.async_tree.check())
+    }
+
+    /// Check every file in the index for consistency, and repair the problems
+    /// which can be fixed without losing information.
+    ///
+    /// The changes are listed in [`Report::repairs`].
+    ///
+    /// # Errors
+    ///
+    /// an [`Error`] is returned if the filesystem cannot be read or written
+    /// to.
+    pub fn repair(&mut self) -> Result<Report, Error> {
+        block_on(self.async_tree.repair())
+    }
+
+    /// Get all of the [`Record`]s in the index for a given crate.
+    ///
+    /// The records are returned in ascending order of version.
+    ///
+    /// # Errors
+    ///
+    /// ## Outer Error
+    ///
+    /// an [`Error`] is returned if the filesystem cannot be read.
+    ///
+    /// ## Inner Error
+    ///
+    /// This function will return [`NotFoundError`] if the crate does not exist
+    /// in the index.
+    pub fn get(
+        &self,
+        crate_name: impl Into<String>,
+    ) -> WrappedResult<Vec<Record>, NotFoundError, Error> {
+        block_on(self.async_tree.get(crate_name))
+    }
+
+    /// Get the [`Record`] for a specific version of a crate.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use crate_index::{blocking::tree::Tree, Error, tree::NotFoundError};
+    /// #
+    /// # fn main() -> Result<(), Error> {
+    /// #    let tree = Tree::open("root")?;
+    /// #
+    /// let crate_name = "some-crate";
+    /// let version = "0.1.0".parse().unwrap();
+    ///
+    /// match tree.get_version(crate_name, &version)? {
+    ///     Ok(record) => println!("checksum: {}", record.check_sum()),
+    ///     Err(NotFoundError::Crate(e)) => println!("crate not found! ({})", e.crate_name()),
+    ///     Err(NotFoundError::Version(e)) => println!("version not found! ({})", e.version()),
+    /// }
+    /// #
+    /// #     Ok(())
+    /// # }
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// ## Outer Error
+    ///
+    /// an [`Error`] is returned if the filesystem cannot be read.
+    ///
+    /// ## Inner Error
+    ///
+    /// This function will return [`NotFoundError`] if the crate or the
+    /// selected version does not exist in the index.
+    pub fn get_version(
+        &self,
+        crate_name: impl Into<String>,
+        version: &Version,
+    ) -> WrappedResult<Record, NotFoundError, Error> {
+        block_on(self.async_tree.get_version(crate_name, version))
+    }
+
+    /// Get the newest non-yanked version of a crate which matches a version
+    /// requirement, and can be built with the given version of `rustc`.
+    ///
+    /// Returns `None` if there is no such version.
+    ///
+    /// # Errors
+    ///
+    /// ## Outer Error
+    ///
+    /// an [`Error`] is returned if the filesystem cannot be read
+    ///
+    /// ## Inner Error
+    ///
+    /// A [`NotFoundError`] is returned if the crate can not be found in the
+    /// index
+    pub fn latest_compatible(
+        &self,
+        crate_name: impl Into<String>,
+        requirement: &VersionReq,
+        rustc: &Version,
+    ) -> WrappedResult<Option<Record>, NotFoundError, Error> {
+        block_on(
+            self.async_tree
+                .latest_compatible(crate_name, requirement, rustc),
+        )
+    }
+
+    /// Enable or disable dependency validation for subsequent inserts.
+    ///
+    /// See [`Builder::validate_dependencies`] for details.
+    pub fn set_validate_dependencies(&mut self, enabled: bool) {
+        self.async_tree.set_validate_dependencies(enabled);
     }
 
     /// The location on the filesystem of the root of the index
@@ -246,6 +457,12 @@ impl Tree {
         self.async_tree.download()
     }
 
+    /// The template for the download URLs of .crate files
+    #[must_use]
+    pub fn download_template(&self) -> &DownloadTemplate {
+        self.async_tree.download_template()
+    }
+
     /// The Url of the API
     #[must_use]
     pub fn api(&self) -> Option<&Url> {
@@ -269,6 +486,7 @@ impl Tree {
 }
 
 #[cfg(test)]
+#[allow(clippy::manual_assert)]
 mod tests {
 
     use super::{Record, Tree};
@@ -394,4 +612,36 @@ mod tests {
 
         tree.unyank(crate_name, &version).unwrap().unwrap();
     }
+
+    #[test_case("Some-Name", "0.1.0"; "when crate exists and version exists")]
+    #[test_case("Some-Name", "0.2.0" => panics "not found"; "when crate exists but version doesn't exist")]
+    #[test_case("Other-Name", "0.1.0" => panics "not found"; "when crate doesn't exist")]
+    fn get_version(crate_name: &str, version: &str) {
+        let version = Version::parse(version).unwrap();
+        let temp_dir = tempfile::tempdir().unwrap();
+        let root = temp_dir.path();
+        let download = "https://my-crates-server.com/api/v1/crates/{crate}/{version}/download";
+
+        let initial_metadata = metadata("Some-Name", "0.1.0");
+
+        let mut tree = Tree::initialise(root, download)
+            .build()
+            .expect("couldn't create tree");
+
+        tree.insert(initial_metadata.clone())
+            .unwrap()
+            .expect("couldn't insert initial metadata");
+
+        assert_eq!(
+            tree.get(crate_name).unwrap().expect("not found"),
+            vec![initial_metadata.clone()]
+        );
+
+        let record = tree
+            .get_version(crate_name, &version)
+            .unwrap()
+            .expect("not found");
+
+        assert_eq!(record, initial_metadata);
+    }
 }
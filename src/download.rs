@@ -0,0 +1,255 @@
+//! The template for download URLs of `.crate` files.
+//!
+//! The `dl` field of the index config tells Cargo where to download `.crate`
+//! files from. It may contain the following markers, which Cargo replaces
+//! with values for the crate being downloaded-
+//!
+//! - `{crate}`: the name of the crate
+//! - `{version}`: the version of the crate
+//! - `{prefix}`: the directory prefix of the crate's index file (eg. `ca/rg`)
+//! - `{lowerprefix}`: the prefix, in lowercase
+//! - `{sha256-checksum}`: the checksum of the `.crate` file
+//!
+//! If none of the markers are present, Cargo appends
+//! `/{crate}/{version}/download` to the template.
+//!
+//! *see the [cargo docs](https://doc.rust-lang.org/cargo/reference/registry-index.html#index-configuration) for details*
+//!
+//! # Example
+//!
+//! ```
+//! use crate_index::{download::DownloadTemplate, Record, Version};
+//!
+//! let template = DownloadTemplate::parse("https://my-crates-server.com/crates/{lowerprefix}/{crate}")
+//!     .expect("invalid template");
+//!
+//! let record = Record::new("Some-Crate", Version::new(0, 1, 0), "checksum");
+//!
+//! assert_eq!(
+//!     template.render(&record).unwrap().as_str(),
+//!     "https://my-crates-server.com/crates/so/me/Some-Crate"
+//! );
+//! ```
+
+use crate::Record;
+use semver::Version;
+use serde::{Deserialize, Serialize};
+use std::{convert::TryFrom, fmt, str::FromStr};
+use url::{ParseError, Url};
+
+/// The markers which are substituted when a template is rendered
+const MARKERS: &[&str] = &[
+    "{crate}",
+    "{version}",
+    "{prefix}",
+    "{lowerprefix}",
+    "{sha256-checksum}",
+];
+
+/// A validated template for the download URLs of `.crate` files
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct DownloadTemplate {
+    template: String,
+}
+
+impl DownloadTemplate {
+    /// Parse a download template.
+    ///
+    /// # Errors
+    ///
+    /// A [`TemplateError`] is returned if the template does not produce a valid
+    /// URL when rendered.
+    pub fn parse(template: impl Into<String>) -> Result<Self, TemplateError> {
+        let template = Self {
+            template: template.into(),
+        };
+
+        let example = Record::new("crate", Version::new(0, 0, 0), "0".repeat(64));
+        if let Err(source) = Url::parse(&template.substitute(&example)) {
+            return Err(TemplateError {
+                template: template.template,
+                source,
+            });
+        }
+
+        Ok(template)
+    }
+
+    /// The URL which Cargo will use to download the `.crate` file for a given
+    /// [`Record`].
+    ///
+    /// # Errors
+    ///
+    /// A [`TemplateError`] is returned if substituting the record's fields
+    /// produces an invalid URL. This can only happen if markers are used in
+    /// the host, and the crate name is not a valid hostname.
+    pub fn render(&self, record: &Record) -> Result<Url, TemplateError> {
+        Url::parse(&self.substitute(record)).map_err(|source| TemplateError {
+            template: self.template.clone(),
+            source,
+        })
+    }
+
+    /// The template as a string, exactly as it appears in the index config
+    #[must_use]
+    pub fn as_str(&self) -> &str {
+        &self.template
+    }
+
+    pub(crate) fn as_string(&self) -> &String {
+        &self.template
+    }
+
+    fn substitute(&self, record: &Record) -> String {
+        if MARKERS.iter().any(|marker| self.template.contains(marker)) {
+            substitute(&self.template, record)
+        } else {
+            format!(
+                "{}/{}/{}/download",
+                self.template,
+                record.name(),
+                record.version()
+            )
+        }
+    }
+}
+
+impl FromStr for DownloadTemplate {
+    type Err = TemplateError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s)
+    }
+}
+
+impl TryFrom<String> for DownloadTemplate {
+    type Error = TemplateError;
+
+    fn try_from(template: String) -> Result<Self, Self::Error> {
+        Self::parse(template)
+    }
+}
+
+impl From<DownloadTemplate> for String {
+    fn from(template: DownloadTemplate) -> Self {
+        template.template
+    }
+}
+
+impl fmt::Display for DownloadTemplate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.template)
+    }
+}
+
+/// The error returned when a download template is not valid, or doesn't
+/// produce a valid URL for a crate
+#[derive(Debug, thiserror::Error)]
+#[error("invalid download template '{template}': {source}")]
+pub struct TemplateError {
+    template: String,
+    source: ParseError,
+}
+
+impl TemplateError {
+    /// The template which failed to parse
+    #[must_use]
+    pub fn template(&self) -> &str {
+        &self.template
+    }
+}
+
+/// Substitute the download template markers in a string with the values for
+/// the given [`Record`].
+pub(crate) fn substitute(template: &str, record: &Record) -> String {
+    let prefix = prefix(record.name());
+
+    template
+        .replace("{crate}", record.name())
+        .replace("{version}", &record.version().to_string())
+        .replace("{prefix}", &prefix)
+        .replace("{lowerprefix}", &prefix.to_ascii_lowercase())
+        .replace("{sha256-checksum}", record.check_sum())
+}
+
+/// The directory prefix of a crate, as used by the `{prefix}` marker.
+///
+/// The name is split by characters rather than bytes, so that names which
+/// haven't been validated can't cause a panic.
+pub(crate) fn prefix(crate_name: &str) -> String {
+    let chars: Vec<char> = crate_name.chars().collect();
+
+    match chars.len() {
+        len @ 0..=2 => len.to_string(),
+        3 => format!("3/{}", chars[0]),
+        _ => format!(
+            "{}/{}",
+            chars[0..2].iter().collect::<String>(),
+            chars[2..4].iter().collect::<String>()
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{prefix, substitute, DownloadTemplate};
+    use crate::Record;
+    use semver::Version;
+    use test_case::test_case;
+
+    fn record() -> Record {
+        Record::new("Some-Crate", Version::new(0, 1, 0), "checksum")
+    }
+
+    #[test_case("{crate}/{version}/download" => "Some-Crate/0.1.0/download"; "crate and version")]
+    #[test_case("{prefix}/{crate}-{version}.crate" => "So/me/Some-Crate-0.1.0.crate"; "prefix")]
+    #[test_case("{lowerprefix}/{sha256-checksum}" => "so/me/checksum"; "lower prefix and checksum")]
+    fn substitute_markers(template: &str) -> String {
+        substitute(template, &record())
+    }
+
+    #[test_case("a" => "1"; "one character")]
+    #[test_case("ab" => "2"; "two characters")]
+    #[test_case("Abc" => "3/A"; "three characters")]
+    #[test_case("Abcd" => "Ab/cd"; "four characters")]
+    #[test_case("ábcdé" => "áb/cd"; "non-ascii characters")]
+    fn directory_prefix(crate_name: &str) -> String {
+        prefix(crate_name)
+    }
+
+    #[test_case("https://my-crates-server.com/api/v1/crates/{crate}/{version}/download" => "https://my-crates-server.com/api/v1/crates/Some-Crate/0.1.0/download"; "with markers")]
+    #[test_case("https://my-crates-server.com/api/v1/crates" => "https://my-crates-server.com/api/v1/crates/Some-Crate/0.1.0/download"; "without markers")]
+    #[test_case("https://my-crates-server.com/{lowerprefix}/{crate}?sum={sha256-checksum}" => "https://my-crates-server.com/so/me/Some-Crate?sum=checksum"; "with query")]
+    #[test_case("download" => panics "invalid"; "relative url")]
+    #[test_case("https://{crate}:port/" => panics "invalid"; "invalid port")]
+    fn render(template: &str) -> String {
+        DownloadTemplate::parse(template)
+            .expect("invalid")
+            .render(&record())
+            .unwrap()
+            .to_string()
+    }
+
+    #[test]
+    fn render_invalid_host() {
+        let template = DownloadTemplate::parse("https://{crate}.my-crates-server.com/").unwrap();
+        let record = Record::new("not a host", Version::new(0, 1, 0), "checksum");
+
+        let error = template.render(&record).unwrap_err();
+        assert_eq!(error.template(), "https://{crate}.my-crates-server.com/");
+    }
+
+    #[test]
+    fn serde() {
+        let template = "https://my-crates-server.com/{crate}/{version}";
+
+        let json = serde_json::to_string(&DownloadTemplate::parse(template).unwrap()).unwrap();
+        assert_eq!(json, format!("\"{}\"", template));
+
+        let parsed: DownloadTemplate = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed.as_str(), template);
+
+        assert!(serde_json::from_str::<DownloadTemplate>("\"not a url\"").is_err());
+    }
+}
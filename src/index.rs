@@ -3,17 +3,31 @@
 //! In normal usage, it would not be required to use these underlying types.
 //! They are exposed here so that can be reused in other crates.
 
-use crate::{validate::Error as ValidationError, Record, Url, WrappedResult};
+use crate::{
+    download::{DownloadTemplate, TemplateError},
+    package,
+    storage::Storage,
+    validate::Error as ValidationError,
+    Record, Url, WrappedResult,
+};
 use async_std::path::PathBuf;
-use semver::Version;
+use semver::{Version, VersionReq};
 use std::io::Error as IoError;
 
 pub mod tree;
-use tree::{Builder as TreeBuilder, NotFoundError, Tree};
+use tree::{
+    Builder as TreeBuilder, CorruptionError, Error as TreeError, LockOptions, LockedError,
+    NotFoundError, Report, Tree,
+};
 
 pub mod git;
 
-use git::{Identity, Repository};
+pub mod sparse;
+
+mod transaction;
+pub use transaction::Transaction;
+
+use git::{Identity, MergeError, Repository};
 
 /// A representation of a crates registry, backed by both a directory and a git
 /// repository on the filesystem.
@@ -28,16 +42,22 @@ use git::{Identity, Repository};
 pub struct Index {
     tree: Tree,
     repo: Repository,
+
+    /// In sync mode, the number of times a rejected push is retried
+    sync: Option<u32>,
 }
 
 /// A builder for initialising a new [`Index`]
 #[derive(Debug)]
 #[must_use]
 pub struct Builder<'a> {
-    tree_builder: TreeBuilder,
+    tree: TreeBuilder,
     root: PathBuf,
     origin: Option<Url>,
+    remote_name: Option<String>,
+    branch: Option<String>,
     identity: Option<Identity<'a>>,
+    sync: Option<u32>,
 }
 
 impl<'a> Builder<'a> {
@@ -45,7 +65,7 @@ impl<'a> Builder<'a> {
     /// The API should implement the REST interface as defined in
     /// [the Cargo book](https://doc.rust-lang.org/cargo/reference/registries.html)
     pub fn api(mut self, api: Url) -> Self {
-        self.tree_builder = self.tree_builder.api(api);
+        self.tree = self.tree.api(api);
         self
     }
 
@@ -55,6 +75,23 @@ impl<'a> Builder<'a> {
         self
     }
 
+    /// Set the name of the remote which is pulled from and pushed to.
+    ///
+    /// This is `origin` by default.
+    pub fn remote_name(mut self, name: impl Into<String>) -> Self {
+        self.remote_name = Some(name.into());
+        self
+    }
+
+    /// Set the name of the branch which the index is committed to.
+    ///
+    /// By default, this is the default branch for new git repositories (set by
+    /// `init.defaultBranch` in the git config).
+    pub fn branch(mut self, name: impl Into<String>) -> Self {
+        self.branch = Some(name.into());
+        self
+    }
+
     /// Add an allowed registry.
     ///
     /// Crates in this registry are only allowed to have dependencies which are
@@ -62,7 +99,7 @@ impl<'a> Builder<'a> {
     ///
     /// Add multiple registries my calling this method multiple times.
     pub fn allowed_registry(mut self, registry: Url) -> Self {
-        self.tree_builder = self.tree_builder.allowed_registry(registry);
+        self.tree = self.tree.allowed_registry(registry);
         self
     }
 
@@ -70,7 +107,20 @@ impl<'a> Builder<'a> {
     ///
     /// You will almost always want this, so this exists as a handy shortcut.
     pub fn allow_crates_io(mut self) -> Self {
-        self.tree_builder = self.tree_builder.allow_crates_io();
+        self.tree = self.tree.allow_crates_io();
+        self
+    }
+
+    /// Check the dependencies of inserted crates.
+    ///
+    /// When enabled, a [`Record`] is rejected if any of its dependencies from
+    /// this registry cannot be satisfied by a non-yanked version in the index,
+    /// or if any of its dependencies are from a registry which is not allowed.
+    ///
+    /// This setting is not stored in the index. To keep it when the index is
+    /// reopened, use [`OpenOptions::validate_dependencies`].
+    pub fn validate_dependencies(mut self) -> Self {
+        self.tree = self.tree.validate_dependencies();
         self
     }
 
@@ -80,15 +130,46 @@ impl<'a> Builder<'a> {
         self
     }
 
+    /// Keep the index in sync with its origin.
+    ///
+    /// In sync mode, every change to the index pulls from the origin first,
+    /// is validated against the updated index, and is then committed and
+    /// pushed. If the push is rejected because the origin has moved on, the
+    /// commit is discarded and the whole process is retried up to `retries`
+    /// times. This allows several instances to publish to the same origin.
+    ///
+    /// A change which is not published is never left behind. If the push
+    /// fails, or every retry is rejected, the commit is discarded and an error
+    /// is returned. If a
+    /// retry finds the change is no longer valid against the updated index
+    /// (for example, because another instance published the same version),
+    /// the validation error is returned, and the index holds only the pulled
+    /// changes.
+    ///
+    /// The initial commit is pushed when the index is built, so an origin must
+    /// also be set.
+    pub fn sync(mut self, retries: u32) -> Self {
+        self.sync = Some(retries);
+        self
+    }
+
     /// Construct the [`Index`] with the given parameters.
     ///
     /// # Errors
     ///
-    /// This method can fail if the root path doesn't exist, or the filesystem
-    /// cannot be written to.
+    /// This method can fail if the root path doesn't exist, the filesystem
+    /// cannot be written to, or the download template is not valid.
     pub async fn build(self) -> Result<Index, Error> {
-        let tree = self.tree_builder.build().await?;
-        let repo = Repository::init(self.root)?;
+        let tree = self.tree.build().await?;
+        let mut repo = Repository::init(self.root)?;
+
+        if let Some(branch) = self.branch {
+            repo.set_initial_branch(branch)?;
+        }
+
+        if let Some(name) = self.remote_name {
+            repo.set_remote(name);
+        }
 
         if let Some(url) = self.origin {
             repo.add_origin(&url)?;
@@ -99,14 +180,79 @@ impl<'a> Builder<'a> {
             repo.set_email(identity.email)?;
         }
 
+        repo.add_path("config.json")?;
         repo.create_initial_commit()?;
 
-        let index = Index { tree, repo };
+        if self.sync.is_some() {
+            repo.push()?;
+        }
+
+        let index = Index {
+            tree,
+            repo,
+            sync: self.sync,
+        };
 
         Ok(index)
     }
 }
 
+/// Options for opening an existing [`Index`] with [`Index::open_with`]
+#[derive(Debug, Clone, Default)]
+#[must_use]
+pub struct OpenOptions {
+    lock: LockOptions,
+    remote_name: Option<String>,
+    branch: Option<String>,
+    validate_dependencies: bool,
+}
+
+impl OpenOptions {
+    /// The default options.
+    ///
+    /// An exclusive lock is taken, and the `origin` remote and the branch
+    /// which is checked out are used for pulling and pushing.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the options for locking the root of the index.
+    pub fn lock(mut self, lock: LockOptions) -> Self {
+        self.lock = lock;
+        self
+    }
+
+    /// Set the name of the remote which is pulled from and pushed to.
+    pub fn remote_name(mut self, name: impl Into<String>) -> Self {
+        self.remote_name = Some(name.into());
+        self
+    }
+
+    /// Set the name of the branch which is pulled and pushed.
+    ///
+    /// Changes are always committed to the branch which is checked out, so
+    /// this should only be needed if it can't be detected (for example, if
+    /// `HEAD` is detached).
+    pub fn branch(mut self, name: impl Into<String>) -> Self {
+        self.branch = Some(name.into());
+        self
+    }
+
+    /// Check the dependencies of inserted crates.
+    ///
+    /// See [`Builder::validate_dependencies`] for details.
+    pub fn validate_dependencies(mut self) -> Self {
+        self.validate_dependencies = true;
+        self
+    }
+}
+
+impl From<LockOptions> for OpenOptions {
+    fn from(lock: LockOptions) -> Self {
+        Self::new().lock(lock)
+    }
+}
+
 impl Index {
     /// Create a new index.
     ///
@@ -114,6 +260,9 @@ impl Index {
     /// Additional options can be set using the builder API (see
     /// [`Builder`] for options).
     ///
+    /// The download URL is a [`DownloadTemplate`], which is validated when the
+    /// index is built.
+    ///
     /// # Example
     ///
     /// ## Basic Config
@@ -150,15 +299,21 @@ impl Index {
     /// ```
     pub fn initialise<'a>(root: impl Into<PathBuf>, download: impl Into<String>) -> Builder<'a> {
         let root = root.into();
-        let tree_builder = Tree::initialise(&root, download);
+        let tree = Tree::initialise(&root, download);
         let origin = None;
+        let remote_name = None;
+        let branch = None;
         let identity = None;
+        let sync = None;
 
         Builder {
-            tree_builder,
+            tree,
             root,
             origin,
+            remote_name,
+            branch,
             identity,
+            sync,
         }
     }
 
@@ -176,16 +331,87 @@ impl Index {
     /// # };
     /// ```
     ///
+    /// An exclusive lock is taken on the root, so that no other process can
+    /// open the index at the same time. The `origin` remote and the branch
+    /// which is checked out are used for pulling and pushing. See
+    /// [`Index::open_with`] to configure these.
+    ///
     /// # Errors
     ///
     /// This method can return an error if the filepath doesn't exist, can't be
-    /// read from, or if the index is malformed.
+    /// read from, if the index is malformed, or if another process holds a lock
+    /// on the index.
     pub async fn open(root: impl Into<PathBuf>) -> Result<Self, Error> {
+        Self::open_with(root, OpenOptions::default()).await
+    }
+
+    /// Open an existing index at the given root path, with the given options.
+    ///
+    /// The options can be [`OpenOptions`], or just the [`LockOptions`] for
+    /// locking the root (see [`Tree::open_with`] for details).
+    ///
+    /// # Example
+    /// ```no_run
+    /// use crate_index::{tree::LockOptions, Index, OpenOptions};
+    /// use std::time::Duration;
+    /// # use crate_index::Error;
+    /// # async {
+    ///
+    /// let lock_options = LockOptions::exclusive().timeout(Duration::from_secs(10));
+    /// let index = Index::open_with("/index", lock_options).await?;
+    ///
+    /// let options = OpenOptions::new().remote_name("upstream").branch("main");
+    /// let index = Index::open_with("/other-index", options).await?;
+    /// # Ok::<(), Error>(())
+    /// # };
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// This method can return an error if the filepath doesn't exist, can't be
+    /// read from, if the index is malformed, or if another process holds a
+    /// conflicting lock on the index for longer than the timeout.
+    pub async fn open_with(
+        root: impl Into<PathBuf>,
+        options: impl Into<OpenOptions>,
+    ) -> Result<Self, Error> {
+        let root = root.into();
+        let options = options.into();
+        let mut tree = Tree::open_with(&root, options.lock).await?;
+        tree.set_validate_dependencies(options.validate_dependencies);
+        let mut repo = Repository::open(&root)?;
+        let sync = None;
+
+        if let Some(name) = options.remote_name {
+            repo.set_remote(name);
+        }
+
+        if let Some(branch) = options.branch {
+            repo.set_branch(branch);
+        }
+
+        Ok(Self { tree, repo, sync })
+    }
+
+    /// Open an existing index at the given root path, skipping any lines of
+    /// the index files which cannot be parsed.
+    ///
+    /// The lines which couldn't be parsed are returned alongside the index.
+    /// See [`Tree::open_lenient`] for details.
+    ///
+    /// # Errors
+    ///
+    /// This method can return an error if the filepath doesn't exist, can't be
+    /// read from, or if the config file or git repository is malformed.
+    pub async fn open_lenient(
+        root: impl Into<PathBuf>,
+    ) -> Result<(Self, Vec<CorruptionError>), Error> {
         let root = root.into();
-        let tree = Tree::open(&root).await?;
+        let (tree, errors) = Tree::open_lenient(&root).await?;
         let repo = Repository::open(&root)?;
+        let sync = None;
 
-        Ok(Self { tree, repo })
+        Ok((Self { tree, repo, sync }, errors))
     }
 
     /// Insert a crate [`Record`] into the index.
@@ -203,13 +429,85 @@ impl Index {
     /// data.
     pub async fn insert(&mut self, record: Record) -> WrappedResult<(), ValidationError, Error> {
         let commit_message = format!("updating crate `{}#{}`", record.name(), record.version());
-        if let Err(e) = self.tree.insert(record).await? {
+
+        let mut attempt = 0;
+        loop {
+            self.pull().await?;
+
+            let path = match self.tree.insert(record.clone()).await? {
+                Ok(path) => path,
+                Err(e) => return Ok(Err(e)),
+            };
+
+            self.repo.add_path(path)?;
+            if self.publish(&commit_message, attempt).await? {
+                return Ok(Ok(()));
+            }
+            attempt += 1;
+        }
+    }
+
+    /// Insert a crate [`Record`] into the index, and store its `.crate` file.
+    ///
+    /// The file is stored before the record is committed, so the index never
+    /// refers to a file which doesn't exist. The record is validated before
+    /// anything is stored. If the insert still fails (for example, because
+    /// the git commit or push fails), the stored file is rolled back.
+    ///
+    /// # Errors
+    ///
+    /// ## Outer Error
+    ///
+    /// A critical error is returned if the filesystem cannot be read, a git
+    /// error occurs, or the file cannot be stored
+    ///
+    /// ## Inner Error
+    ///
+    /// A [`ValidationError`] is returned if the checksum of the `.crate` file
+    /// doesn't match the record, or the crate record contains invalid data.
+    pub async fn insert_with_artifact<S>(
+        &mut self,
+        record: Record,
+        crate_file: &[u8],
+        storage: &S,
+    ) -> WrappedResult<(), ValidationError, Error>
+    where
+        S: Storage + ?Sized,
+    {
+        let check_sum = package::check_sum(crate_file);
+        if &check_sum != record.check_sum() {
+            return Ok(Err(ValidationError::check_sum(
+                record.check_sum(),
+                check_sum,
+            )));
+        }
+
+        // the record is checked before the storage is touched, since the crate
+        // name is used to build the artifact's path
+        if let Err(e) = self.tree.validate(&record).await? {
             return Ok(Err(e));
         }
 
-        self.repo.add_all()?; //TODO: add just the required path
-        self.repo.commit(commit_message)?;
-        Ok(Ok(()))
+        let previous = storage.get(&record).await?;
+        storage.put(&record, crate_file).await?;
+
+        let stored = record.clone();
+        let result = self.insert(record).await;
+
+        if !matches!(result, Ok(Ok(()))) {
+            let rollback = match previous {
+                Some(previous) => storage.put(&stored, &previous).await,
+                None => storage.delete(&stored).await,
+            };
+
+            // a failure to roll back is only reported if the insert itself
+            // didn't fail
+            if result.is_ok() {
+                rollback?;
+            }
+        }
+
+        result
     }
 
     /// 'Yank' a [`Record`] in the index.
@@ -261,14 +559,21 @@ impl Index {
         let crate_name = crate_name.into();
         let commit_message = format!("yanking crate `{}#{}`", &crate_name, &version);
 
-        Ok(match self.tree.yank(crate_name, version).await? {
-            Ok(()) => {
-                self.repo.add_all()?; //TODO: add just the required path
-                self.repo.commit(commit_message)?;
-                Ok(())
+        let mut attempt = 0;
+        loop {
+            self.pull().await?;
+
+            let path = match self.tree.yank(&crate_name, version).await? {
+                Ok(path) => path,
+                Err(e) => return Ok(Err(e)),
+            };
+
+            self.repo.add_path(path)?;
+            if self.publish(&commit_message, attempt).await? {
+                return Ok(Ok(()));
             }
-            Err(e) => Err(e),
-        })
+            attempt += 1;
+        }
     }
 
     /// 'Unyank' a [`Record`] in the index.
@@ -318,14 +623,311 @@ impl Index {
         let crate_name = crate_name.into();
         let commit_message = format!("unyanking crate `{}#{}`", &crate_name, &version);
 
-        Ok(match self.tree.unyank(crate_name, version).await? {
-            Ok(()) => {
-                self.repo.add_all()?;
-                self.repo.commit(commit_message)?;
-                Ok(())
+        let mut attempt = 0;
+        loop {
+            self.pull().await?;
+
+            let path = match self.tree.unyank(&crate_name, version).await? {
+                Ok(path) => path,
+                Err(e) => return Ok(Err(e)),
+            };
+
+            self.repo.add_path(path)?;
+            if self.publish(&commit_message, attempt).await? {
+                return Ok(Ok(()));
             }
-            Err(e) => Err(e),
-        })
+            attempt += 1;
+        }
+    }
+
+    /// Start a [`Transaction`], which collects inserts, yanks and unyanks and
+    /// commits them all at once.
+    ///
+    /// The changes are validated together when the transaction is committed,
+    /// and are written in a single commit. If any change is rejected, none of
+    /// them are applied. This is much faster than making the changes one at a
+    /// time when importing or yanking many crates.
+    pub fn transaction(&mut self) -> Transaction<'_> {
+        Transaction::new(self)
+    }
+
+    /// Rewrite every file in the index into the canonical layout used by
+    /// crates.io, in a single commit.
+    ///
+    /// See [`Tree::normalise`] for details. No commit is made if every file is
+    /// already in the canonical layout.
+    ///
+    /// Returns the names of the crates whose files changed.
+    ///
+    /// # Errors
+    ///
+    /// A critical error is returned if the filesystem cannot be read, or a git
+    /// error occurs
+    pub async fn normalise(&mut self) -> Result<Vec<String>, Error> {
+        let mut attempt = 0;
+        loop {
+            self.pull().await?;
+
+            let changed = self.tree.normalise().await?;
+            if changed.is_empty() {
+                return Ok(changed);
+            }
+
+            self.repo.add_paths(changed.iter().map(tree::get_path))?;
+            if self
+                .publish(
+                    format!("normalising {} index files", changed.len()),
+                    attempt,
+                )
+                .await?
+            {
+                return Ok(changed);
+            }
+            attempt += 1;
+        }
+    }
+
+    /// Replace the git history of the index with a single commit, keeping the
+    /// old history on a snapshot branch.
+    ///
+    /// This is the procedure crates.io uses to stop its index from growing too
+    /// slow to clone. The index files are unchanged. See
+    /// [`Repository::squash_history`] for details.
+    ///
+    /// If `push` is `true`, the latest changes are pulled first, and then the
+    /// snapshot branch is pushed and the squashed branch is force-pushed to
+    /// the origin (see [`Repository::push_squashed`]). Any clones of the index
+    /// will need to be re-cloned, or reset to the new history.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # use crate_index::{Index, Error};
+    /// # async {
+    /// let mut index = Index::open("/index").await?;
+    /// index.squash_history("snapshot-2020-01-01", true).await?;
+    /// # Ok::<(), Error>(())
+    /// # };
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if the snapshot branch already exists, the pull
+    /// or push fails, the origin has moved on since the pull, or another git
+    /// error occurs
+    pub async fn squash_history(
+        &mut self,
+        snapshot_branch_name: impl AsRef<str>,
+        push: bool,
+    ) -> Result<(), Error> {
+        if push {
+            self.repo.pull()??;
+            self.tree.reload().await?;
+        }
+
+        self.repo.squash_history(&snapshot_branch_name)?;
+
+        if push {
+            self.repo.push_squashed(&snapshot_branch_name)?;
+        }
+
+        Ok(())
+    }
+
+    /// Check every file in the index for consistency.
+    ///
+    /// See [`Tree::check`] for details.
+    ///
+    /// # Errors
+    ///
+    /// A critical error is returned if the filesystem cannot be read
+    pub async fn check(&self) -> Result<Report, Error> {
+        Ok(self.tree.check().await?)
+    }
+
+    /// Check every file in the index for consistency, and commit repairs for
+    /// the problems which can be fixed without losing information.
+    ///
+    /// See [`Tree::repair`] for details. No commit is made if nothing was
+    /// repaired.
+    ///
+    /// # Errors
+    ///
+    /// A critical error is returned if the filesystem cannot be read or
+    /// written to, or a git error occurs
+    pub async fn repair(&mut self) -> Result<Report, Error> {
+        let mut attempt = 0;
+        loop {
+            self.pull().await?;
+
+            let report = self.tree.repair().await?;
+            if report.repairs().is_empty() {
+                return Ok(report);
+            }
+
+            for path in report.touched_paths() {
+                if self.root().join(path).exists().await {
+                    self.repo.add_path(path)?;
+                } else {
+                    self.repo.remove_path(path)?;
+                }
+            }
+            let commit_message = format!("repairing {} index problems", report.repairs().len());
+            if self.publish(commit_message, attempt).await? {
+                return Ok(report);
+            }
+            attempt += 1;
+        }
+    }
+
+    /// In sync mode, pull from the origin before making a change, so that the
+    /// change is validated against the latest version of the index.
+    async fn pull(&mut self) -> Result<(), Error> {
+        if self.sync.is_some() {
+            self.repo.pull()??;
+            self.tree.reload().await?;
+        }
+
+        Ok(())
+    }
+
+    /// Commit the staged changes and, in sync mode, push them to the origin.
+    ///
+    /// If the push fails, the commit is discarded and the tree is reloaded, so
+    /// the index is left exactly as it was before the change. Returns `false`
+    /// if the push was rejected and the change should be made again, or an
+    /// error once every retry has been used.
+    async fn publish(&mut self, message: impl AsRef<str>, attempt: u32) -> Result<bool, Error> {
+        let head = self.repo.head()?;
+        self.repo.commit(message)?;
+
+        let retries = match self.sync {
+            Some(retries) => retries,
+            None => return Ok(true),
+        };
+
+        let pushed = self.repo.try_push();
+        if let Ok(true) = pushed {
+            return Ok(true);
+        }
+
+        self.repo.reset(head)?;
+        self.tree.reload().await?;
+
+        match pushed {
+            Err(e) => Err(e.into()),
+            Ok(_) if attempt < retries => Ok(false),
+            Ok(_) => Err(git2::Error::new(
+                git2::ErrorCode::NotFastForward,
+                git2::ErrorClass::Reference,
+                format!("the remote rejected the push ({} attempts)", attempt + 1),
+            )
+            .into()),
+        }
+    }
+
+    /// Get all of the [`Record`]s in the index for a given crate.
+    ///
+    /// The records are returned in ascending order of version.
+    ///
+    /// # Errors
+    ///
+    /// ## Outer Error
+    ///
+    /// A critical error is returned if the filesystem cannot be read
+    ///
+    /// ## Inner Error
+    ///
+    /// A [`NotFoundError`] is returned if the crate can not be found in the
+    /// index
+    pub async fn get(
+        &self,
+        crate_name: impl Into<String>,
+    ) -> WrappedResult<Vec<Record>, NotFoundError, Error> {
+        Ok(self.tree.get(crate_name).await?)
+    }
+
+    /// Get the [`Record`] for a specific version of a crate.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use crate_index::{Index, Error, tree::NotFoundError};
+    /// #
+    /// # #[async_std::main]
+    /// # async fn main() -> Result<(), Error> {
+    /// #    let index = Index::open("root").await?;
+    /// #
+    /// let crate_name = "some-crate";
+    /// let version = "0.1.0".parse().unwrap();
+    ///
+    /// match index.get_version(crate_name, &version).await? {
+    ///     Ok(record) => println!("checksum: {}", record.check_sum()),
+    ///     Err(NotFoundError::Crate(e)) => println!("crate not found! ({})", e.crate_name()),
+    ///     Err(NotFoundError::Version(e)) => println!("version not found! ({})", e.version()),
+    /// }
+    /// #
+    /// #     Ok(())
+    /// # }
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// ## Outer Error
+    ///
+    /// A critical error is returned if the filesystem cannot be read
+    ///
+    /// ## Inner Error
+    ///
+    /// A [`NotFoundError`] is returned if either the crate or the specified
+    /// version can not be found in the index
+    pub async fn get_version(
+        &self,
+        crate_name: impl Into<String>,
+        version: &Version,
+    ) -> WrappedResult<Record, NotFoundError, Error> {
+        Ok(self.tree.get_version(crate_name, version).await?)
+    }
+
+    /// Get the newest non-yanked version of a crate which matches a version
+    /// requirement, and can be built with the given version of `rustc`.
+    ///
+    /// Returns `None` if there is no such version.
+    ///
+    /// # Errors
+    ///
+    /// ## Outer Error
+    ///
+    /// A critical error is returned if the filesystem cannot be read
+    ///
+    /// ## Inner Error
+    ///
+    /// A [`NotFoundError`] is returned if the crate can not be found in the
+    /// index
+    pub async fn latest_compatible(
+        &self,
+        crate_name: impl Into<String>,
+        requirement: &VersionReq,
+        rustc: &Version,
+    ) -> WrappedResult<Option<Record>, NotFoundError, Error> {
+        Ok(self
+            .tree
+            .latest_compatible(crate_name, requirement, rustc)
+            .await?)
+    }
+
+    /// Enable or disable dependency validation for subsequent inserts.
+    ///
+    /// See [`Builder::validate_dependencies`] for details.
+    pub fn set_validate_dependencies(&mut self, enabled: bool) {
+        self.tree.set_validate_dependencies(enabled);
+    }
+
+    /// Enable sync mode with the given number of retries, or disable it with
+    /// `None`.
+    ///
+    /// See [`Builder::sync`] for details.
+    pub fn set_sync(&mut self, retries: Option<u32>) {
+        self.sync = retries;
     }
 
     /// The location on the filesystem of the root of the index
@@ -340,6 +942,12 @@ impl Index {
         self.tree.download()
     }
 
+    /// The template for the download URLs of .crate files
+    #[must_use]
+    pub fn download_template(&self) -> &DownloadTemplate {
+        self.tree.download_template()
+    }
+
     /// The Url of the API
     #[must_use]
     pub fn api(&self) -> Option<&Url> {
@@ -353,6 +961,11 @@ impl Index {
         self.tree.allowed_registries()
     }
 
+    #[cfg(feature = "server")]
+    pub(crate) fn tree(&self) -> &Tree {
+        &self.tree
+    }
+
     /// Split this [`Index`] into its constituent parts
     #[must_use]
     pub fn into_parts(self) -> (Tree, Repository) {
@@ -367,15 +980,56 @@ pub enum Error {
     #[error("IO Error")]
     Io(#[from] IoError),
 
-    /// libgit2 error
-    #[error("Git Error")]
+    /// A file in the index cannot be parsed
+    #[error(transparent)]
+    Corruption(#[from] CorruptionError),
+
+    /// The index is locked by another process
+    #[error(transparent)]
+    Locked(#[from] LockedError),
+
+    /// The index was opened with a shared lock, and cannot be modified
+    #[error("the index was opened with a shared lock, and is read-only")]
+    ReadOnly,
+
+    /// libgit2 error
+    #[error("Git Error")]
     Git(#[from] git2::Error),
+
+    /// Changes pulled from the origin conflict with local changes
+    #[error(transparent)]
+    Merge(#[from] MergeError),
+
+    /// The download template is not valid
+    #[error("Invalid download template")]
+    Template(#[from] TemplateError),
+}
+
+impl From<TreeError> for Error {
+    fn from(error: TreeError) -> Self {
+        match error {
+            TreeError::Io(error) => Self::Io(error),
+            TreeError::Corruption(error) => Self::Corruption(error),
+            TreeError::Locked(error) => Self::Locked(error),
+            TreeError::ReadOnly => Self::ReadOnly,
+            TreeError::Template(error) => Self::Template(error),
+        }
+    }
 }
 
 #[cfg(test)]
+#[allow(clippy::manual_assert)]
 mod tests {
-    use super::Index;
-    use crate::{index::Record, Url};
+    use super::{Error, Index, LockOptions, OpenOptions};
+    use crate::{
+        index::Record,
+        package,
+        record::Dependency,
+        storage::{LocalStorage, Storage},
+        tree::TransactionError,
+        validate::Error as ValidationError,
+        Url,
+    };
     use async_std::path::PathBuf;
     use semver::Version;
     use test_case::test_case;
@@ -411,6 +1065,43 @@ mod tests {
         assert_eq!(index.allowed_registries(), &expected_allowed_registries);
     }
 
+    #[async_std::test]
+    async fn invalid_download_template() {
+        let temp_dir = tempfile::tempdir().unwrap();
+
+        let result = Index::initialise(temp_dir.path(), "not a url")
+            .identity("dummy username", "dummy@email.com")
+            .build()
+            .await;
+
+        match result {
+            Err(Error::Template(e)) => assert_eq!(e.template(), "not a url"),
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        // nothing is written to the filesystem
+        assert!(!temp_dir.path().join("config.json").exists());
+    }
+
+    #[async_std::test]
+    async fn download_url() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let download = "https://my-crates-server.com/api/v1/crates";
+
+        let index = Index::initialise(temp_dir.path(), download)
+            .identity("dummy username", "dummy@email.com")
+            .build()
+            .await
+            .unwrap();
+
+        let record = metadata("Some-Name", "0.1.0");
+
+        assert_eq!(
+            index.download_template().render(&record).unwrap().as_str(),
+            "https://my-crates-server.com/api/v1/crates/Some-Name/0.1.0/download"
+        );
+    }
+
     #[test_case("Some-Name", "0.1.1" ; "when used properly")]
     #[test_case("Some_Name", "0.1.1" => panics "invalid" ; "when crate names differ only by hyphens and underscores")]
     #[test_case("some_name", "0.1.1" => panics "invalid" ; "when crate names differ only by capitalisation")]
@@ -453,6 +1144,742 @@ mod tests {
         Record::new(name, Version::parse(version).unwrap(), "checksum")
     }
 
+    #[async_std::test]
+    async fn validate_dependencies_on_open() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let download = "https://my-crates-server.com/api/v1/crates/{crate}/{version}/download";
+
+        let index = Index::initialise(temp_dir.path(), download)
+            .identity("dummy username", "dummy@email.com")
+            .validate_dependencies()
+            .build()
+            .await
+            .unwrap();
+        drop(index);
+
+        let dependency = Dependency::builder("missing", "1.0".parse().unwrap())
+            .build()
+            .unwrap();
+        let record = Record::builder("Some-Name", Version::new(0, 1, 0), "checksum")
+            .dependency(dependency)
+            .build()
+            .unwrap();
+
+        let options = OpenOptions::new().validate_dependencies();
+        let mut index = Index::open_with(temp_dir.path(), options).await.unwrap();
+        match index.insert(record).await.unwrap() {
+            Err(ValidationError::UnresolvedDependency { .. }) => (),
+            other => panic!("expected an unresolved dependency, got {:?}", other),
+        }
+    }
+
+    #[async_std::test]
+    async fn insert_with_artifact() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let storage_dir = tempfile::tempdir().unwrap();
+        let download = "https://my-crates-server.com/api/v1/crates/{crate}/{version}/download";
+
+        let mut index = Index::initialise(temp_dir.path(), download)
+            .identity("dummy username", "dummy@email.com")
+            .build()
+            .await
+            .unwrap();
+        let storage = LocalStorage::new(storage_dir.path(), "{crate}/{version}/download");
+
+        let crate_file = b"version 0.1.0";
+        let record = Record::new(
+            "Some-Name",
+            Version::new(0, 1, 0),
+            package::check_sum(crate_file),
+        );
+
+        index
+            .insert_with_artifact(record.clone(), crate_file, &storage)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(storage.verify(&record).await.unwrap());
+        assert_eq!(
+            index.get("Some-Name").await.unwrap().unwrap(),
+            vec![record.clone()]
+        );
+
+        // a checksum mismatch is rejected without storing anything
+        let other = Record::new("Other-Name", Version::new(0, 1, 0), "checksum");
+        match index
+            .insert_with_artifact(other.clone(), crate_file, &storage)
+            .await
+            .unwrap()
+        {
+            Err(ValidationError::CheckSum { .. }) => (),
+            result => panic!("unexpected result: {:?}", result),
+        }
+        assert_eq!(storage.get(&other).await.unwrap(), None);
+
+        // a rejected record with no previous artifact is removed
+        let invalid = Record::new("nul", Version::new(0, 1, 0), package::check_sum(crate_file));
+        assert!(index
+            .insert_with_artifact(invalid.clone(), crate_file, &storage)
+            .await
+            .unwrap()
+            .is_err());
+        assert_eq!(storage.get(&invalid).await.unwrap(), None);
+
+        // an invalid name is rejected before it can be used as a path
+        let outside = Record::new(
+            "../outside",
+            Version::new(0, 1, 0),
+            package::check_sum(crate_file),
+        );
+        match index
+            .insert_with_artifact(outside, crate_file, &storage)
+            .await
+            .unwrap()
+        {
+            Err(ValidationError::InvalidName { .. }) => (),
+            other => panic!("expected an invalid name, got {:?}", other),
+        }
+        assert!(!storage_dir.path().join("../outside").exists());
+
+        // the artifact is removed if committing the record fails
+        index.set_sync(Some(0));
+        let other = Record::new(
+            "Other-Name",
+            Version::new(0, 1, 0),
+            package::check_sum(crate_file),
+        );
+        assert!(index
+            .insert_with_artifact(other.clone(), crate_file, &storage)
+            .await
+            .is_err());
+        assert_eq!(storage.get(&other).await.unwrap(), None);
+
+        // an artifact which was already stored is restored if committing the
+        // record fails
+        let previous_file = b"a previous 0.2.0";
+        let replaced = Record::new(
+            "Some-Name",
+            Version::new(0, 2, 0),
+            package::check_sum(crate_file),
+        );
+        storage.put(&replaced, previous_file).await.unwrap();
+        assert!(index
+            .insert_with_artifact(replaced.clone(), crate_file, &storage)
+            .await
+            .is_err());
+        assert_eq!(
+            storage.get(&replaced).await.unwrap(),
+            Some(previous_file.to_vec())
+        );
+        assert_eq!(
+            index.get("Some-Name").await.unwrap().unwrap(),
+            vec![record.clone()]
+        );
+    }
+
+    #[async_std::test]
+    async fn normalise() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let download = "https://my-crates-server.com/api/v1/crates/{crate}/{version}/download";
+
+        let index = Index::initialise(temp_dir.path(), download)
+            .identity("dummy username", "dummy@email.com")
+            .build()
+            .await
+            .unwrap();
+
+        for (path, line) in &[
+            (
+                "so/me/some-name",
+                r#"{"vers":"0.1.0","name":"some-name","cksum":"checksum"}"#,
+            ),
+            (
+                "ot/he/other-name",
+                r#"{"vers":"0.1.0","name":"other-name","cksum":"checksum"}"#,
+            ),
+        ] {
+            let path = temp_dir.path().join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, line).unwrap();
+        }
+        index.repo.add_all().unwrap();
+        index.repo.commit("rewriting index files").unwrap();
+        drop(index);
+
+        let mut index = Index::open(temp_dir.path()).await.unwrap();
+        let repo = git2::Repository::open(temp_dir.path()).unwrap();
+        let head = repo.head().unwrap().peel_to_commit().unwrap().id();
+
+        assert_eq!(
+            index.normalise().await.unwrap(),
+            vec!["other-name", "some-name"]
+        );
+
+        // both files are rewritten in a single commit
+        let commit = repo.head().unwrap().peel_to_commit().unwrap();
+        assert_eq!(commit.parent_id(0).unwrap(), head);
+        assert_eq!(commit.message(), Some("normalising 2 index files"));
+
+        // nothing is committed when the index is already normalised
+        assert!(index.normalise().await.unwrap().is_empty());
+        assert_eq!(
+            repo.head().unwrap().peel_to_commit().unwrap().id(),
+            commit.id()
+        );
+    }
+
+    #[async_std::test]
+    async fn corrupt_index() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let download = "https://my-crates-server.com/api/v1/crates/{crate}/{version}/download";
+
+        let mut index = Index::initialise(temp_dir.path(), download)
+            .identity("dummy username", "dummy@email.com")
+            .build()
+            .await
+            .unwrap();
+        index
+            .insert(metadata("Some-Name", "0.1.0"))
+            .await
+            .unwrap()
+            .unwrap();
+
+        let path = temp_dir.path().join("so/me/Some-Name");
+        std::fs::write(&path, "{}").unwrap();
+
+        match index.insert(metadata("Some-Name", "0.2.0")).await {
+            Err(Error::Corruption(e)) => assert_eq!(e.line(), 1),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        drop(index);
+
+        let (index, errors) = Index::open_lenient(temp_dir.path()).await.unwrap();
+        assert_eq!(errors.len(), 1);
+        assert!(index.get("Some-Name").await.unwrap().unwrap().is_empty());
+        drop(index);
+
+        std::fs::write(temp_dir.path().join("config.json"), "[]").unwrap();
+        match Index::open(temp_dir.path()).await {
+            Err(Error::Corruption(e)) => assert!(e.path().ends_with("config.json")),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[async_std::test]
+    async fn repair() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let download = "https://my-crates-server.com/api/v1/crates/{crate}/{version}/download";
+
+        let index = Index::initialise(temp_dir.path(), download)
+            .identity("dummy username", "dummy@email.com")
+            .build()
+            .await
+            .unwrap();
+
+        let path = temp_dir.path().join("wr/on/some-name");
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, metadata("some-name", "0.1.0").to_string()).unwrap();
+        index.repo.add_all().unwrap();
+        index.repo.commit("adding a misplaced file").unwrap();
+        drop(index);
+
+        let mut index = Index::open(temp_dir.path()).await.unwrap();
+        assert_eq!(index.check().await.unwrap().repairs().len(), 0);
+
+        let report = index.repair().await.unwrap();
+        assert_eq!(report.repairs().len(), 1);
+
+        // the file is moved in a single commit
+        let repo = git2::Repository::open(temp_dir.path()).unwrap();
+        let commit = repo.head().unwrap().peel_to_commit().unwrap();
+        assert_eq!(commit.message(), Some("repairing 1 index problems"));
+        let tree = commit.tree().unwrap();
+        assert!(tree.get_path("so/me/some-name".as_ref()).is_ok());
+        assert!(tree.get_path("wr/on/some-name".as_ref()).is_err());
+
+        // nothing is committed when there is nothing to repair
+        index.repair().await.unwrap();
+        assert_eq!(
+            repo.head().unwrap().peel_to_commit().unwrap().id(),
+            commit.id()
+        );
+    }
+
+    #[async_std::test]
+    async fn transaction() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let download = "https://my-crates-server.com/api/v1/crates/{crate}/{version}/download";
+
+        let mut index = Index::initialise(temp_dir.path(), download)
+            .identity("dummy username", "dummy@email.com")
+            .build()
+            .await
+            .unwrap();
+        index
+            .insert(metadata("Some-Name", "0.1.0"))
+            .await
+            .unwrap()
+            .unwrap();
+
+        let repo = git2::Repository::open(temp_dir.path()).unwrap();
+        let head = || repo.head().unwrap().peel_to_commit().unwrap();
+        let before = head();
+
+        let changed = index
+            .transaction()
+            .insert(metadata("Some-Name", "0.2.0"))
+            .insert(metadata("Some-Name", "0.3.0"))
+            .insert(metadata("other-name", "0.1.0"))
+            .yank("Some-Name", Version::new(0, 1, 0))
+            .commit()
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(changed, vec!["Some-Name", "other-name"]);
+
+        // all of the changes are in a single commit
+        let after = head();
+        assert_eq!(after.parent_id(0).unwrap(), before.id());
+        assert_eq!(after.message(), Some("applying 4 changes to 2 crates"));
+        assert_eq!(index.get("Some-Name").await.unwrap().unwrap().len(), 3);
+
+        // a rejected change rolls back the whole transaction
+        let result = index
+            .transaction()
+            .insert(metadata("new-name", "0.1.0"))
+            .unyank("missing", Version::new(0, 1, 0))
+            .commit()
+            .await
+            .unwrap();
+        assert!(matches!(result, Err(TransactionError::NotFound(_))));
+        assert!(index.get("new-name").await.unwrap().is_err());
+        assert_eq!(head().id(), after.id());
+
+        // an empty transaction doesn't commit
+        assert!(index
+            .transaction()
+            .commit()
+            .await
+            .unwrap()
+            .unwrap()
+            .is_empty());
+        assert_eq!(head().id(), after.id());
+    }
+
+    #[async_std::test]
+    async fn untracked_files_stay_out_of_commits() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let download = "https://my-crates-server.com/api/v1/crates/{crate}/{version}/download";
+
+        let mut index = Index::initialise(temp_dir.path(), download)
+            .identity("dummy username", "dummy@email.com")
+            .build()
+            .await
+            .unwrap();
+
+        std::fs::write(temp_dir.path().join("notes.txt"), "not part of the index").unwrap();
+        std::fs::create_dir(temp_dir.path().join("scratch")).unwrap();
+        std::fs::write(temp_dir.path().join("scratch/stray"), "").unwrap();
+
+        let repo = git2::Repository::open(temp_dir.path()).unwrap();
+        let assert_committed = |expected: &[&str]| {
+            let tree = repo.head().unwrap().peel_to_tree().unwrap();
+            assert!(tree.get_name("notes.txt").is_none());
+            assert!(tree.get_name("scratch").is_none());
+            for path in expected {
+                assert!(
+                    tree.get_path(std::path::Path::new(path)).is_ok(),
+                    "{}",
+                    path
+                );
+            }
+        };
+
+        let version = Version::new(0, 1, 0);
+        index
+            .insert(metadata("Some-Name", "0.1.0"))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_committed(&["so/me/Some-Name"]);
+
+        index.yank("Some-Name", &version).await.unwrap().unwrap();
+        assert_committed(&["so/me/Some-Name"]);
+
+        index.unyank("Some-Name", &version).await.unwrap().unwrap();
+        assert_committed(&["so/me/Some-Name"]);
+
+        index
+            .transaction()
+            .insert(metadata("other-name", "0.1.0"))
+            .yank("Some-Name", version)
+            .commit()
+            .await
+            .unwrap()
+            .unwrap();
+        assert_committed(&["so/me/Some-Name", "ot/he/other-name"]);
+
+        // the stray files are still there, and still untracked
+        let status = repo.status_file(std::path::Path::new("notes.txt")).unwrap();
+        assert!(status.is_wt_new());
+    }
+
+    /// Clone an origin into a temporary directory, and open it as an index in
+    /// sync mode
+    async fn clone_origin(origin: &Url, retries: u32) -> (tempfile::TempDir, Index) {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let repo = git2::Repository::clone(origin.as_str(), temp_dir.path()).unwrap();
+        let mut config = repo.config().unwrap();
+        config.set_str("user.name", "dummy username").unwrap();
+        config.set_str("user.email", "dummy@email.com").unwrap();
+
+        let mut index = Index::open(temp_dir.path()).await.unwrap();
+        index.set_sync(Some(retries));
+        (temp_dir, index)
+    }
+
+    /// The crate names in the index files at the head of a branch of a bare
+    /// origin
+    fn pushed_crates(origin_dir: &std::path::Path, branch: &str) -> Vec<String> {
+        let repo = git2::Repository::open_bare(origin_dir).unwrap();
+        let tree = repo
+            .find_reference(&format!("refs/heads/{}", branch))
+            .unwrap()
+            .peel_to_tree()
+            .unwrap();
+
+        let mut names = Vec::new();
+        tree.walk(git2::TreeWalkMode::PreOrder, |_, entry| {
+            if entry.kind() == Some(git2::ObjectType::Blob) && entry.name() != Some("config.json") {
+                names.push(entry.name().unwrap().to_string());
+            }
+            git2::TreeWalkResult::Ok
+        })
+        .unwrap();
+        names.sort();
+        names
+    }
+
+    #[async_std::test]
+    async fn sync() {
+        let origin_dir = tempfile::tempdir().unwrap();
+        git2::Repository::init_bare(origin_dir.path()).unwrap();
+        let origin = Url::from_file_path(origin_dir.path()).unwrap();
+        let temp_dir = tempfile::tempdir().unwrap();
+        let download = "https://my-crates-server.com/api/v1/crates/{crate}/{version}/download";
+
+        let mut first = Index::initialise(temp_dir.path(), download)
+            .origin(origin.clone())
+            .identity("dummy username", "dummy@email.com")
+            .sync(3)
+            .build()
+            .await
+            .unwrap();
+        let (_second_dir, mut second) = clone_origin(&origin, 3).await;
+
+        // every change is pushed
+        first
+            .insert(metadata("Some-Name", "0.1.0"))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            pushed_crates(origin_dir.path(), "master"),
+            vec!["Some-Name"]
+        );
+
+        // changes are validated against the latest version of the origin
+        assert!(second
+            .insert(metadata("some_name", "0.1.0"))
+            .await
+            .unwrap()
+            .is_err());
+        second
+            .insert(metadata("other-name", "0.1.0"))
+            .await
+            .unwrap()
+            .unwrap();
+        second
+            .yank("Some-Name", &Version::new(0, 1, 0))
+            .await
+            .unwrap()
+            .unwrap();
+
+        first
+            .transaction()
+            .insert(metadata("Some-Name", "0.2.0"))
+            .insert(metadata("third-name", "0.1.0"))
+            .commit()
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            pushed_crates(origin_dir.path(), "master"),
+            vec!["Some-Name", "other-name", "third-name"]
+        );
+        assert!(first
+            .get_version("Some-Name", &Version::new(0, 1, 0))
+            .await
+            .unwrap()
+            .unwrap()
+            .yanked());
+    }
+
+    #[async_std::test]
+    async fn failed_push_is_discarded() {
+        let origin_dir = tempfile::tempdir().unwrap();
+        git2::Repository::init_bare(origin_dir.path()).unwrap();
+        let origin = Url::from_file_path(origin_dir.path()).unwrap();
+        let temp_dir = tempfile::tempdir().unwrap();
+        let download = "https://my-crates-server.com/api/v1/crates/{crate}/{version}/download";
+
+        let mut index = Index::initialise(temp_dir.path(), download)
+            .origin(origin)
+            .identity("dummy username", "dummy@email.com")
+            .sync(1)
+            .build()
+            .await
+            .unwrap();
+        let repo = git2::Repository::open(temp_dir.path()).unwrap();
+        let head = repo.head().unwrap().target().unwrap();
+
+        // the origin can't update its branch while another process holds it
+        let lock = origin_dir.path().join("refs/heads/master.lock");
+        std::fs::write(&lock, "").unwrap();
+
+        assert!(index.insert(metadata("Some-Name", "0.1.0")).await.is_err());
+        assert_eq!(repo.head().unwrap().target().unwrap(), head);
+        assert!(!temp_dir.path().join("so/me/Some-Name").exists());
+        assert!(index.get("Some-Name").await.unwrap().is_err());
+
+        // the failed change isn't published along with the next one
+        std::fs::remove_file(&lock).unwrap();
+        index
+            .insert(metadata("other-name", "0.1.0"))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            pushed_crates(origin_dir.path(), "master"),
+            vec!["other-name"]
+        );
+    }
+
+    #[async_std::test]
+    async fn sync_custom_branch_and_remote() {
+        let origin_dir = tempfile::tempdir().unwrap();
+        git2::Repository::init_opts(
+            origin_dir.path(),
+            git2::RepositoryInitOptions::new()
+                .bare(true)
+                .initial_head("main"),
+        )
+        .unwrap();
+        let origin = Url::from_file_path(origin_dir.path()).unwrap();
+        let temp_dir = tempfile::tempdir().unwrap();
+        let download = "https://my-crates-server.com/api/v1/crates/{crate}/{version}/download";
+
+        let mut first = Index::initialise(temp_dir.path(), download)
+            .origin(origin.clone())
+            .remote_name("upstream")
+            .branch("main")
+            .identity("dummy username", "dummy@email.com")
+            .sync(3)
+            .build()
+            .await
+            .unwrap();
+        first
+            .insert(metadata("Some-Name", "0.1.0"))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(pushed_crates(origin_dir.path(), "main"), vec!["Some-Name"]);
+        let origin_repo = git2::Repository::open_bare(origin_dir.path()).unwrap();
+        assert!(origin_repo.find_reference("refs/heads/master").is_err());
+
+        // a clone checks out 'main', which is detected when it is opened
+        let (_second_dir, mut second) = clone_origin(&origin, 3).await;
+        second
+            .insert(metadata("other-name", "0.1.0"))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            pushed_crates(origin_dir.path(), "main"),
+            vec!["Some-Name", "other-name"]
+        );
+
+        // the remote name has to be given when the index is reopened
+        drop(first);
+        let options = OpenOptions::new().remote_name("upstream");
+        let mut first = Index::open_with(temp_dir.path(), options).await.unwrap();
+        first.set_sync(Some(3));
+        first
+            .insert(metadata("third-name", "0.1.0"))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            pushed_crates(origin_dir.path(), "main"),
+            vec!["Some-Name", "other-name", "third-name"]
+        );
+    }
+
+    #[async_std::test]
+    async fn squash_history() {
+        let origin_dir = tempfile::tempdir().unwrap();
+        git2::Repository::init_bare(origin_dir.path()).unwrap();
+        let origin = Url::from_file_path(origin_dir.path()).unwrap();
+        let temp_dir = tempfile::tempdir().unwrap();
+        let download = "https://my-crates-server.com/api/v1/crates/{crate}/{version}/download";
+
+        let mut first = Index::initialise(temp_dir.path(), download)
+            .origin(origin.clone())
+            .identity("dummy username", "dummy@email.com")
+            .sync(3)
+            .build()
+            .await
+            .unwrap();
+        let (_second_dir, mut second) = clone_origin(&origin, 3).await;
+
+        first
+            .insert(metadata("Some-Name", "0.1.0"))
+            .await
+            .unwrap()
+            .unwrap();
+        second
+            .insert(metadata("other-name", "0.1.0"))
+            .await
+            .unwrap()
+            .unwrap();
+
+        // the change from the other clone is pulled before squashing
+        first.squash_history("snapshot", true).await.unwrap();
+        assert_eq!(
+            pushed_crates(origin_dir.path(), "master"),
+            vec!["Some-Name", "other-name"]
+        );
+        assert_eq!(
+            pushed_crates(origin_dir.path(), "snapshot"),
+            vec!["Some-Name", "other-name"]
+        );
+
+        let origin_repo = git2::Repository::open_bare(origin_dir.path()).unwrap();
+        let head = origin_repo.refname_to_id("refs/heads/master").unwrap();
+        let snapshot = origin_repo.refname_to_id("refs/heads/snapshot").unwrap();
+        let history_len = |commit| {
+            let mut revwalk = origin_repo.revwalk().unwrap();
+            revwalk.push(commit).unwrap();
+            revwalk.count()
+        };
+        assert_eq!(history_len(head), 1);
+        assert_eq!(history_len(snapshot), 3);
+        assert_eq!(
+            origin_repo.find_commit(head).unwrap().tree_id(),
+            origin_repo.find_commit(snapshot).unwrap().tree_id()
+        );
+
+        // changes can be made on top of the squashed history
+        first
+            .insert(metadata("Some-Name", "0.2.0"))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            history_len(origin_repo.refname_to_id("refs/heads/master").unwrap()),
+            2
+        );
+    }
+
+    #[test]
+    fn sync_concurrent_publishers() {
+        let origin_dir = tempfile::tempdir().unwrap();
+        git2::Repository::init_bare(origin_dir.path()).unwrap();
+        let origin = Url::from_file_path(origin_dir.path()).unwrap();
+        let temp_dir = tempfile::tempdir().unwrap();
+        let download = "https://my-crates-server.com/api/v1/crates/{crate}/{version}/download";
+
+        async_std::task::block_on(
+            Index::initialise(temp_dir.path(), download)
+                .origin(origin.clone())
+                .identity("dummy username", "dummy@email.com")
+                .sync(0)
+                .build(),
+        )
+        .unwrap();
+
+        let publishers: Vec<_> = (0..3)
+            .map(|publisher| {
+                let origin = origin.clone();
+                std::thread::spawn(move || {
+                    async_std::task::block_on(async {
+                        let (_temp_dir, mut index) = clone_origin(&origin, 50).await;
+                        for crate_number in 0..3 {
+                            let name = format!("crate-{}-{}", publisher, crate_number);
+                            index
+                                .insert(metadata(&name, "0.1.0"))
+                                .await
+                                .unwrap()
+                                .unwrap();
+                        }
+                    });
+                })
+            })
+            .collect();
+
+        for publisher in publishers {
+            publisher.join().unwrap();
+        }
+
+        // every publisher's changes reach the origin, even though the pushes
+        // race each other
+        assert_eq!(pushed_crates(origin_dir.path(), "master").len(), 9);
+    }
+
+    #[async_std::test]
+    async fn locked() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let download = "https://my-crates-server.com/api/v1/crates/{crate}/{version}/download";
+
+        let mut index = Index::initialise(temp_dir.path(), download)
+            .identity("dummy username", "dummy@email.com")
+            .build()
+            .await
+            .unwrap();
+        index
+            .insert(metadata("Some-Name", "0.1.0"))
+            .await
+            .unwrap()
+            .unwrap();
+
+        match Index::open(temp_dir.path()).await {
+            Err(Error::Locked(e)) => assert_eq!(e.root().as_os_str(), temp_dir.path().as_os_str()),
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        // the lock file is never committed
+        let repo = git2::Repository::open(temp_dir.path()).unwrap();
+        let tree = repo.head().unwrap().peel_to_tree().unwrap();
+        assert!(tree.get_name(".index.lock").is_none());
+        assert!(tree.get_name("config.json").is_some());
+        drop(index);
+
+        // several readers can share the index, but can't modify it
+        let mut reader = Index::open_with(temp_dir.path(), LockOptions::shared())
+            .await
+            .unwrap();
+        let other_reader = Index::open_with(temp_dir.path(), LockOptions::shared())
+            .await
+            .unwrap();
+        assert!(reader.get("Some-Name").await.unwrap().is_ok());
+        assert!(other_reader.get("Some-Name").await.unwrap().is_ok());
+
+        match reader.insert(metadata("Some-Name", "0.2.0")).await {
+            Err(Error::ReadOnly) => (),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
     #[test_case("Some-Name", "0.1.0"; "when crate exists and version exists")]
     #[test_case("Some-Name", "0.2.0" => panics "not found"; "when crate exists but version doesn't exist")]
     #[test_case("Other-Name", "0.2.0" => panics "not found"; "when crate doesn't exist")]
@@ -485,4 +1912,43 @@ mod tests {
             index.unyank(crate_name, &version).await.unwrap().unwrap();
         });
     }
+
+    #[test_case("Some-Name", "0.1.0"; "when crate exists and version exists")]
+    #[test_case("Some-Name", "0.2.0" => panics "not found"; "when crate exists but version doesn't exist")]
+    #[test_case("Other-Name", "0.1.0" => panics "not found"; "when crate doesn't exist")]
+    fn get_version(crate_name: &str, version: &str) {
+        let version = Version::parse(version).unwrap();
+        async_std::task::block_on(async {
+            let temp_dir = tempfile::tempdir().unwrap();
+            let root = temp_dir.path();
+            let download = "https://my-crates-server.com/api/v1/crates/{crate}/{version}/download";
+
+            let initial_metadata = metadata("Some-Name", "0.1.0");
+
+            let mut index = Index::initialise(root, download)
+                .identity("dummy username", "dummy@email.com")
+                .build()
+                .await
+                .expect("couldn't create index");
+
+            index
+                .insert(initial_metadata.clone())
+                .await
+                .unwrap()
+                .expect("couldn't insert initial metadata");
+
+            assert_eq!(
+                index.get(crate_name).await.unwrap().expect("not found"),
+                vec![initial_metadata.clone()]
+            );
+
+            let record = index
+                .get_version(crate_name, &version)
+                .await
+                .unwrap()
+                .expect("not found");
+
+            assert_eq!(record, initial_metadata);
+        });
+    }
 }
@@ -2,18 +2,34 @@
 
 //! Abstractions over a git repository containing an index.
 
-use std::{fmt, path::Path};
+use crate::{tree::LOCK_FILE, Record, WrappedResult};
+use semver::Version;
+use std::{
+    collections::{btree_map::Entry, BTreeMap},
+    fmt,
+    path::Path,
+};
 use url::Url;
 
+/// The name of the remote which is used unless another is given
+const DEFAULT_REMOTE: &str = "origin";
+
+/// The name of the branch which is used if it can't be detected from `HEAD`
+const DEFAULT_BRANCH: &str = "master";
+
 /// Representation of a git repository on the host filesystem
 pub struct Repository {
     repo: git2::Repository,
+    remote: String,
+    branch: String,
 }
 
 impl fmt::Debug for Repository {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("Repository")
             .field("repo", &self.repo.workdir())
+            .field("remote", &self.remote)
+            .field("branch", &self.branch)
             .finish()
     }
 }
@@ -26,10 +42,24 @@ pub(crate) struct Identity<'a> {
 
 impl Repository {
     /// Initialise a new git repository at the given path.
+    ///
+    /// The branch is the default for new repositories (set by
+    /// `init.defaultBranch` in the git config).
     pub fn init(root: impl AsRef<Path>) -> Result<Self, git2::Error> {
         let repo = git2::Repository::init(root)?;
 
-        Ok(Repository { repo })
+        Ok(Self::new(repo))
+    }
+
+    fn new(repo: git2::Repository) -> Self {
+        let remote = DEFAULT_REMOTE.to_string();
+        let branch = head_branch(&repo).unwrap_or_else(|| DEFAULT_BRANCH.to_string());
+
+        Repository {
+            repo,
+            remote,
+            branch,
+        }
     }
 
     /// Commit the current tree state as an "Initial commit"
@@ -49,14 +79,57 @@ impl Repository {
     }
 
     /// Open an existing repository
+    ///
+    /// The branch is the one checked out, or `master` if `HEAD` is detached.
     pub fn open(root: impl AsRef<Path>) -> Result<Self, git2::Error> {
         let repo = git2::Repository::open(root)?;
-        Ok(Repository { repo })
+        Ok(Self::new(repo))
+    }
+
+    /// The name of the remote which is pulled from and pushed to
+    #[must_use]
+    pub fn remote(&self) -> &str {
+        &self.remote
+    }
+
+    /// Set the name of the remote which is pulled from and pushed to.
+    ///
+    /// This is `origin` by default.
+    pub fn set_remote(&mut self, remote: impl Into<String>) {
+        self.remote = remote.into();
+    }
+
+    /// The name of the branch which is pulled and pushed
+    #[must_use]
+    pub fn branch(&self) -> &str {
+        &self.branch
+    }
+
+    /// Set the name of the branch which is pulled and pushed.
+    ///
+    /// By default, this is the branch which was checked out when the
+    /// repository was opened. Changes are always committed to the branch which
+    /// is checked out, so the two should match.
+    pub fn set_branch(&mut self, branch: impl Into<String>) {
+        self.branch = branch.into();
     }
 
-    /// Add a remote to the repository
+    /// Check out a branch in a repository with no commits. The branch is
+    /// created by the first commit.
+    pub(crate) fn set_initial_branch(
+        &mut self,
+        branch: impl Into<String>,
+    ) -> Result<(), git2::Error> {
+        let branch = branch.into();
+        self.repo.set_head(&format!("refs/heads/{}", branch))?;
+        self.branch = branch;
+        Ok(())
+    }
+
+    /// Add a remote to the repository, with the name given by
+    /// [`Repository::remote`]
     pub(crate) fn add_origin(&self, remote: &Url) -> Result<(), git2::Error> {
-        self.repo.remote("origin", remote.as_str())?;
+        self.repo.remote(&self.remote, remote.as_str())?;
         Ok(())
     }
 
@@ -73,17 +146,45 @@ impl Repository {
         self.repo.index()?.add_path(path.as_ref())
     }
 
+    /// Add several files to the repository by relative path
+    pub fn add_paths<P>(&self, paths: impl IntoIterator<Item = P>) -> Result<(), git2::Error>
+    where
+        P: AsRef<Path>,
+    {
+        let mut index = self.repo.index()?;
+        for path in paths {
+            index.add_path(path.as_ref())?;
+        }
+        Ok(())
+    }
+
+    /// Remove a file from the repository by relative path
+    pub fn remove_path(&self, path: impl AsRef<Path>) -> Result<(), git2::Error> {
+        self.repo.index()?.remove_path(path.as_ref())
+    }
+
     /// Add every file in the tree to the repository.
     ///
     /// everything that matches '*', that is.
     pub fn add_all(&self) -> Result<(), git2::Error> {
         let mut index = self.repo.index()?;
-        index.add_all(&["."], git2::IndexAddOption::DEFAULT, None)
+
+        // the lock file is never committed (a non-zero return skips the path)
+        let mut skip_lock_file = |path: &Path, _: &[u8]| i32::from(path == Path::new(LOCK_FILE));
+
+        index.add_all(
+            &["."],
+            git2::IndexAddOption::DEFAULT,
+            Some(&mut skip_lock_file),
+        )
     }
 
     /// Commit all staged changes
     pub fn commit(&self, message: impl AsRef<str>) -> Result<(), git2::Error> {
         let mut index = self.repo.index()?;
+        // the index is written too, so that later checkouts see the commit's
+        // files as tracked
+        index.write()?;
         let oid = index.write_tree()?;
         let signature = self.repo.signature()?;
         // let parent_commit = self.find_last_commit()?;
@@ -100,40 +201,42 @@ impl Repository {
         Ok(())
     }
 
-    fn fetch(&self) -> Result<git2::AnnotatedCommit, git2::Error> {
+    fn fetch(&self) -> Result<git2::AnnotatedCommit<'_>, git2::Error> {
         let mut fetch_options = git2::FetchOptions::new();
         fetch_options.download_tags(git2::AutotagOption::All);
 
-        self.repo
-            .find_remote("origin")?
-            .fetch(&["master"], Some(&mut fetch_options), None)?;
+        self.repo.find_remote(&self.remote)?.fetch(
+            &[&self.branch],
+            Some(&mut fetch_options),
+            None,
+        )?;
 
         let fetch_head = self.repo.find_reference("FETCH_HEAD")?;
 
         self.repo.reference_to_annotated_commit(&fetch_head)
     }
 
-    fn merge(&self, commit: &git2::AnnotatedCommit) -> Result<(), git2::Error> {
+    fn merge(&self, commit: &git2::AnnotatedCommit) -> WrappedResult<(), MergeError, git2::Error> {
         // 1. do a merge analysis
         let analysis = self.repo.merge_analysis(&[commit])?;
 
         // 2. Do the appropriate merge
         if analysis.0.is_fast_forward() {
             // do a fast forward
-            let refname = "refs/heads/master";
-            if let Ok(mut r) = self.repo.find_reference(refname) {
+            let refname = format!("refs/heads/{}", self.branch);
+            if let Ok(mut r) = self.repo.find_reference(&refname) {
                 fast_forward(&self.repo, &mut r, commit)?;
             } else {
                 // The branch doesn't exist so just set the reference to the
                 // commit directly. Usually this is because you are pulling
                 // into an empty repository.
                 self.repo.reference(
-                    refname,
+                    &refname,
                     commit.id(),
                     true,
-                    &format!("Setting {} to {}", "master", commit.id()),
+                    &format!("Setting {} to {}", self.branch, commit.id()),
                 )?;
-                self.repo.set_head(refname)?;
+                self.repo.set_head(&refname)?;
                 self.repo.checkout_head(Some(
                     git2::build::CheckoutBuilder::default()
                         .allow_conflicts(true)
@@ -146,28 +249,211 @@ impl Repository {
             let head_commit = self
                 .repo
                 .reference_to_annotated_commit(&self.repo.head()?)?;
-            normal_merge(&self.repo, &head_commit, commit)?;
-        } else {
+            if let Err(e) = normal_merge(&self.repo, &head_commit, commit)? {
+                return Ok(Err(e));
+            }
         }
-        Ok(())
+        Ok(Ok(()))
     }
 
     /// Pull all commits from the configured remote
-    pub fn pull(&self) -> Result<(), git2::Error> {
+    ///
+    /// If the local and remote branches have diverged, the two histories are
+    /// reconciled with a merge commit. Index files which have been changed on
+    /// both sides are merged record-by-record (see [`MergeError`] for the cases
+    /// which cannot be merged automatically).
+    ///
+    /// # Errors
+    ///
+    /// ## Outer Error
+    ///
+    /// A [`git2::Error`] is returned if the remote cannot be reached, or the
+    /// repository cannot be updated. Uncommitted changes are never
+    /// overwritten. If the pull would have to overwrite one, an error with the
+    /// code [`git2::ErrorCode::Conflict`] is returned, and the local
+    /// repository is left unchanged.
+    ///
+    /// ## Inner Error
+    ///
+    /// A [`MergeError`] is returned if the local and remote changes conflict.
+    /// In this case the local repository is left unchanged.
+    pub fn pull(&self) -> WrappedResult<(), MergeError, git2::Error> {
         let fetch_commit = self.fetch()?;
-        self.merge(&fetch_commit)?;
-
-        Ok(())
+        self.merge(&fetch_commit)
     }
 
     /// Push all commits to the configured remotes
+    ///
+    /// An error with the code [`git2::ErrorCode::NotFastForward`] is returned
+    /// if the remote rejects the push.
     pub fn push(&self) -> Result<(), git2::Error> {
-        self.repo
-            .find_remote("origin")?
-            .push(&["refs/heads/master:refs/heads/master"], None)?;
+        if self.try_push()? {
+            Ok(())
+        } else {
+            Err(git2::Error::new(
+                git2::ErrorCode::NotFastForward,
+                git2::ErrorClass::Reference,
+                "the remote rejected the push",
+            ))
+        }
+    }
+
+    /// Push all commits to the configured remotes, reporting whether the push
+    /// was accepted.
+    ///
+    /// Returns `false` if the remote rejected the push, usually because it has
+    /// commits which are not in the local branch. In that case, pull and try
+    /// again.
+    pub fn try_push(&self) -> Result<bool, git2::Error> {
+        let refspec = format!("refs/heads/{0}:refs/heads/{0}", self.branch);
+        self.push_refspecs(&[refspec])
+    }
 
+    /// Replace the history of the branch with a single root commit of the
+    /// current tree.
+    ///
+    /// The old history is kept on a new branch with the given name, in the
+    /// same way as the snapshots of the crates.io index. The tree and the
+    /// working directory are unchanged.
+    ///
+    /// An error with the code [`git2::ErrorCode::Exists`] is returned if the
+    /// snapshot branch already exists.
+    pub fn squash_history(&self, snapshot_branch_name: impl AsRef<str>) -> Result<(), git2::Error> {
+        let snapshot_branch_name = snapshot_branch_name.as_ref();
+        let mut head = self.repo.head()?;
+        let commit = head.peel_to_commit()?;
+
+        self.repo.branch(snapshot_branch_name, &commit, false)?;
+
+        let signature = self.repo.signature()?;
+        let message = format!(
+            "Squash history into one commit\n\nPrevious HEAD was {}, now on the `{}` branch",
+            commit.id(),
+            snapshot_branch_name
+        );
+        let oid = self
+            .repo
+            .commit(None, &signature, &signature, &message, &commit.tree()?, &[])?;
+
+        head.set_target(oid, &message)?;
         Ok(())
     }
+
+    /// Push a history squashed by [`Repository::squash_history`].
+    ///
+    /// The snapshot branch is pushed first, and the branch is only
+    /// force-pushed once the remote has accepted the snapshot, so the old
+    /// history is never lost. Before the force-push, the branch on the remote
+    /// is checked to still be at the commit which was squashed, so commits
+    /// pushed by others in the meantime are not overwritten (though a push
+    /// which lands between the check and the force-push can still be lost).
+    ///
+    /// An error with the code [`git2::ErrorCode::NotFastForward`] is returned
+    /// if the remote rejects the snapshot branch, or if the branch on the
+    /// remote has moved on. In that case the branch on the remote is
+    /// unchanged.
+    pub fn push_squashed(&self, snapshot_branch_name: impl AsRef<str>) -> Result<(), git2::Error> {
+        let snapshot_branch_name = snapshot_branch_name.as_ref();
+        let snapshot = self
+            .repo
+            .find_branch(snapshot_branch_name, git2::BranchType::Local)?
+            .get()
+            .peel_to_commit()?
+            .id();
+
+        let snapshot_refspec = format!("refs/heads/{0}:refs/heads/{0}", snapshot_branch_name);
+        if !self.push_refspecs(&[snapshot_refspec])? {
+            return Err(git2::Error::new(
+                git2::ErrorCode::NotFastForward,
+                git2::ErrorClass::Reference,
+                "the remote rejected the snapshot branch",
+            ));
+        }
+
+        if self.remote_head()? != Some(snapshot) {
+            return Err(git2::Error::new(
+                git2::ErrorCode::NotFastForward,
+                git2::ErrorClass::Reference,
+                "the remote branch has changed since the history was squashed",
+            ));
+        }
+
+        let refspec = format!("+refs/heads/{0}:refs/heads/{0}", self.branch);
+        if self.push_refspecs(&[refspec])? {
+            Ok(())
+        } else {
+            Err(git2::Error::new(
+                git2::ErrorCode::NotFastForward,
+                git2::ErrorClass::Reference,
+                "the remote rejected the squashed branch",
+            ))
+        }
+    }
+
+    /// The id of the commit at the head of the branch on the remote, if the
+    /// branch exists there
+    fn remote_head(&self) -> Result<Option<git2::Oid>, git2::Error> {
+        let mut remote = self.repo.find_remote(&self.remote)?;
+        remote.connect(git2::Direction::Fetch)?;
+
+        let refname = format!("refs/heads/{}", self.branch);
+        let head = remote
+            .list()?
+            .iter()
+            .find(|head| head.name() == refname)
+            .map(git2::RemoteHead::oid);
+
+        Ok(head)
+    }
+
+    fn push_refspecs(&self, refspecs: &[String]) -> Result<bool, git2::Error> {
+        let mut rejected = false;
+
+        let mut callbacks = git2::RemoteCallbacks::new();
+        callbacks.push_update_reference(|_, status| {
+            rejected |= status.is_some();
+            Ok(())
+        });
+
+        let mut push_options = git2::PushOptions::new();
+        push_options.remote_callbacks(callbacks);
+
+        match self
+            .repo
+            .find_remote(&self.remote)?
+            .push(refspecs, Some(&mut push_options))
+        {
+            Ok(()) => (),
+            Err(e) if e.code() == git2::ErrorCode::NotFastForward => return Ok(false),
+            Err(e) => return Err(e),
+        }
+
+        drop(push_options);
+        Ok(!rejected)
+    }
+
+    /// The id of the commit at the head of the current branch
+    pub(crate) fn head(&self) -> Result<git2::Oid, git2::Error> {
+        Ok(self.repo.head()?.peel_to_commit()?.id())
+    }
+
+    /// Discard every commit after the given one, along with any changes to
+    /// tracked files
+    pub(crate) fn reset(&self, commit: git2::Oid) -> Result<(), git2::Error> {
+        let commit = self
+            .repo
+            .find_object(commit, Some(git2::ObjectType::Commit))?;
+        self.repo.reset(&commit, git2::ResetType::Hard, None)
+    }
+}
+
+/// The name of the branch which `HEAD` points to, if any
+fn head_branch(repo: &git2::Repository) -> Option<String> {
+    repo.find_reference("HEAD")
+        .ok()?
+        .symbolic_target()?
+        .strip_prefix("refs/heads/")
+        .map(ToString::to_string)
 }
 
 fn fast_forward(
@@ -180,27 +466,308 @@ fn fast_forward(
         None => String::from_utf8_lossy(lb.name_bytes()).to_string(),
     };
     let msg = format!("Fast-Forward: Setting {} to id: {}", name, rc.id());
+    checkout(repo, rc.id())?;
     lb.set_target(rc.id(), &msg)?;
     repo.set_head(&name)?;
-    repo.checkout_head(Some(git2::build::CheckoutBuilder::default()
-            // For some reason the force is required to make the working directory actually get updated
-            // I suspect we should be adding some logic to handle dirty working directory states
-            // but this is just an example so maybe not.
-            .force()))?;
     Ok(())
 }
 
+/// Update the working directory and the git index to match a commit.
+///
+/// This is done before `HEAD` is moved to the commit. Files which have
+/// uncommitted changes are never overwritten. If the checkout would have to
+/// overwrite one, an error with the code [`git2::ErrorCode::Conflict`] is
+/// returned, and nothing is changed.
+fn checkout(repo: &git2::Repository, commit: git2::Oid) -> Result<(), git2::Error> {
+    let commit = repo.find_object(commit, Some(git2::ObjectType::Commit))?;
+    repo.checkout_tree(&commit, Some(git2::build::CheckoutBuilder::new().safe()))
+}
+
+/// The 'stage' bits of a git index entry's flags. These are non-zero for
+/// entries which represent one side of a conflict.
+const INDEX_ENTRY_STAGE_MASK: u16 = 0x3000;
+
 fn normal_merge(
-    _repo: &git2::Repository,
-    _local: &git2::AnnotatedCommit,
-    _remote: &git2::AnnotatedCommit,
-) -> Result<(), git2::Error> {
-    unimplemented!()
+    repo: &git2::Repository,
+    local: &git2::AnnotatedCommit,
+    remote: &git2::AnnotatedCommit,
+) -> WrappedResult<(), MergeError, git2::Error> {
+    let local_commit = repo.find_commit(local.id())?;
+    let remote_commit = repo.find_commit(remote.id())?;
+
+    let base = match repo.merge_base(local.id(), remote.id()) {
+        Ok(oid) => Some(oid),
+        Err(e) if e.code() == git2::ErrorCode::NotFound => None,
+        Err(e) => return Err(e),
+    };
+
+    // histories with no common ancestor are merged against an empty tree
+    let ancestor_tree = match base {
+        Some(oid) => repo.find_commit(oid)?.tree()?,
+        None => repo.find_tree(repo.treebuilder(None)?.write()?)?,
+    };
+
+    let mut index = repo.merge_trees(
+        &ancestor_tree,
+        &local_commit.tree()?,
+        &remote_commit.tree()?,
+        None,
+    )?;
+
+    let conflicts = index.conflicts()?.collect::<Result<Vec<_>, _>>()?;
+
+    for conflict in conflicts {
+        let (our, their) = match (conflict.our, conflict.their) {
+            (Some(our), Some(their)) => (our, their),
+            (our, their) => {
+                let entry = our.or(their).or(conflict.ancestor).unwrap();
+                return Ok(Err(MergeError::unmergeable(&entry.path)));
+            }
+        };
+
+        let path = String::from_utf8_lossy(&our.path).into_owned();
+
+        let blob_content = |entry: &git2::IndexEntry| -> Result<Vec<u8>, git2::Error> {
+            Ok(repo.find_blob(entry.id)?.content().to_vec())
+        };
+
+        let local_times = yank_times(repo, local.id(), base, &path)?;
+        let remote_times = yank_times(repo, remote.id(), base, &path)?;
+
+        let merged = match merge_index_file(
+            &path,
+            &blob_content(&our)?,
+            &blob_content(&their)?,
+            |version| remote_times.get(version) > local_times.get(version),
+        ) {
+            Ok(merged) => merged,
+            Err(e) => return Ok(Err(e)),
+        };
+
+        for stage in 1..=3 {
+            if index.get_path(Path::new(&path), stage).is_some() {
+                index.remove(Path::new(&path), stage)?;
+            }
+        }
+
+        let mut entry = our;
+        entry.id = repo.blob(merged.as_bytes())?;
+        #[allow(clippy::cast_possible_truncation)]
+        {
+            entry.file_size = merged.len() as u32;
+        }
+        entry.flags &= !INDEX_ENTRY_STAGE_MASK;
+        index.add(&entry)?;
+    }
+
+    let tree = repo.find_tree(index.write_tree_to(repo)?)?;
+    let signature = repo.signature()?;
+    let message = format!("Merge commit '{}'", remote.id());
+
+    let oid = repo.commit(
+        None,
+        &signature,
+        &signature,
+        &message,
+        &tree,
+        &[&local_commit, &remote_commit],
+    )?;
+
+    checkout(repo, oid)?;
+    repo.head()?.set_target(oid, &message)?;
+
+    Ok(Ok(()))
+}
+
+/// Whether a path in the repository is a crate index file
+fn is_index_file(path: &str) -> bool {
+    let file_name = path.rsplit('/').next().unwrap_or_default();
+
+    !(file_name.starts_with('.') || file_name == "config.json")
+}
+
+/// Merge two diverged versions of an index file.
+///
+/// The resulting file contains the union of the records on both sides, keyed
+/// by version. Where a version appears on both sides, the checksums must
+/// match. If the two sides disagree about the 'yanked' status of a record,
+/// the status from the side which changed it most recently wins, as decided
+/// by `remote_is_newer` (see [`yank_times`]).
+fn merge_index_file(
+    path: &str,
+    local: &[u8],
+    remote: &[u8],
+    remote_is_newer: impl Fn(&Version) -> bool,
+) -> Result<String, MergeError> {
+    if !is_index_file(path) {
+        return Err(MergeError::unmergeable(path));
+    }
+
+    let local = parse_records(path, local)?;
+    let mut merged = parse_records(path, remote)?;
+
+    for (version, local_record) in local {
+        match merged.entry(version) {
+            Entry::Vacant(entry) => {
+                entry.insert(local_record);
+            }
+            Entry::Occupied(mut entry) => {
+                let remote_is_newer = remote_is_newer(entry.key());
+                merge_record(&local_record, entry.get_mut(), remote_is_newer)?;
+            }
+        }
+    }
+
+    let lines: Vec<String> = merged.values().map(Record::to_string).collect();
+    Ok(lines.join("\n"))
+}
+
+/// Merge a local record into the remote record of the same version
+fn merge_record(
+    local: &Record,
+    remote: &mut Record,
+    remote_is_newer: bool,
+) -> Result<(), MergeError> {
+    if local.check_sum() != remote.check_sum() {
+        return Err(MergeError::Checksum {
+            crate_name: remote.name().clone(),
+            version: remote.version().clone(),
+            local: local.check_sum().clone(),
+            remote: remote.check_sum().clone(),
+        });
+    }
+
+    if local.yanked() != remote.yanked() && !remote_is_newer {
+        if local.yanked() {
+            remote.yank();
+        } else {
+            remote.unyank();
+        }
+    }
+
+    Ok(())
+}
+
+/// For each version in an index file, the time of the most recent commit on
+/// one side of a merge which changed its 'yanked' status (or added it).
+///
+/// Only the commits after the merge base are considered, so a version which
+/// is missing from the map hasn't been changed on this side.
+fn yank_times(
+    repo: &git2::Repository,
+    tip: git2::Oid,
+    base: Option<git2::Oid>,
+    path: &str,
+) -> Result<BTreeMap<Version, i64>, git2::Error> {
+    let mut revwalk = repo.revwalk()?;
+    revwalk.set_sorting(git2::Sort::TIME)?;
+    revwalk.push(tip)?;
+    if let Some(base) = base {
+        revwalk.hide(base)?;
+    }
+
+    let mut times = BTreeMap::new();
+    for oid in revwalk {
+        let commit = repo.find_commit(oid?)?;
+        let parents = commit
+            .parents()
+            .map(|parent| records_at(repo, &parent, path))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        for (version, record) in records_at(repo, &commit, path)? {
+            // a commit changed the record if it differs from every parent
+            let changed = parents.iter().all(|parent| {
+                parent
+                    .get(&version)
+                    .map_or(true, |previous| previous.yanked() != record.yanked())
+            });
+
+            // commits are visited newest first, so the first change is kept
+            if changed && !times.contains_key(&version) {
+                times.insert(version, commit.time().seconds());
+            }
+        }
+    }
+
+    Ok(times)
+}
+
+/// The records of an index file at a commit.
+///
+/// This is empty if the file doesn't exist at that commit. Files which can't
+/// be parsed are also treated as empty, since only the files at the tips of a
+/// merge need to be valid.
+fn records_at(
+    repo: &git2::Repository,
+    commit: &git2::Commit,
+    path: &str,
+) -> Result<BTreeMap<Version, Record>, git2::Error> {
+    match commit.tree()?.get_path(Path::new(path)) {
+        Ok(entry) => {
+            let blob = repo.find_blob(entry.id())?;
+            Ok(parse_records(path, blob.content()).unwrap_or_default())
+        }
+        Err(e) if e.code() == git2::ErrorCode::NotFound => Ok(BTreeMap::new()),
+        Err(e) => Err(e),
+    }
+}
+
+fn parse_records(path: &str, content: &[u8]) -> Result<BTreeMap<Version, Record>, MergeError> {
+    let content = String::from_utf8_lossy(content);
+    let mut records = BTreeMap::new();
+
+    for line in content.lines().filter(|line| !line.trim().is_empty()) {
+        let record: Record =
+            serde_json::from_str(line).map_err(|_| MergeError::unmergeable(path))?;
+        records.insert(record.version().clone(), record);
+    }
+
+    Ok(records)
+}
+
+/// The error returned when diverged local and remote changes to the index
+/// cannot be merged automatically
+#[derive(Debug, Clone, thiserror::Error)]
+pub enum MergeError {
+    /// The same version of a crate has been published with different
+    /// checksums on either side of the merge
+    #[error(
+        "conflicting checksums for {crate_name} - {version} (local: {local}, remote: {remote})"
+    )]
+    Checksum {
+        /// The name of the crate
+        crate_name: String,
+        /// The conflicting crate version
+        version: Version,
+        /// The local checksum
+        local: String,
+        /// The remote checksum
+        remote: String,
+    },
+
+    /// A conflicting file could not be merged, because it is not an index file
+    /// or because it is malformed
+    #[error("unable to merge conflicting changes to '{path}'")]
+    Unmergeable {
+        /// The path of the file, relative to the root of the repository
+        path: String,
+    },
+}
+
+impl MergeError {
+    fn unmergeable(path: impl AsRef<[u8]>) -> Self {
+        Self::Unmergeable {
+            path: String::from_utf8_lossy(path.as_ref()).into_owned(),
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::Repository;
+    use super::{MergeError, Repository};
+    use crate::Record;
+    use semver::Version;
+    use std::path::Path;
     use url::Url;
 
     fn create_bare_repo() -> (tempfile::TempDir, git2::Repository) {
@@ -232,6 +799,64 @@ mod tests {
         local_repo.push().expect("couldn't push to remote");
     }
 
+    #[test]
+    fn push_to_custom_branch_and_remote() {
+        let (remote_dir, remote) = create_bare_repo();
+
+        let (temp_dir, mut local_repo) = create_repository();
+        local_repo.set_initial_branch("main").unwrap();
+        local_repo.set_remote("upstream");
+        local_repo
+            .add_origin(&Url::from_file_path(remote_dir.path()).unwrap())
+            .unwrap();
+        local_repo.create_initial_commit().unwrap();
+        local_repo.push().unwrap();
+
+        assert!(local_repo.repo.find_remote("upstream").is_ok());
+        assert!(remote.find_reference("refs/heads/main").is_ok());
+        assert!(remote.find_reference("refs/heads/master").is_err());
+
+        // the branch is detected when the repository is opened
+        let repo = Repository::open(temp_dir.path()).unwrap();
+        assert_eq!(repo.branch(), "main");
+        assert_eq!(repo.remote(), "origin");
+    }
+
+    #[test]
+    fn push_rejected() {
+        let (_remote_dir, (first_dir, first_repo), (second_dir, second_repo)) =
+            create_clones(&[record("0.1.0", "a")]);
+
+        std::fs::write(first_dir.path().join("first-file"), "").unwrap();
+        first_repo.add_path("first-file").unwrap();
+        first_repo.commit("added first file").unwrap();
+        assert!(first_repo.try_push().unwrap());
+
+        // the remote has moved on, so the push is rejected
+        let head = second_repo.head().unwrap();
+        std::fs::write(second_dir.path().join("second-file"), "").unwrap();
+        second_repo.add_path("second-file").unwrap();
+        second_repo.commit("added second file").unwrap();
+        assert!(!second_repo.try_push().unwrap());
+        assert_eq!(
+            second_repo.push().unwrap_err().code(),
+            git2::ErrorCode::NotFastForward
+        );
+
+        // after discarding the local commit and pulling, the change can be
+        // made again
+        second_repo.reset(head).unwrap();
+        assert!(!second_dir.path().join("second-file").exists());
+        second_repo.pull().unwrap().unwrap();
+        std::fs::write(second_dir.path().join("second-file"), "").unwrap();
+        second_repo.add_path("second-file").unwrap();
+        second_repo.commit("added second file").unwrap();
+        assert!(second_repo.try_push().unwrap());
+
+        first_repo.pull().unwrap().unwrap();
+        assert!(first_dir.path().join("second-file").exists());
+    }
+
     #[test]
     fn pull_from_origin_add_all() {
         // create a 'remote' git repo
@@ -253,7 +878,7 @@ mod tests {
         let (local_dir, local_repo) = create_repository();
         local_repo.add_origin(&remote_path).unwrap();
         local_repo.create_initial_commit().unwrap();
-        local_repo.pull().unwrap();
+        local_repo.pull().unwrap().unwrap();
         assert!(local_dir.path().join("some-file").exists());
     }
 
@@ -278,7 +903,421 @@ mod tests {
         let (local_dir, local_repo) = create_repository();
         local_repo.add_origin(&remote_path).unwrap();
         local_repo.create_initial_commit().unwrap();
-        local_repo.pull().unwrap();
+        local_repo.pull().unwrap().unwrap();
         assert!(local_dir.path().join("some-file").exists());
     }
+
+    const INDEX_FILE: &str = "so/me/some-crate";
+
+    fn record(version: &str, check_sum: &str) -> Record {
+        Record::new("some-crate", Version::parse(version).unwrap(), check_sum)
+    }
+
+    fn yanked(version: &str, check_sum: &str) -> Record {
+        let mut record = record(version, check_sum);
+        record.yank();
+        record
+    }
+
+    fn write_records(root: &Path, records: &[Record]) {
+        let path = root.join(INDEX_FILE);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        let lines: Vec<String> = records.iter().map(Record::to_string).collect();
+        std::fs::write(path, lines.join("\n")).unwrap();
+    }
+
+    fn read_records(root: &Path) -> Vec<Record> {
+        std::fs::read_to_string(root.join(INDEX_FILE))
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    fn commit_records(root: &Path, repo: &Repository, records: &[Record]) {
+        write_records(root, records);
+        repo.add_all().unwrap();
+        repo.commit("update some-crate").unwrap();
+    }
+
+    /// Commit records with a given commit time, in seconds
+    fn commit_records_at(root: &Path, repo: &Repository, records: &[Record], time: i64) {
+        write_records(root, records);
+        repo.add_all().unwrap();
+
+        let mut index = repo.repo.index().unwrap();
+        index.write().unwrap();
+        let tree = repo.repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let time = git2::Time::new(time, 0);
+        let signature = git2::Signature::new("first last", "first.last@gmail.com", &time).unwrap();
+        let parent = repo.repo.head().unwrap().peel_to_commit().unwrap();
+        repo.repo
+            .commit(
+                Some("HEAD"),
+                &signature,
+                &signature,
+                "update some-crate",
+                &tree,
+                &[&parent],
+            )
+            .unwrap();
+    }
+
+    /// Create a bare 'remote' repository, and two local clones of it which
+    /// share an initial commit containing the given records.
+    fn create_clones(
+        records: &[Record],
+    ) -> (
+        tempfile::TempDir,
+        (tempfile::TempDir, Repository),
+        (tempfile::TempDir, Repository),
+    ) {
+        let (remote_dir, _) = create_bare_repo();
+        let remote_path = Url::from_file_path(remote_dir.path().canonicalize().unwrap()).unwrap();
+
+        let (first_dir, first_repo) = create_repository();
+        first_repo.add_origin(&remote_path).unwrap();
+        first_repo.create_initial_commit().unwrap();
+        commit_records(first_dir.path(), &first_repo, records);
+        first_repo.push().unwrap();
+
+        let (second_dir, second_repo) = create_repository();
+        second_repo.add_origin(&remote_path).unwrap();
+        second_repo.pull().unwrap().unwrap();
+
+        (
+            remote_dir,
+            (first_dir, first_repo),
+            (second_dir, second_repo),
+        )
+    }
+
+    #[test]
+    fn pull_diverged_different_files() {
+        let (_remote_dir, (first_dir, first_repo), (second_dir, second_repo)) =
+            create_clones(&[record("0.1.0", "a")]);
+
+        std::fs::write(first_dir.path().join("first-file"), "").unwrap();
+        first_repo.add_all().unwrap();
+        first_repo.commit("added first file").unwrap();
+        first_repo.push().unwrap();
+
+        std::fs::write(second_dir.path().join("second-file"), "").unwrap();
+        second_repo.add_all().unwrap();
+        second_repo.commit("added second file").unwrap();
+
+        second_repo.pull().unwrap().unwrap();
+        assert!(second_dir.path().join("first-file").exists());
+        assert!(second_dir.path().join("second-file").exists());
+
+        // the merged history can be pushed back to the remote
+        second_repo.push().unwrap();
+        first_repo.pull().unwrap().unwrap();
+        assert!(first_dir.path().join("second-file").exists());
+    }
+
+    #[test]
+    fn pull_diverged_new_versions() {
+        let (_remote_dir, (first_dir, first_repo), (second_dir, second_repo)) =
+            create_clones(&[record("0.1.0", "a")]);
+
+        commit_records(
+            first_dir.path(),
+            &first_repo,
+            &[record("0.1.0", "a"), record("0.2.0", "b")],
+        );
+        first_repo.push().unwrap();
+
+        commit_records(
+            second_dir.path(),
+            &second_repo,
+            &[record("0.1.0", "a"), record("1.0.0", "c")],
+        );
+
+        second_repo.pull().unwrap().unwrap();
+
+        let expected = vec![
+            record("0.1.0", "a"),
+            record("0.2.0", "b"),
+            record("1.0.0", "c"),
+        ];
+        assert_eq!(read_records(second_dir.path()), expected);
+    }
+
+    #[test]
+    fn pull_diverged_yank_and_new_version() {
+        let (_remote_dir, (first_dir, first_repo), (second_dir, second_repo)) =
+            create_clones(&[record("0.1.0", "a"), record("0.2.0", "b")]);
+
+        commit_records(
+            first_dir.path(),
+            &first_repo,
+            &[yanked("0.1.0", "a"), record("0.2.0", "b")],
+        );
+        first_repo.push().unwrap();
+
+        commit_records(
+            second_dir.path(),
+            &second_repo,
+            &[
+                record("0.1.0", "a"),
+                yanked("0.2.0", "b"),
+                record("0.3.0", "c"),
+            ],
+        );
+
+        second_repo.pull().unwrap().unwrap();
+
+        let expected = vec![
+            yanked("0.1.0", "a"),
+            yanked("0.2.0", "b"),
+            record("0.3.0", "c"),
+        ];
+        assert_eq!(read_records(second_dir.path()), expected);
+    }
+
+    #[test]
+    fn pull_diverged_yank_from_the_commit_which_changed_it() {
+        let (_remote_dir, (first_dir, first_repo), (second_dir, second_repo)) =
+            create_clones(&[record("0.1.0", "a")]);
+        let time = second_repo
+            .repo
+            .head()
+            .unwrap()
+            .peel_to_commit()
+            .unwrap()
+            .time();
+        let time = time.seconds();
+
+        // the local side yanks 0.1.0 and publishes 0.2.0 first
+        commit_records_at(
+            second_dir.path(),
+            &second_repo,
+            &[yanked("0.1.0", "a"), record("0.2.0", "b")],
+            time + 10,
+        );
+
+        // then the remote side yanks both
+        commit_records_at(
+            first_dir.path(),
+            &first_repo,
+            &[yanked("0.1.0", "a"), yanked("0.2.0", "b")],
+            time + 20,
+        );
+        first_repo.push().unwrap();
+
+        // and finally the local side unyanks 0.1.0, without touching 0.2.0
+        commit_records_at(
+            second_dir.path(),
+            &second_repo,
+            &[
+                record("0.1.0", "a"),
+                record("0.2.0", "b"),
+                record("0.3.0", "c"),
+            ],
+            time + 30,
+        );
+
+        second_repo.pull().unwrap().unwrap();
+
+        let expected = vec![
+            record("0.1.0", "a"),
+            yanked("0.2.0", "b"),
+            record("0.3.0", "c"),
+        ];
+        assert_eq!(read_records(second_dir.path()), expected);
+    }
+
+    #[test]
+    fn pull_keeps_uncommitted_changes() {
+        let (_remote_dir, (first_dir, first_repo), (second_dir, second_repo)) =
+            create_clones(&[record("0.1.0", "a")]);
+
+        commit_records(
+            first_dir.path(),
+            &first_repo,
+            &[record("0.1.0", "a"), record("0.2.0", "b")],
+        );
+        first_repo.push().unwrap();
+
+        std::fs::write(second_dir.path().join("second-file"), "").unwrap();
+        second_repo.add_all().unwrap();
+        second_repo.commit("added second file").unwrap();
+        let head = second_repo.head().unwrap();
+
+        // an edit which hasn't been committed, to a file the merge changes
+        write_records(second_dir.path(), &[yanked("0.1.0", "a")]);
+
+        assert_eq!(
+            second_repo.pull().unwrap_err().code(),
+            git2::ErrorCode::Conflict
+        );
+        assert_eq!(second_repo.head().unwrap(), head);
+        assert_eq!(read_records(second_dir.path()), vec![yanked("0.1.0", "a")]);
+    }
+
+    #[test]
+    fn pull_diverged_conflicting_checksums() {
+        let (_remote_dir, (first_dir, first_repo), (second_dir, second_repo)) =
+            create_clones(&[record("0.1.0", "a")]);
+
+        commit_records(
+            first_dir.path(),
+            &first_repo,
+            &[record("0.1.0", "a"), record("0.2.0", "b")],
+        );
+        first_repo.push().unwrap();
+
+        let local_records = [record("0.1.0", "a"), record("0.2.0", "c")];
+        commit_records(second_dir.path(), &second_repo, &local_records);
+
+        match second_repo.pull().unwrap() {
+            Err(MergeError::Checksum {
+                version,
+                local,
+                remote,
+                ..
+            }) => {
+                assert_eq!(version, Version::new(0, 2, 0));
+                assert_eq!(local, "c");
+                assert_eq!(remote, "b");
+            }
+            other => panic!("expected a checksum conflict, got {:?}", other),
+        }
+
+        // the local repository is untouched
+        assert_eq!(read_records(second_dir.path()), local_records.to_vec());
+    }
+
+    /// The number of commits in the history of a commit
+    fn history_len(repo: &git2::Repository, commit: git2::Oid) -> usize {
+        let mut revwalk = repo.revwalk().unwrap();
+        revwalk.push(commit).unwrap();
+        revwalk.count()
+    }
+
+    #[test]
+    fn squash_history() {
+        let (temp_dir, repo) = create_repository();
+        repo.create_initial_commit().unwrap();
+        commit_records(temp_dir.path(), &repo, &[record("0.1.0", "a")]);
+        commit_records(
+            temp_dir.path(),
+            &repo,
+            &[record("0.1.0", "a"), record("0.2.0", "b")],
+        );
+        let old_head = repo.repo.head().unwrap().peel_to_commit().unwrap();
+
+        repo.squash_history("snapshot-2020-01-01").unwrap();
+
+        // the branch is a single root commit of the same tree
+        let new_head = repo.repo.head().unwrap().peel_to_commit().unwrap();
+        assert_eq!(repo.repo.head().unwrap().shorthand(), Some(repo.branch()));
+        assert_eq!(new_head.parent_count(), 0);
+        assert_eq!(new_head.tree_id(), old_head.tree_id());
+        assert_eq!(history_len(&repo.repo, new_head.id()), 1);
+
+        // the old history is intact on the snapshot branch
+        let snapshot = repo
+            .repo
+            .find_branch("snapshot-2020-01-01", git2::BranchType::Local)
+            .unwrap()
+            .get()
+            .peel_to_commit()
+            .unwrap();
+        assert_eq!(snapshot.id(), old_head.id());
+        assert_eq!(history_len(&repo.repo, snapshot.id()), 3);
+
+        // the working directory and the git index are unchanged
+        assert!(repo.repo.statuses(None).unwrap().is_empty());
+        assert_eq!(
+            read_records(temp_dir.path()),
+            vec![record("0.1.0", "a"), record("0.2.0", "b")]
+        );
+
+        // the snapshot branch can't be overwritten
+        assert_eq!(
+            repo.squash_history("snapshot-2020-01-01")
+                .unwrap_err()
+                .code(),
+            git2::ErrorCode::Exists
+        );
+    }
+
+    #[test]
+    fn push_squashed() {
+        let (remote_dir, (first_dir, first_repo), (_second_dir, second_repo)) =
+            create_clones(&[record("0.1.0", "a")]);
+        commit_records(
+            first_dir.path(),
+            &first_repo,
+            &[record("0.1.0", "a"), record("0.2.0", "b")],
+        );
+        first_repo.push().unwrap();
+        let old_head = first_repo.head().unwrap();
+
+        first_repo.squash_history("snapshot").unwrap();
+
+        // a normal push is rejected, since the histories have diverged
+        assert!(!first_repo.try_push().unwrap());
+        first_repo.push_squashed("snapshot").unwrap();
+
+        let remote = git2::Repository::open_bare(remote_dir.path()).unwrap();
+        let branch = format!("refs/heads/{}", first_repo.branch());
+        let head = remote.refname_to_id(&branch).unwrap();
+        assert_eq!(head, first_repo.head().unwrap());
+        assert_eq!(history_len(&remote, head), 1);
+        assert_eq!(
+            remote.refname_to_id("refs/heads/snapshot").unwrap(),
+            old_head
+        );
+
+        // a new clone gets the squashed history
+        let (third_dir, third_repo) = create_repository();
+        third_repo
+            .add_origin(&Url::from_file_path(remote_dir.path()).unwrap())
+            .unwrap();
+        third_repo.pull().unwrap().unwrap();
+        assert_eq!(third_repo.head().unwrap(), head);
+        assert_eq!(
+            read_records(third_dir.path()),
+            vec![record("0.1.0", "a"), record("0.2.0", "b")]
+        );
+
+        // the snapshot branch can't be pushed over an existing one, and then
+        // the branch isn't pushed either
+        second_repo.squash_history("snapshot").unwrap();
+        assert_eq!(
+            second_repo.push_squashed("snapshot").unwrap_err().code(),
+            git2::ErrorCode::NotFastForward
+        );
+        assert_eq!(remote.refname_to_id(&branch).unwrap(), head);
+    }
+
+    #[test]
+    fn push_squashed_checks_the_remote_branch() {
+        let (remote_dir, (_first_dir, first_repo), (second_dir, second_repo)) =
+            create_clones(&[record("0.1.0", "a")]);
+        first_repo.squash_history("snapshot").unwrap();
+
+        // the remote moves on after the history was squashed
+        commit_records(
+            second_dir.path(),
+            &second_repo,
+            &[record("0.1.0", "a"), record("0.2.0", "b")],
+        );
+        second_repo.push().unwrap();
+
+        assert_eq!(
+            first_repo.push_squashed("snapshot").unwrap_err().code(),
+            git2::ErrorCode::NotFastForward
+        );
+
+        let remote = git2::Repository::open_bare(remote_dir.path()).unwrap();
+        let branch = format!("refs/heads/{}", first_repo.branch());
+        assert_eq!(
+            remote.refname_to_id(&branch).unwrap(),
+            second_repo.head().unwrap()
+        );
+    }
 }
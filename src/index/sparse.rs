@@ -0,0 +1,335 @@
+//! Export an index [`Tree`] as a static 'sparse' index.
+//!
+//! Cargo's sparse protocol (`sparse+https://`) fetches the `config.json` and
+//! each crate's index file individually over plain HTTP, so an index exported
+//! this way can be served by any static file server or CDN, without git.
+//!
+//! *see the [cargo docs](https://doc.rust-lang.org/cargo/reference/registry-index.html#sparse-protocol) for details*
+//!
+//! # Example
+//!
+//! ```no_run
+//! use crate_index::{sparse::SparseIndex, tree::Tree};
+//! # use crate_index::Error;
+//! # async {
+//! let tree = Tree::open("/index").await?;
+//!
+//! let mut sparse_index = SparseIndex::open("/var/www/index").await?;
+//!
+//! for path in sparse_index.update(&tree).await? {
+//!     println!("updated {} (etag: {})", path, sparse_index.etag(&path).unwrap());
+//! }
+//! # Ok::<(), Error>(())
+//! # };
+//! ```
+
+use crate::{
+    download::prefix,
+    tree::{CorruptionError, Error, Tree},
+    utils,
+};
+use async_std::{
+    fs,
+    path::{Path, PathBuf},
+};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    io::{Error as IoError, ErrorKind},
+};
+
+/// The name of the file in which the content hashes of the exported files are
+/// stored.
+const MANIFEST: &str = ".etags.json";
+
+/// A directory on the filesystem containing a static sparse index
+#[derive(Debug)]
+pub struct SparseIndex {
+    root: PathBuf,
+    hashes: BTreeMap<String, String>,
+}
+
+impl SparseIndex {
+    /// Open a sparse index directory, creating it if it doesn't exist.
+    ///
+    /// The content hashes of previously exported files are loaded from the
+    /// directory, so that subsequent updates only rewrite files which have
+    /// changed.
+    ///
+    /// # Errors
+    ///
+    /// This method can fail if the directory cannot be created, or the existing
+    /// manifest cannot be read. If the manifest cannot be parsed, the error is
+    /// [`Error::Corruption`]. In that case, delete the manifest, and the next
+    /// update rewrites every file.
+    pub async fn open(root: impl Into<PathBuf>) -> Result<Self, Error> {
+        let root = root.into();
+        fs::create_dir_all(&root).await?;
+
+        let manifest = root.join(MANIFEST);
+        let hashes = if manifest.exists().await {
+            let bytes = fs::read(&manifest).await?;
+            serde_json::from_slice(&bytes)
+                .map_err(|e| CorruptionError::new(&manifest, e.line(), e))?
+        } else {
+            BTreeMap::default()
+        };
+
+        Ok(Self { root, hashes })
+    }
+
+    /// Bring the sparse index up to date with the given index [`Tree`].
+    ///
+    /// Only files whose contents have changed since the last update are
+    /// written, and files for crates which are no longer in the tree are
+    /// deleted. The paths of those files, relative to the root of the sparse
+    /// index, are returned (this is useful for purging a CDN cache).
+    ///
+    /// # Errors
+    ///
+    /// This method can fail if the index tree cannot be read, or the sparse
+    /// index cannot be written to.
+    pub async fn update(&mut self, tree: &Tree) -> Result<Vec<String>, Error> {
+        let mut updated = Vec::new();
+
+        if self.write("config.json", tree.config().to_string()).await? {
+            updated.push("config.json".to_string());
+        }
+
+        let mut paths = BTreeSet::new();
+        paths.insert("config.json".to_string());
+
+        for crate_name in tree.crates() {
+            let path = file_path(crate_name);
+            let contents = tree.file(crate_name).await?.to_string();
+
+            if self.write(&path, contents).await? {
+                updated.push(path.clone());
+            }
+            paths.insert(path);
+        }
+
+        let removed: Vec<String> = self
+            .hashes
+            .keys()
+            .filter(|path| !paths.contains(*path))
+            .cloned()
+            .collect();
+
+        for path in removed {
+            self.remove(&path).await?;
+            updated.push(path);
+        }
+
+        if !updated.is_empty() {
+            let manifest = serde_json::to_string_pretty(&self.hashes).map_err(IoError::from)?;
+            utils::write_atomic(self.root.join(MANIFEST), manifest).await?;
+        }
+
+        Ok(updated)
+    }
+
+    /// Write a file to the sparse index if its contents have changed.
+    ///
+    /// Returns `true` if the file was written.
+    async fn write(&mut self, path: &str, contents: String) -> Result<bool, IoError> {
+        let hash = content_hash(&contents);
+        let full_path = self.root.join(path);
+
+        if self.hashes.get(path) == Some(&hash) && full_path.exists().await {
+            return Ok(false);
+        }
+
+        create_parents(&full_path).await?;
+        utils::write_atomic(full_path, contents).await?;
+        self.hashes.insert(path.to_string(), hash);
+
+        Ok(true)
+    }
+
+    /// Delete a file which is no longer in the index from the sparse index.
+    async fn remove(&mut self, path: &str) -> Result<(), IoError> {
+        match fs::remove_file(self.root.join(path)).await {
+            Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+            _ => (),
+        }
+        self.hashes.remove(path);
+
+        Ok(())
+    }
+
+    /// The location on the filesystem of the root of the sparse index
+    #[must_use]
+    pub fn root(&self) -> &PathBuf {
+        &self.root
+    }
+
+    /// The `ETag` for a file in the sparse index, given its path relative to the
+    /// root of the sparse index.
+    ///
+    /// The tag is a quoted SHA256 hash of the file contents, suitable for use
+    /// directly as the value of an HTTP `ETag` header. Returns `None` if the
+    /// file has not been exported.
+    #[must_use]
+    pub fn etag(&self, path: impl AsRef<str>) -> Option<String> {
+        self.hashes
+            .get(path.as_ref())
+            .map(|hash| format!("\"{}\"", hash))
+    }
+}
+
+/// Export an index [`Tree`] to a static sparse index directory.
+///
+/// This is a shortcut for [`SparseIndex::open`] followed by
+/// [`SparseIndex::update`].
+///
+/// # Errors
+///
+/// This method can fail if the index tree cannot be read, or the sparse index
+/// cannot be written to.
+pub async fn export(tree: &Tree, target: impl Into<PathBuf>) -> Result<SparseIndex, Error> {
+    let mut sparse_index = SparseIndex::open(target).await?;
+    sparse_index.update(tree).await?;
+    Ok(sparse_index)
+}
+
+/// The path of a crate's index file in a sparse index, relative to the root.
+///
+/// Cargo requests sparse index files using the lowercased crate name, with
+/// `_` and `-` kept as they are.
+///
+/// # Example
+///
+/// ```
+/// use crate_index::sparse::file_path;
+///
+/// assert_eq!(file_path("Serde"), "se/rd/serde");
+/// assert_eq!(file_path("syn"), "3/s/syn");
+/// assert_eq!(file_path("foo_bar"), "fo/o_/foo_bar");
+/// ```
+#[must_use]
+pub fn file_path(crate_name: impl AsRef<str>) -> String {
+    let crate_name = crate_name.as_ref().to_ascii_lowercase();
+    format!("{}/{}", prefix(&crate_name), crate_name)
+}
+
+pub(crate) fn content_hash(contents: &str) -> String {
+    hex::encode(Sha256::digest(contents.as_bytes()))
+}
+
+async fn create_parents(path: &Path) -> Result<(), IoError> {
+    fs::create_dir_all(path.parent().unwrap()).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{content_hash, export, file_path, SparseIndex, MANIFEST};
+    use crate::{
+        tree::{Error, Tree},
+        Record,
+    };
+    use semver::Version;
+
+    fn metadata(name: &str, version: &str) -> Record {
+        Record::new(name, Version::parse(version).unwrap(), "checksum")
+    }
+
+    #[async_std::test]
+    async fn export_and_update() {
+        let index_dir = tempfile::tempdir().unwrap();
+        let sparse_dir = tempfile::tempdir().unwrap();
+        let download = "https://my-crates-server.com/api/v1/crates/{crate}/{version}/download";
+
+        let mut tree = Tree::initialise(index_dir.path(), download)
+            .build()
+            .await
+            .unwrap();
+
+        for (name, version) in &[
+            ("Some-Name", "0.1.0"),
+            ("Some-Name", "0.2.0"),
+            ("abc", "1.0.0"),
+        ] {
+            tree.insert(metadata(name, version)).await.unwrap().unwrap();
+        }
+
+        let sparse_index = export(&tree, sparse_dir.path()).await.unwrap();
+
+        let contents = std::fs::read_to_string(sparse_dir.path().join("so/me/some-name")).unwrap();
+        let expected = format!(
+            "{}\n{}",
+            metadata("Some-Name", "0.1.0"),
+            metadata("Some-Name", "0.2.0")
+        );
+        assert_eq!(contents, expected);
+        assert_eq!(
+            sparse_index.etag("so/me/some-name"),
+            Some(format!("\"{}\"", content_hash(&expected)))
+        );
+
+        assert!(sparse_dir.path().join("3/a/abc").exists());
+        assert!(sparse_dir.path().join("config.json").exists());
+
+        // reopening the sparse index, only changed files are rewritten
+        let mut sparse_index = SparseIndex::open(sparse_dir.path()).await.unwrap();
+        assert!(sparse_index.update(&tree).await.unwrap().is_empty());
+
+        tree.insert(metadata("abc", "1.1.0"))
+            .await
+            .unwrap()
+            .unwrap();
+        let updated = sparse_index.update(&tree).await.unwrap();
+        assert_eq!(updated, vec!["3/a/abc".to_string()]);
+    }
+
+    #[test]
+    fn underscores_are_kept_in_the_prefix() {
+        assert_eq!(file_path("foo_bar"), "fo/o_/foo_bar");
+        assert_eq!(file_path("A_b"), "3/a/a_b");
+        assert_eq!(file_path("x-y_z"), "x-/y_/x-y_z");
+    }
+
+    #[async_std::test]
+    async fn removed_files_are_deleted() {
+        let index_dir = tempfile::tempdir().unwrap();
+        let sparse_dir = tempfile::tempdir().unwrap();
+        let download = "https://my-crates-server.com/api/v1/crates/{crate}/{version}/download";
+
+        let mut tree = Tree::initialise(index_dir.path(), download)
+            .build()
+            .await
+            .unwrap();
+        for name in &["foo_bar", "abc"] {
+            tree.insert(metadata(name, "0.1.0")).await.unwrap().unwrap();
+        }
+
+        let mut sparse_index = export(&tree, sparse_dir.path()).await.unwrap();
+        assert!(sparse_dir.path().join("fo/o_/foo_bar").exists());
+
+        drop(tree);
+        std::fs::remove_file(index_dir.path().join("fo/o-/foo_bar")).unwrap();
+        let tree = Tree::open(index_dir.path()).await.unwrap();
+
+        let updated = sparse_index.update(&tree).await.unwrap();
+        assert_eq!(updated, vec!["fo/o_/foo_bar".to_string()]);
+        assert!(!sparse_dir.path().join("fo/o_/foo_bar").exists());
+        assert!(sparse_index.etag("fo/o_/foo_bar").is_none());
+        assert!(sparse_dir.path().join("3/a/abc").exists());
+
+        // the removal is recorded in the manifest
+        let mut sparse_index = SparseIndex::open(sparse_dir.path()).await.unwrap();
+        assert!(sparse_index.update(&tree).await.unwrap().is_empty());
+    }
+
+    #[async_std::test]
+    async fn corrupt_manifest() {
+        let sparse_dir = tempfile::tempdir().unwrap();
+        let manifest = sparse_dir.path().join(MANIFEST);
+        std::fs::write(&manifest, "{").unwrap();
+
+        match SparseIndex::open(sparse_dir.path()).await {
+            Err(Error::Corruption(e)) => assert_eq!(e.path().as_os_str(), manifest.as_os_str()),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+}
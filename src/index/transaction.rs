@@ -0,0 +1,131 @@
+use super::{
+    tree::{self, Operation, TransactionError},
+    Error, Index,
+};
+use crate::{Record, WrappedResult};
+use semver::Version;
+
+/// A batch of changes to an [`Index`], committed together.
+///
+/// Create a `Transaction` with [`Index::transaction`].
+///
+/// # Example
+///
+/// ```no_run
+/// # use crate_index::{Index, Error, Record};
+/// #
+/// # #[async_std::main]
+/// # async fn main() -> Result<(), Error> {
+/// #    let mut index = Index::open("root").await?;
+/// #
+/// let check_sum = "d867001db0e2b6e0496f9fac96930e2d42233ecd3ca0413e0753d4c7695d289c";
+///
+/// let result = index
+///     .transaction()
+///     .insert(Record::new("some-crate", "0.1.0".parse().unwrap(), check_sum))
+///     .insert(Record::new("other-crate", "0.1.0".parse().unwrap(), check_sum))
+///     .yank("old-crate", "1.0.0".parse().unwrap())
+///     .commit()
+///     .await?;
+///
+/// match result {
+///     Ok(changed) => println!("updated {} crates", changed.len()),
+///     Err(e) => println!("nothing changed: {}", e),
+/// }
+/// #
+/// #     Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+#[must_use]
+pub struct Transaction<'a> {
+    index: &'a mut Index,
+    operations: Vec<Operation>,
+}
+
+impl<'a> Transaction<'a> {
+    pub(crate) fn new(index: &'a mut Index) -> Self {
+        Self {
+            index,
+            operations: Vec::new(),
+        }
+    }
+
+    /// Insert a crate [`Record`] into the index.
+    pub fn insert(mut self, record: Record) -> Self {
+        self.operations.push(Operation::Insert(record));
+        self
+    }
+
+    /// 'Yank' a version of a crate in the index.
+    pub fn yank(mut self, crate_name: impl Into<String>, version: Version) -> Self {
+        self.operations.push(Operation::Yank {
+            crate_name: crate_name.into(),
+            version,
+        });
+        self
+    }
+
+    /// 'Unyank' a version of a crate in the index.
+    pub fn unyank(mut self, crate_name: impl Into<String>, version: Version) -> Self {
+        self.operations.push(Operation::Unyank {
+            crate_name: crate_name.into(),
+            version,
+        });
+        self
+    }
+
+    /// Add an [`Operation`] to the transaction.
+    pub fn operation(mut self, operation: Operation) -> Self {
+        self.operations.push(operation);
+        self
+    }
+
+    /// Apply every change in the transaction, and commit them to the git
+    /// repository in a single commit.
+    ///
+    /// See [`Tree::apply`](crate::tree::Tree::apply) for details. No commit is
+    /// made if the transaction is empty, or any change is rejected. In sync
+    /// mode (see [`Builder::sync`](crate::Builder::sync)), the whole
+    /// transaction is retried if the push is rejected.
+    ///
+    /// Returns the names of the crates whose files changed, in alphabetical
+    /// order.
+    ///
+    /// # Errors
+    ///
+    /// ## Outer Error
+    ///
+    /// A critical error is returned if the filesystem cannot be read or
+    /// written to, or a git error occurs
+    ///
+    /// ## Inner Error
+    ///
+    /// A [`TransactionError`] is returned for the first change which is
+    /// rejected. In this case, the index is left untouched.
+    pub async fn commit(self) -> WrappedResult<Vec<String>, TransactionError, Error> {
+        let count = self.operations.len();
+
+        let mut attempt = 0;
+        loop {
+            self.index.pull().await?;
+
+            let changed = match self.index.tree.apply(self.operations.clone()).await? {
+                Ok(changed) => changed,
+                Err(e) => return Ok(Err(e)),
+            };
+            if changed.is_empty() {
+                return Ok(Ok(changed));
+            }
+
+            self.index
+                .repo
+                .add_paths(changed.iter().map(tree::get_path))?;
+            let commit_message = format!("applying {} changes to {} crates", count, changed.len());
+            if self.index.publish(commit_message, attempt).await? {
+                return Ok(Ok(changed));
+            }
+            attempt += 1;
+        }
+    }
+}
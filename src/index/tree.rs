@@ -1,24 +1,49 @@
 //! Abstractions over a filesystem directory containing an index.
 
-use crate::{index::Record, utils, validate::Error as ValidationError, WrappedResult};
-use async_std::path::PathBuf;
-use semver::Version;
+use crate::{
+    download::{DownloadTemplate, TemplateError},
+    index::Record,
+    utils,
+    validate::Error as ValidationError,
+    WrappedResult,
+};
+use async_std::path::{Path, PathBuf};
+use semver::{Version, VersionReq};
 use std::{collections::HashSet, io::Error as IoError};
 use url::Url;
 
 mod file;
-use file::IndexFile;
 pub use file::VersionNotFoundError;
+pub(crate) use file::{get_path, join_lines, IndexFile};
 
 mod config;
 use config::Config;
 
+mod check;
+pub use check::{Problem, Repair, Report};
+
+mod lock;
+use lock::Lock;
+
+mod transaction;
+pub(crate) use lock::LOCK_FILE;
+pub use lock::{LockOptions, LockedError};
+use transaction::Batch;
+pub use transaction::{Operation, TransactionError};
+
 /// An interface to a crate index directory on the filesystem
 #[derive(Debug)]
 pub struct Tree {
     root: PathBuf,
     config: Config,
     crates: HashSet<String>,
+    validate_dependencies: bool,
+
+    /// Whether unparseable lines in index files are skipped
+    lenient: bool,
+
+    /// The advisory lock on the root, held for the lifetime of the tree
+    lock: Lock,
 }
 
 /// Builder for creating a new [`Tree`]
@@ -26,7 +51,8 @@ pub struct Tree {
 #[must_use]
 pub struct Builder {
     root: PathBuf,
-    config: Config,
+    config: Result<Config, TemplateError>,
+    validate_dependencies: bool,
 }
 
 impl Builder {
@@ -35,7 +61,7 @@ impl Builder {
     /// The API should implement the REST interface as defined in
     /// [the Cargo book](https://doc.rust-lang.org/cargo/reference/registries.html)
     pub fn api(mut self, api: Url) -> Self {
-        self.config = self.config.with_api(api);
+        self.config = self.config.map(|config| config.with_api(api));
         self
     }
 
@@ -46,7 +72,9 @@ impl Builder {
     ///
     /// Add multiple registries my calling this method multiple times.
     pub fn allowed_registry(mut self, registry: Url) -> Self {
-        self.config = self.config.with_allowed_registry(registry);
+        self.config = self
+            .config
+            .map(|config| config.with_allowed_registry(registry));
         self
     }
 
@@ -54,7 +82,20 @@ impl Builder {
     ///
     /// You will almost always want this, so this exists as a handy shortcut.
     pub fn allow_crates_io(mut self) -> Self {
-        self.config = self.config.with_crates_io_registry();
+        self.config = self.config.map(Config::with_crates_io_registry);
+        self
+    }
+
+    /// Check the dependencies of inserted crates.
+    ///
+    /// When enabled, a [`Record`] is rejected if any of its dependencies from
+    /// this registry cannot be satisfied by a non-yanked version in the tree,
+    /// or if any of its dependencies are from a registry which is not allowed.
+    ///
+    /// This setting is not stored in the index. When the tree is reopened, it
+    /// can be enabled again with [`Tree::set_validate_dependencies`].
+    pub fn validate_dependencies(mut self) -> Self {
+        self.validate_dependencies = true;
         self
     }
 
@@ -63,11 +104,12 @@ impl Builder {
     /// # Errors
     ///
     /// This method can fail if the root path doesn't exist, or the filesystem
-    /// cannot be written to.
-    pub async fn build(self) -> Result<Tree, IoError> {
+    /// cannot be written to. An invalid download template is returned as
+    /// [`Error::Template`].
+    pub async fn build(self) -> Result<Tree, Error> {
         // once 'IntoFuture' is stabilised, this 'build' method should be replaced with
         // an 'IntoFuture' implementation so that the builder can be awaited directly
-        Tree::new(self.root, self.config).await
+        Tree::new(self.root, self.config?, self.validate_dependencies).await
     }
 }
 
@@ -78,6 +120,9 @@ impl Tree {
     /// Additional options can be set using the builder API (see
     /// [`Builder`] for options).
     ///
+    /// The download URL is a [`DownloadTemplate`], which is validated when the
+    /// tree is built.
+    ///
     /// # Example
     ///
     /// ## Basic Config
@@ -114,19 +159,34 @@ impl Tree {
     /// ```
     pub fn initialise(root: impl Into<PathBuf>, download: impl Into<String>) -> Builder {
         let root = root.into();
-        let config = Config::new(download);
-        Builder { root, config }
+        let config = DownloadTemplate::parse(download).map(Config::new);
+        let validate_dependencies = false;
+
+        Builder {
+            root,
+            config,
+            validate_dependencies,
+        }
     }
 
-    pub(crate) async fn new(root: PathBuf, config: Config) -> Result<Self, IoError> {
+    pub(crate) async fn new(
+        root: PathBuf,
+        config: Config,
+        validate_dependencies: bool,
+    ) -> Result<Self, Error> {
+        let lock = Lock::acquire(&root, LockOptions::exclusive()).await?;
         config.to_file(root.join("config.json")).await?;
 
         let crates = HashSet::default();
+        let lenient = false;
 
         let tree = Self {
             root,
             config,
             crates,
+            validate_dependencies,
+            lenient,
+            lock,
         };
 
         Ok(tree)
@@ -134,68 +194,152 @@ impl Tree {
 
     /// Open an existing index tree at the given root path.
     ///
+    /// Index files are read when they are used, so a corrupt file is only
+    /// detected when an operation touches it. To check every file up front,
+    /// use [`Tree::open_lenient`].
+    ///
+    /// An exclusive lock is taken on the root, so that no other process can
+    /// open the tree at the same time. See [`Tree::open_with`] to configure
+    /// the lock.
+    ///
+    /// # Errors
+    ///
+    /// This method can fail if the given path does not exist, or the config
+    /// file cannot be read. If the config file cannot be parsed, the error is
+    /// [`Error::Corruption`]. If another process holds a lock on the root, the
+    /// error is [`Error::Locked`].
+    pub async fn open(root: impl Into<PathBuf>) -> Result<Self, Error> {
+        Self::open_with(root, LockOptions::default()).await
+    }
+
+    /// Open an existing index tree at the given root path, locking the root
+    /// with the given options.
+    ///
+    /// A tree opened with a [shared](LockOptions::shared) lock is read-only.
+    /// Any attempt to modify it fails with [`Error::ReadOnly`].
+    ///
     /// # Errors
     ///
     /// This method can fail if the given path does not exist, or the config
-    /// file cannot be read.
-    pub async fn open(root: impl Into<PathBuf>) -> Result<Self, IoError> {
+    /// file cannot be read. If the config file cannot be parsed, the error is
+    /// [`Error::Corruption`]. If another process holds a conflicting lock on
+    /// the root for longer than the timeout, the error is [`Error::Locked`].
+    pub async fn open_with(
+        root: impl Into<PathBuf>,
+        lock_options: LockOptions,
+    ) -> Result<Self, Error> {
         let root = root.into();
+        let lock = Lock::acquire(&root, lock_options).await?;
         let config = Config::from_file(root.join("config.json")).await?;
         let crates = utils::crate_names(&root).await?;
+        let validate_dependencies = false;
+        let lenient = false;
 
         let tree = Self {
             root,
             config,
             crates,
+            validate_dependencies,
+            lenient,
+            lock,
         };
 
         Ok(tree)
     }
 
-    async fn file(&self, crate_name: impl Into<String>) -> Result<IndexFile, IoError> {
-        IndexFile::open(self.root(), crate_name).await
+    /// Open an existing index tree at the given root path, skipping any
+    /// lines of the index files which cannot be parsed.
+    ///
+    /// Every index file is read when the tree is opened, and the lines which
+    /// couldn't be parsed are returned alongside the tree. These lines are
+    /// ignored by all operations on the tree, but are preserved when a file is
+    /// written to.
+    ///
+    /// # Errors
+    ///
+    /// This method can fail if the given path does not exist, or the config
+    /// file cannot be read or parsed.
+    pub async fn open_lenient(
+        root: impl Into<PathBuf>,
+    ) -> Result<(Self, Vec<CorruptionError>), Error> {
+        let mut tree = Self::open(root).await?;
+        tree.lenient = true;
+
+        let mut crate_names: Vec<&String> = tree.crates.iter().collect();
+        crate_names.sort();
+
+        let mut errors = Vec::new();
+        for crate_name in crate_names {
+            let (_, file_errors) = IndexFile::open_lenient(tree.root(), crate_name).await?;
+            errors.extend(file_errors);
+        }
+
+        Ok((tree, errors))
+    }
+
+    pub(crate) async fn file(&self, crate_name: impl Into<String>) -> Result<IndexFile, Error> {
+        if self.lenient {
+            Ok(IndexFile::open_lenient(self.root(), crate_name).await?.0)
+        } else {
+            IndexFile::open(self.root(), crate_name).await
+        }
     }
 
     /// Insert a crate [`Record`] into the index.
     ///
+    /// Returns the path of the index file which was written, relative to the
+    /// root of the index.
+    ///
     /// # Errors
     ///
     /// ## Outer Error
     ///
-    /// an [`IoError`] is returned if the filesystem cannot be read or written
-    /// to.
+    /// an [`Error`] is returned if the filesystem cannot be read or written
+    /// to, or if the index file for the crate is corrupt (see
+    /// [`CorruptionError`]).
     ///
     /// ## Inner Error
     ///
     /// a [`ValidationError`] is returned if the inserted metadata is not valid.
     ///
     /// This can occur if the name contains invalid characters, or if the crate
-    /// name is too similar to an existing crate.
+    /// name is too similar to an existing crate. If dependency validation is
+    /// enabled, this also occurs if a dependency cannot be satisfied.
     pub async fn insert(
         &mut self,
         crate_metadata: Record,
-    ) -> WrappedResult<(), ValidationError, IoError> {
-        if let Err(e) = self.validate_name(crate_metadata.name()) {
-            return Ok(Err(e));
-        }
+    ) -> WrappedResult<PathBuf, ValidationError, Error> {
+        self.lock.check_writable()?;
 
-        let crate_name = crate_metadata.name().clone();
+        let path = get_path(crate_metadata.name());
 
-        // open the index file for editing
-        let mut index_file = self.file(&crate_name).await?;
-
-        // insert the new metadata
-        if let Err(e) = index_file.insert(crate_metadata).await? {
+        let mut batch = Batch::new(self);
+        if let Err(e) = batch.insert(crate_metadata).await? {
             return Ok(Err(e));
         }
 
-        self.crates.insert(crate_name);
+        let changed = batch.save().await?;
+        self.crates.extend(changed);
+
+        Ok(Ok(path))
+    }
 
-        Ok(Ok(()))
+    /// Check that a [`Record`] could be inserted into the index, without
+    /// writing anything.
+    ///
+    /// This runs the same checks as [`Tree::insert`].
+    pub(crate) async fn validate(
+        &self,
+        crate_metadata: &Record,
+    ) -> WrappedResult<(), ValidationError, Error> {
+        Batch::new(self).insert(crate_metadata.clone()).await
     }
 
     /// Mark a selected version of a crate as 'yanked'.
     ///
+    /// Returns the path of the index file which was written, relative to the
+    /// root of the index.
+    ///
     /// # Example
     ///
     /// ```no_run
@@ -212,7 +356,7 @@ impl Tree {
     /// let version = "0.1.0".parse().unwrap();
     ///
     /// match tree.yank(crate_name, &version).await? {
-    ///     Ok(()) => println!("crate yanked!"),
+    ///     Ok(path) => println!("crate yanked! ({:?} changed)", path),
     ///     Err(NotFoundError::Crate(e)) => println!("crate not found! ({})", e.crate_name()),
     ///     Err(NotFoundError::Version(e)) => println!("version not found! ({})", e.version()),
     /// }
@@ -225,8 +369,9 @@ impl Tree {
     ///
     /// ## Outer Error
     ///
-    /// an [`IoError`] is returned if the filesystem cannot be read or written
-    /// to.
+    /// an [`Error`] is returned if the filesystem cannot be read or written
+    /// to, or if the index file for the crate is corrupt (see
+    /// [`CorruptionError`]).
     ///
     /// ## Inner Error
     ///
@@ -236,22 +381,15 @@ impl Tree {
         &mut self,
         crate_name: impl Into<String>,
         version: &Version,
-    ) -> WrappedResult<(), NotFoundError, IoError> {
-        let crate_name = crate_name.into();
-        if self.crates.contains(&crate_name) {
-            Ok(self
-                .file(crate_name)
-                .await?
-                .yank(version)
-                .await?
-                .map_err(NotFoundError::from))
-        } else {
-            Ok(Err(NotFoundError::no_crate(crate_name)))
-        }
+    ) -> WrappedResult<PathBuf, NotFoundError, Error> {
+        self.set_yanked(crate_name.into(), version, true).await
     }
 
     /// Mark a selected version of a crate as 'unyanked'.
     ///
+    /// Returns the path of the index file which was written, relative to the
+    /// root of the index.
+    ///
     /// # Example
     ///
     /// ```no_run
@@ -268,7 +406,7 @@ impl Tree {
     /// let version = "0.1.0".parse().unwrap();
     ///
     /// match tree.unyank(crate_name, &version).await? {
-    ///     Ok(()) => println!("crate unyanked!"),
+    ///     Ok(path) => println!("crate unyanked! ({:?} changed)", path),
     ///     Err(NotFoundError::Crate(e)) => println!("crate not found! ({})", e.crate_name()),
     ///     Err(NotFoundError::Version(e)) => println!("version not found! ({})", e.version()),
     /// }
@@ -281,8 +419,9 @@ impl Tree {
     ///
     /// ## Outer Error
     ///
-    /// an [`IoError`] is returned if the filesystem cannot be read or written
-    /// to.
+    /// an [`Error`] is returned if the filesystem cannot be read or written
+    /// to, or if the index file for the crate is corrupt (see
+    /// [`CorruptionError`]).
     ///
     /// ## Inner Error
     ///
@@ -292,20 +431,345 @@ impl Tree {
         &mut self,
         crate_name: impl Into<String>,
         version: &Version,
-    ) -> WrappedResult<(), NotFoundError, IoError> {
+    ) -> WrappedResult<PathBuf, NotFoundError, Error> {
+        self.set_yanked(crate_name.into(), version, false).await
+    }
+
+    async fn set_yanked(
+        &mut self,
+        crate_name: String,
+        version: &Version,
+        yanked: bool,
+    ) -> WrappedResult<PathBuf, NotFoundError, Error> {
+        self.lock.check_writable()?;
+
+        let path = get_path(&crate_name);
+
+        let mut batch = Batch::new(self);
+        if let Err(e) = batch.set_yanked(crate_name, version, yanked).await? {
+            return Ok(Err(e));
+        }
+
+        batch.save().await?;
+
+        Ok(Ok(path))
+    }
+
+    /// Apply a batch of [`Operation`]s to the index.
+    ///
+    /// Each operation is validated against the changes made by the operations
+    /// before it, so a crate can depend on a version inserted earlier in the
+    /// same batch. The changes are only written once every operation has been
+    /// validated, and each index file is written at most once. If any
+    /// operation is rejected, nothing is written.
+    ///
+    /// Returns the names of the crates whose files changed, in alphabetical
+    /// order.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use crate_index::{tree::{Operation, Tree}, Error, Record};
+    /// #
+    /// # #[async_std::main]
+    /// # async fn main() -> Result<(), Error> {
+    /// #    let mut tree = Tree::open("root").await?;
+    /// #
+    /// let version = "0.1.0".parse().unwrap();
+    ///
+    /// let operations = vec![
+    ///     Operation::Insert(Record::new("some-crate", version, "checksum")),
+    ///     Operation::Yank {
+    ///         crate_name: "other-crate".to_string(),
+    ///         version: "0.2.0".parse().unwrap(),
+    ///     },
+    /// ];
+    ///
+    /// match tree.apply(operations).await? {
+    ///     Ok(changed) => println!("updated {} crates", changed.len()),
+    ///     Err(e) => println!("nothing changed: {}", e),
+    /// }
+    /// #
+    /// #     Ok(())
+    /// # }
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// ## Outer Error
+    ///
+    /// an [`Error`] is returned if the filesystem cannot be read or written
+    /// to, or if an index file is corrupt (see [`CorruptionError`]). If any
+    /// index file cannot be written, none of the files are changed.
+    ///
+    /// ## Inner Error
+    ///
+    /// a [`TransactionError`] is returned for the first operation which is
+    /// rejected.
+    pub async fn apply(
+        &mut self,
+        operations: impl IntoIterator<Item = Operation>,
+    ) -> WrappedResult<Vec<String>, TransactionError, Error> {
+        self.lock.check_writable()?;
+
+        let mut batch = Batch::new(self);
+        for operation in operations {
+            if let Err(e) = batch.apply(operation).await? {
+                return Ok(Err(e));
+            }
+        }
+
+        let changed = batch.save().await?;
+        self.crates.extend(changed.iter().cloned());
+
+        Ok(Ok(changed))
+    }
+
+    /// Rewrite every file in the index into the canonical layout used by
+    /// crates.io.
+    ///
+    /// Fields are written in the same order as crates.io, and features and
+    /// dependencies are sorted, so that later changes to a file produce
+    /// minimal diffs. Fields which this library doesn't understand are kept.
+    ///
+    /// Returns the names of the crates whose files changed.
+    ///
+    /// # Errors
+    ///
+    /// an [`Error`] is returned if the filesystem cannot be read or written
+    /// to.
+    pub async fn normalise(&mut self) -> Result<Vec<String>, Error> {
+        self.lock.check_writable()?;
+
+        let mut crate_names: Vec<&String> = self.crates.iter().collect();
+        crate_names.sort();
+
+        let mut changed = Vec::new();
+
+        for crate_name in crate_names {
+            if self.file(crate_name).await?.normalise().await? {
+                changed.push(crate_name.clone());
+            }
+        }
+
+        Ok(changed)
+    }
+
+    /// Check every file in the index for consistency.
+    ///
+    /// Unlike [`Tree::open_lenient`], this reads the files on the filesystem
+    /// directly, so it also finds files which are in the wrong place, contain
+    /// duplicate versions, or aren't index files at all. See [`Problem`] for
+    /// the full list of checks.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use crate_index::{tree::Tree, Error};
+    /// #
+    /// # #[async_std::main]
+    /// # async fn main() -> Result<(), Error> {
+    /// #    let tree = Tree::open("root").await?;
+    /// #
+    /// for problem in tree.check().await?.problems() {
+    ///     println!("{}", problem);
+    /// }
+    /// #
+    /// #     Ok(())
+    /// # }
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// an [`Error`] is returned if the filesystem cannot be read.
+    pub async fn check(&self) -> Result<Report, Error> {
+        Ok(check::run(self.root(), false).await?)
+    }
+
+    /// Check every file in the index for consistency, and repair the problems
+    /// which can be fixed without losing information.
+    ///
+    /// Misplaced files are moved to the correct directory (unless a file
+    /// already exists there), all but the last copy of a duplicated version
+    /// are removed (matching which copy is read), and empty files are
+    /// deleted. Other problems are reported, but left alone. The changes are
+    /// listed in [`Report::repairs`].
+    ///
+    /// # Errors
+    ///
+    /// an [`Error`] is returned if the filesystem cannot be read or written
+    /// to.
+    pub async fn repair(&mut self) -> Result<Report, Error> {
+        self.lock.check_writable()?;
+
+        let report = check::run(self.root(), true).await?;
+        self.reload().await?;
+
+        Ok(report)
+    }
+
+    /// Re-read the names of the crates in the index, after the files have
+    /// been changed by something other than this tree (such as a git pull).
+    pub(crate) async fn reload(&mut self) -> Result<(), IoError> {
+        self.crates = utils::crate_names(&self.root).await?;
+        Ok(())
+    }
+
+    /// Get all of the [`Record`]s in the index for a given crate.
+    ///
+    /// The records are returned in ascending order of version.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use crate_index::{tree::{Tree, NotFoundError}, Error};
+    /// #
+    /// # #[async_std::main]
+    /// # async fn main() -> Result<(), Error> {
+    /// #    let tree = Tree::open("root").await?;
+    /// #
+    /// match tree.get("some-crate").await? {
+    ///     Ok(records) => {
+    ///         for record in records {
+    ///             println!("found version {}", record.version())
+    ///         }
+    ///     }
+    ///     Err(e) => println!("{}", e),
+    /// }
+    /// #
+    /// #     Ok(())
+    /// # }
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// ## Outer Error
+    ///
+    /// an [`Error`] is returned if the filesystem cannot be read.
+    ///
+    /// ## Inner Error
+    ///
+    /// This function will return [`NotFoundError`] if the crate does not exist
+    /// in the index.
+    pub async fn get(
+        &self,
+        crate_name: impl Into<String>,
+    ) -> WrappedResult<Vec<Record>, NotFoundError, Error> {
+        let crate_name = crate_name.into();
+        if self.crates.contains(&crate_name) {
+            let index_file = self.file(crate_name).await?;
+            Ok(Ok(index_file
+                .into_iter()
+                .map(|(_, record)| record)
+                .collect()))
+        } else {
+            Ok(Err(NotFoundError::no_crate(crate_name)))
+        }
+    }
+
+    /// Get the [`Record`] for a specific version of a crate.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use crate_index::{tree::{Tree, NotFoundError}, Error};
+    /// #
+    /// # #[async_std::main]
+    /// # async fn main() -> Result<(), Error> {
+    /// #    let tree = Tree::open("root").await?;
+    /// #
+    /// let crate_name = "some-crate";
+    /// let version = "0.1.0".parse().unwrap();
+    ///
+    /// match tree.get_version(crate_name, &version).await? {
+    ///     Ok(record) => println!("checksum: {}", record.check_sum()),
+    ///     Err(NotFoundError::Crate(e)) => println!("crate not found! ({})", e.crate_name()),
+    ///     Err(NotFoundError::Version(e)) => println!("version not found! ({})", e.version()),
+    /// }
+    /// #
+    /// #     Ok(())
+    /// # }
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// ## Outer Error
+    ///
+    /// an [`Error`] is returned if the filesystem cannot be read.
+    ///
+    /// ## Inner Error
+    ///
+    /// This function will return [`NotFoundError`] if the crate or the
+    /// selected version does not exist in the index.
+    pub async fn get_version(
+        &self,
+        crate_name: impl Into<String>,
+        version: &Version,
+    ) -> WrappedResult<Record, NotFoundError, Error> {
         let crate_name = crate_name.into();
         if self.crates.contains(&crate_name) {
-            Ok(self
-                .file(crate_name)
-                .await?
-                .unyank(version)
-                .await?
-                .map_err(NotFoundError::from))
+            let index_file = self.file(&crate_name).await?;
+            Ok(index_file
+                .get(version)
+                .cloned()
+                .ok_or_else(|| NotFoundError::no_version(crate_name, version.clone())))
         } else {
             Ok(Err(NotFoundError::no_crate(crate_name)))
         }
     }
 
+    /// Get the newest non-yanked version of a crate which matches a version
+    /// requirement, and can be built with the given version of `rustc`.
+    ///
+    /// Returns `None` if there is no such version.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use crate_index::{tree::Tree, Error, Version};
+    /// #
+    /// # #[async_std::main]
+    /// # async fn main() -> Result<(), Error> {
+    /// #    let tree = Tree::open("root").await?;
+    /// #
+    /// let requirement = "^1.0".parse().unwrap();
+    /// let rustc = Version::new(1, 56, 0);
+    ///
+    /// match tree.latest_compatible("some-crate", &requirement, &rustc).await? {
+    ///     Ok(Some(record)) => println!("use version {}", record.version()),
+    ///     Ok(None) => println!("no compatible version!"),
+    ///     Err(e) => println!("{}", e),
+    /// }
+    /// #
+    /// #     Ok(())
+    /// # }
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// ## Outer Error
+    ///
+    /// an [`Error`] is returned if the filesystem cannot be read
+    ///
+    /// ## Inner Error
+    ///
+    /// A [`NotFoundError`] is returned if the crate can not be found in the
+    /// index
+    pub async fn latest_compatible(
+        &self,
+        crate_name: impl Into<String>,
+        requirement: &VersionReq,
+        rustc: &Version,
+    ) -> WrappedResult<Option<Record>, NotFoundError, Error> {
+        Ok(self.get(crate_name).await?.map(|records| {
+            records.into_iter().rev().find(|record| {
+                !record.yanked()
+                    && requirement.matches(record.version())
+                    && record.is_compatible_with(rustc)
+            })
+        }))
+    }
+
     /// The location on the filesystem of the root of the index
     #[must_use]
     pub fn root(&self) -> &PathBuf {
@@ -315,6 +779,12 @@ impl Tree {
     /// The Url for downloading .crate files
     #[must_use]
     pub fn download(&self) -> &String {
+        self.config.download().as_string()
+    }
+
+    /// The template for the download URLs of .crate files
+    #[must_use]
+    pub fn download_template(&self) -> &DownloadTemplate {
         self.config.download()
     }
 
@@ -331,6 +801,15 @@ impl Tree {
         self.config.allowed_registries()
     }
 
+    /// The names of all crates in the index
+    pub(crate) fn crates(&self) -> impl Iterator<Item = &String> {
+        self.crates.iter()
+    }
+
+    pub(crate) fn config(&self) -> &Config {
+        &self.config
+    }
+
     /// Test whether the index contains a particular crate name.
     ///
     /// This method is fast, since the crate names are stored in memory.
@@ -338,21 +817,40 @@ impl Tree {
         self.crates.contains(name.as_ref())
     }
 
-    fn contains_crate_canonical(&self, name: impl AsRef<str>) -> bool {
-        let name = canonicalise(name);
-        self.crates.iter().map(canonicalise).any(|x| x == name)
-    }
+    /// Find the name of a crate in the index, ignoring ASCII case.
+    ///
+    /// Only the directory which would contain the crate's index file is
+    /// searched, so this doesn't get slower as the index grows.
+    #[cfg(feature = "server")]
+    pub(crate) async fn find_crate(&self, name: &str) -> Result<Option<String>, IoError> {
+        use async_std::stream::StreamExt;
+
+        if self.crates.contains(name) {
+            return Ok(Some(name.to_string()));
+        }
 
-    fn validate_name(&self, name: impl AsRef<str>) -> Result<(), ValidationError> {
-        let name = name.as_ref();
-        if self.contains_crate_canonical(name) && !self.contains_crate(name) {
-            Err(ValidationError::invalid_name(
-                name,
-                "name is too similar to existing crate",
-            ))
-        } else {
-            Ok(())
+        let path = self.root.join(get_path(name));
+        let directory = match path.parent() {
+            Some(directory) if directory.is_dir().await => directory,
+            _ => return Ok(None),
+        };
+
+        let mut entries = async_std::fs::read_dir(directory).await?;
+        while let Some(entry) = entries.next().await {
+            let file_name = entry?.file_name().to_string_lossy().into_owned();
+            if file_name.eq_ignore_ascii_case(name) && self.crates.contains(&file_name) {
+                return Ok(Some(file_name));
+            }
         }
+
+        Ok(None)
+    }
+
+    /// Enable or disable dependency validation for subsequent inserts.
+    ///
+    /// See [`Builder::validate_dependencies`] for details.
+    pub fn set_validate_dependencies(&mut self, enabled: bool) {
+        self.validate_dependencies = enabled;
     }
 }
 
@@ -374,12 +872,85 @@ impl CrateNotFoundError {
         &self.crate_name
     }
 }
-/// Recoverable [`Tree`] errors.
+/// The error raised when a file in the index cannot be parsed.
+///
+/// Operations on a [`Tree`] return this as [`Error::Corruption`].
 ///
 /// # Example
+///
+/// ```no_run
+/// use crate_index::tree::{Error, Tree};
+/// # async {
+///
+/// match Tree::open("/index").await {
+///     Ok(tree) => println!("opened index"),
+///     Err(Error::Corruption(e)) => {
+///         println!("corrupt file {:?} at line {}", e.path(), e.line())
+///     }
+///     Err(e) => println!("couldn't open index: {}", e),
+/// }
+/// # };
 /// ```
-/// # use crate_index::tree::NotFoundError;
-/// # let error = NotFoundError::no_crate("some-crate");
+#[derive(Debug, thiserror::Error)]
+#[error("corrupt index file {path:?} at line {line}: {source}")]
+pub struct CorruptionError {
+    path: PathBuf,
+    line: usize,
+    source: serde_json::Error,
+}
+
+impl CorruptionError {
+    pub(crate) fn new(path: impl AsRef<Path>, line: usize, source: serde_json::Error) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+            line,
+            source,
+        }
+    }
+
+    /// The path of the corrupt file
+    #[must_use]
+    pub fn path(&self) -> &PathBuf {
+        &self.path
+    }
+
+    /// The line of the file which couldn't be parsed, counting from 1
+    #[must_use]
+    pub fn line(&self) -> usize {
+        self.line
+    }
+}
+
+/// Critical errors for [`Tree`] operations
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// filesystem IO error
+    #[error("IO Error")]
+    Io(#[from] IoError),
+
+    /// A file in the index cannot be parsed
+    #[error(transparent)]
+    Corruption(#[from] CorruptionError),
+
+    /// The index is locked by another process
+    #[error(transparent)]
+    Locked(#[from] LockedError),
+
+    /// The index was opened with a shared lock, and cannot be modified
+    #[error("the index was opened with a shared lock, and is read-only")]
+    ReadOnly,
+
+    /// The download template is not valid
+    #[error("Invalid download template")]
+    Template(#[from] TemplateError),
+}
+
+/// Recoverable [`Tree`] errors.
+///
+/// # Example
+/// ```
+/// # use crate_index::tree::NotFoundError;
+/// # let error = NotFoundError::no_crate("some-crate");
 /// #
 /// match error {
 ///     NotFoundError::Crate(e) => println!("couldn't find crate {}", e.crate_name()),
@@ -437,12 +1008,16 @@ impl NotFoundError {
 }
 
 #[cfg(test)]
+#[allow(clippy::manual_assert)]
 mod tests {
 
-    use super::{Record, Tree};
-    use crate::Url;
+    use super::{Error, Operation, Problem, Record, Repair, TransactionError, Tree};
+    use crate::{
+        record::{Dependency, DependencyKind},
+        Url,
+    };
     use async_std::path::PathBuf;
-    use semver::Version;
+    use semver::{Version, VersionReq};
     use std::collections::HashSet;
     use test_case::test_case;
 
@@ -476,6 +1051,178 @@ mod tests {
         );
     }
 
+    #[async_std::test]
+    async fn invalid_download_template() {
+        let temp_dir = tempfile::tempdir().unwrap();
+
+        match Tree::initialise(temp_dir.path(), "/api/v1/crates")
+            .build()
+            .await
+        {
+            Err(Error::Template(e)) => assert_eq!(e.template(), "/api/v1/crates"),
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        assert!(!temp_dir.path().join("config.json").exists());
+    }
+
+    fn dependency(name: &str, req: &str, registry: Option<&str>) -> Dependency {
+        Dependency {
+            name: name.to_string(),
+            req: VersionReq::parse(req).unwrap(),
+            features: Vec::new(),
+            optional: false,
+            default_features: true,
+            target: None,
+            kind: DependencyKind::Normal,
+            registry: registry.map(|url| Url::parse(url).unwrap()),
+            package: None,
+            public: None,
+            artifact: None,
+            bindep_target: None,
+            lib: false,
+        }
+    }
+
+    #[test_case(dependency("dep", "0.1", None); "when dependency is satisfied")]
+    #[test_case(dependency("dep", "^0.2", None) => panics "invalid"; "when no version matches")]
+    #[test_case(dependency("dep", "=0.1.1", None) => panics "invalid"; "when only a yanked version matches")]
+    #[test_case(dependency("missing", "*", None) => panics "invalid"; "when crate doesn't exist")]
+    #[test_case(dependency("Dep", "0.1", None); "when dependency name differs in case")]
+    #[test_case(dependency("other_dep", "0.1", None); "when dependency name differs in separator")]
+    #[test_case(Dependency { package: Some("dep".to_string()), ..dependency("renamed", "0.1", None) }; "when renamed dependency is satisfied")]
+    #[test_case(dependency("serde", "1.0", Some("https://github.com/rust-lang/crates.io-index")); "when registry is allowed")]
+    #[test_case(dependency("serde", "1.0", Some("https://my-intranet:8080/index")) => panics "invalid"; "when registry is not allowed")]
+    fn validate_dependencies(dependency: Dependency) {
+        async_std::task::block_on(async {
+            let temp_dir = tempfile::tempdir().unwrap();
+            let download = "https://my-crates-server.com/api/v1/crates/{crate}/{version}/download";
+
+            let mut tree = Tree::initialise(temp_dir.path(), download)
+                .allow_crates_io()
+                .validate_dependencies()
+                .build()
+                .await
+                .unwrap();
+
+            for version in &["0.1.0", "0.1.1"] {
+                tree.insert(metadata("dep", version))
+                    .await
+                    .unwrap()
+                    .unwrap();
+            }
+            tree.yank("dep", &Version::new(0, 1, 1))
+                .await
+                .unwrap()
+                .unwrap();
+            tree.insert(metadata("other-dep", "0.1.0"))
+                .await
+                .unwrap()
+                .unwrap();
+
+            let mut record = metadata("Some-Name", "0.1.0");
+            record.deps.push(dependency);
+
+            tree.insert(record).await.unwrap().expect("invalid");
+        });
+    }
+
+    #[test_case("*", "1.60.0" => Some("0.3.0".to_string()); "when newest version is compatible")]
+    #[test_case("*", "1.50.0" => Some("0.2.0".to_string()); "when newest version needs a newer rustc")]
+    #[test_case("^0.1", "1.60.0" => Some("0.1.0".to_string()); "when restricted by requirement")]
+    #[test_case("=0.2.1", "1.60.0" => None; "when only a yanked version matches")]
+    #[test_case("^1.0", "1.60.0" => None; "when no version matches")]
+    fn latest_compatible(requirement: &str, rustc: &str) -> Option<String> {
+        async_std::task::block_on(async {
+            let temp_dir = tempfile::tempdir().unwrap();
+            let download = "https://my-crates-server.com/api/v1/crates/{crate}/{version}/download";
+
+            let mut tree = Tree::initialise(temp_dir.path(), download)
+                .build()
+                .await
+                .unwrap();
+
+            for (version, rust_version) in &[
+                ("0.1.0", None),
+                ("0.2.0", Some("1.46")),
+                ("0.2.1", None),
+                ("0.3.0", Some("1.56")),
+            ] {
+                let mut record = metadata("Some-Name", version);
+                record.rust_version = rust_version.map(ToString::to_string);
+                tree.insert(record).await.unwrap().unwrap();
+            }
+            tree.yank("Some-Name", &Version::new(0, 2, 1))
+                .await
+                .unwrap()
+                .unwrap();
+
+            tree.latest_compatible(
+                "Some-Name",
+                &VersionReq::parse(requirement).unwrap(),
+                &Version::parse(rustc).unwrap(),
+            )
+            .await
+            .unwrap()
+            .unwrap()
+            .map(|record| record.version().to_string())
+        })
+    }
+
+    #[async_std::test]
+    async fn artifact_dependencies_survive_rewrite() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let download = "https://my-crates-server.com/api/v1/crates/{crate}/{version}/download";
+
+        let mut tree = Tree::initialise(temp_dir.path(), download)
+            .build()
+            .await
+            .unwrap();
+
+        let mut record = metadata("Some-Name", "0.1.0");
+        record.deps.push(Dependency {
+            public: Some(true),
+            artifact: Some(vec!["bin".to_string()]),
+            bindep_target: Some("wasm32-unknown-unknown".to_string()),
+            lib: true,
+            ..dependency("tool", "0.3", None)
+        });
+        tree.insert(record.clone()).await.unwrap().unwrap();
+
+        // yanking rewrites the index file
+        tree.yank("Some-Name", &Version::new(0, 1, 0))
+            .await
+            .unwrap()
+            .unwrap();
+        record.yank();
+
+        let actual = tree
+            .get_version("Some-Name", &Version::new(0, 1, 0))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(actual, record);
+    }
+
+    #[async_std::test]
+    async fn dependencies_not_validated_by_default() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let download = "https://my-crates-server.com/api/v1/crates/{crate}/{version}/download";
+
+        let mut tree = Tree::initialise(temp_dir.path(), download)
+            .build()
+            .await
+            .unwrap();
+
+        let mut record = metadata("Some-Name", "0.1.0");
+        record.deps.push(dependency("missing", "*", None));
+        tree.insert(record.clone()).await.unwrap().unwrap();
+
+        record.vers = Version::new(0, 2, 0);
+        tree.set_validate_dependencies(true);
+        assert!(tree.insert(record).await.unwrap().is_err());
+    }
+
     #[test_case("Some-Name", "0.1.1" ; "when used properly")]
     #[test_case("Some_Name", "0.1.1" => panics "invalid" ; "when crate names differ only by hyphens and underscores")]
     #[test_case("some_name", "0.1.1" => panics "invalid" ; "when crate names differ only by capitalisation")]
@@ -506,10 +1253,40 @@ mod tests {
 
             // create and insert new metadata
             let new_metadata = metadata(name, version);
-            tree.insert(new_metadata).await.unwrap().expect("invalid");
+            let path = tree.insert(new_metadata).await.unwrap().expect("invalid");
+            assert!(root.join(path).exists());
         });
     }
 
+    #[async_std::test]
+    async fn insert_into_existing_similar_crate() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let root = temp_dir.path();
+        let download = "https://my-crates-server.com/api/v1/crates/{crate}/{version}/download";
+        Tree::initialise(root, download).build().await.unwrap();
+
+        // an index which already contains two crates with similar names
+        for (path, name) in &[
+            ("so/me/some-name", "some-name"),
+            ("so/me/some_name", "some_name"),
+        ] {
+            let path = root.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, metadata(name, "0.1.0").to_string()).unwrap();
+        }
+        let mut tree = Tree::open(root).await.unwrap();
+
+        // each existing crate can still be updated
+        for name in &["some-name", "some_name"] {
+            tree.insert(metadata(name, "0.2.0")).await.unwrap().unwrap();
+        }
+        assert!(tree
+            .insert(metadata("Some-Name", "0.2.0"))
+            .await
+            .unwrap()
+            .is_err());
+    }
+
     fn metadata(name: &str, version: &str) -> Record {
         Record::new(name, Version::parse(version).unwrap(), "checksum")
     }
@@ -574,7 +1351,427 @@ mod tests {
                 panic!("not found");
             }
 
-            tree.unyank(crate_name, &version).await.unwrap().unwrap();
+            let path = tree.unyank(crate_name, &version).await.unwrap().unwrap();
+            assert_eq!(path, PathBuf::from("so/me/Some-Name"));
+        });
+    }
+
+    #[async_std::test]
+    async fn apply() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let root = temp_dir.path();
+        let download = "https://my-crates-server.com/api/v1/crates/{crate}/{version}/download";
+
+        let mut tree = Tree::initialise(root, download)
+            .validate_dependencies()
+            .build()
+            .await
+            .unwrap();
+        tree.insert(metadata("Some-Name", "0.1.0"))
+            .await
+            .unwrap()
+            .unwrap();
+
+        // a dependency can be inserted earlier in the same batch
+        let mut record = metadata("other-name", "0.1.0");
+        record.deps.push(dependency("dep", "0.1", None));
+        let operations = vec![
+            Operation::Insert(metadata("dep", "0.1.0")),
+            Operation::Insert(record),
+            Operation::Insert(metadata("Some-Name", "0.2.0")),
+            Operation::Yank {
+                crate_name: "Some-Name".to_string(),
+                version: Version::new(0, 1, 0),
+            },
+        ];
+        assert_eq!(
+            tree.apply(operations).await.unwrap().unwrap(),
+            vec!["Some-Name", "dep", "other-name"]
+        );
+        assert!(tree.contains_crate("other-name"));
+        assert!(tree
+            .get_version("Some-Name", &Version::new(0, 1, 0))
+            .await
+            .unwrap()
+            .unwrap()
+            .yanked());
+
+        // a rejected operation anywhere in the batch leaves the tree untouched
+        let contents = std::fs::read_to_string(root.join("so/me/Some-Name")).unwrap();
+        let operations = vec![
+            Operation::Insert(metadata("Some-Name", "0.3.0")),
+            Operation::Insert(metadata("new-name", "0.1.0")),
+            Operation::Insert(metadata("some_name", "0.1.0")),
+        ];
+        assert!(matches!(
+            tree.apply(operations).await.unwrap(),
+            Err(TransactionError::Validation(_))
+        ));
+        let operations = vec![
+            Operation::Insert(metadata("Some-Name", "0.3.0")),
+            Operation::Unyank {
+                crate_name: "Some-Name".to_string(),
+                version: Version::new(0, 0, 1),
+            },
+        ];
+        assert!(matches!(
+            tree.apply(operations).await.unwrap(),
+            Err(TransactionError::NotFound(_))
+        ));
+
+        assert_eq!(
+            std::fs::read_to_string(root.join("so/me/Some-Name")).unwrap(),
+            contents
+        );
+        assert!(!tree.contains_crate("new-name"));
+        assert!(!root.join("ne/w-/new-name").exists());
+    }
+
+    #[cfg(feature = "server")]
+    #[async_std::test]
+    async fn find_crate() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let download = "https://my-crates-server.com/api/v1/crates/{crate}/{version}/download";
+
+        let mut tree = Tree::initialise(temp_dir.path(), download)
+            .build()
+            .await
+            .unwrap();
+        for name in &["Some_Name", "abc"] {
+            tree.insert(metadata(name, "0.1.0")).await.unwrap().unwrap();
+        }
+
+        assert_eq!(
+            tree.find_crate("some_name").await.unwrap(),
+            Some("Some_Name".to_string())
+        );
+        assert_eq!(
+            tree.find_crate("abc").await.unwrap(),
+            Some("abc".to_string())
+        );
+        assert_eq!(tree.find_crate("some-name").await.unwrap(), None);
+        assert_eq!(tree.find_crate("other").await.unwrap(), None);
+    }
+
+    #[async_std::test]
+    async fn get() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let root = temp_dir.path();
+        let download = "https://my-crates-server.com/api/v1/crates/{crate}/{version}/download";
+
+        let mut tree = Tree::initialise(root, download)
+            .build()
+            .await
+            .expect("couldn't create tree");
+
+        for version in &["0.1.0", "0.2.0", "1.0.0"] {
+            tree.insert(metadata("Some-Name", version))
+                .await
+                .unwrap()
+                .expect("couldn't insert metadata");
+        }
+
+        let records = tree.get("Some-Name").await.unwrap().unwrap();
+        let expected = vec![
+            metadata("Some-Name", "0.1.0"),
+            metadata("Some-Name", "0.2.0"),
+            metadata("Some-Name", "1.0.0"),
+        ];
+        assert_eq!(records, expected);
+
+        assert!(tree.get("Other-Name").await.unwrap().is_err());
+    }
+
+    #[test_case("Some-Name", "0.1.0"; "when crate exists and version exists")]
+    #[test_case("Some-Name", "0.2.0" => panics "not found"; "when crate exists but version doesn't exist")]
+    #[test_case("Other-Name", "0.1.0" => panics "not found"; "when crate doesn't exist")]
+    fn get_version(crate_name: &str, version: &str) {
+        let version = Version::parse(version).unwrap();
+        async_std::task::block_on(async {
+            let temp_dir = tempfile::tempdir().unwrap();
+            let root = temp_dir.path();
+            let download = "https://my-crates-server.com/api/v1/crates/{crate}/{version}/download";
+
+            let initial_metadata = metadata("Some-Name", "0.1.0");
+
+            let mut tree = Tree::initialise(root, download)
+                .build()
+                .await
+                .expect("couldn't create tree");
+
+            tree.insert(initial_metadata.clone())
+                .await
+                .unwrap()
+                .expect("couldn't insert initial metadata");
+
+            let record = tree
+                .get_version(crate_name, &version)
+                .await
+                .unwrap()
+                .expect("not found");
+
+            assert_eq!(record, initial_metadata);
         });
     }
+
+    #[async_std::test]
+    async fn normalise() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let root = temp_dir.path();
+        let download = "https://my-crates-server.com/api/v1/crates/{crate}/{version}/download";
+
+        let mut tree = Tree::initialise(root, download).build().await.unwrap();
+        tree.insert(metadata("Some-Name", "0.1.0"))
+            .await
+            .unwrap()
+            .unwrap();
+
+        let lines = [
+            r#"{"vers":"0.1.0","name":"other-name","cksum":"checksum","pubtime":"2021-01-01T00:00:00Z"}"#,
+            r#"{"name":"other-name","vers":"0.2.0","cksum":"checksum","features":{"b":[],"a":[]},"yanked":true}"#,
+        ];
+        let path = root.join("ot/he/other-name");
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, lines.join("\n")).unwrap();
+        drop(tree);
+
+        let mut tree = Tree::open(root).await.unwrap();
+
+        assert_eq!(tree.normalise().await.unwrap(), vec!["other-name"]);
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            [
+                r#"{"name":"other-name","vers":"0.1.0","deps":[],"cksum":"checksum","features":{},"yanked":false,"pubtime":"2021-01-01T00:00:00Z"}"#,
+                r#"{"name":"other-name","vers":"0.2.0","deps":[],"cksum":"checksum","features":{"a":[],"b":[]},"yanked":true}"#,
+            ]
+            .join("\n")
+        );
+
+        // files which are already normalised are untouched
+        assert!(tree.normalise().await.unwrap().is_empty());
+    }
+
+    #[async_std::test]
+    async fn corrupt_file() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let root = temp_dir.path();
+        let download = "https://my-crates-server.com/api/v1/crates/{crate}/{version}/download";
+
+        let mut tree = Tree::initialise(root, download).build().await.unwrap();
+        tree.insert(metadata("Some-Name", "0.1.0"))
+            .await
+            .unwrap()
+            .unwrap();
+
+        let path = root.join("so/me/Some-Name");
+        let mut contents = std::fs::read_to_string(&path).unwrap();
+        contents.push_str("\nnot json");
+        std::fs::write(&path, &contents).unwrap();
+
+        // operations on the corrupt file fail, rather than panicking
+        let version = Version::new(0, 1, 0);
+        match tree.yank("Some-Name", &version).await {
+            Err(Error::Corruption(e)) => assert_eq!(e.line(), 2),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        match tree.insert(metadata("Some-Name", "0.2.0")).await {
+            Err(Error::Corruption(e)) => assert_eq!(e.line(), 2),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        drop(tree);
+
+        // opening leniently reports the corrupt line, and skips it
+        let (mut tree, errors) = Tree::open_lenient(root).await.unwrap();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].path().as_os_str(), path.as_os_str());
+
+        tree.yank("Some-Name", &version).await.unwrap().unwrap();
+        assert!(tree
+            .get_version("Some-Name", &version)
+            .await
+            .unwrap()
+            .unwrap()
+            .yanked());
+        assert!(std::fs::read_to_string(&path)
+            .unwrap()
+            .ends_with("\nnot json"));
+    }
+
+    #[async_std::test]
+    async fn check() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let root = temp_dir.path();
+        let download = "https://my-crates-server.com/api/v1/crates/{crate}/{version}/download";
+        let check_sum = "d867001db0e2b6e0496f9fac96930e2d42233ecd3ca0413e0753d4c7695d289c";
+
+        let mut tree = Tree::initialise(root, download).build().await.unwrap();
+        tree.insert(Record::new("Some-Name", Version::new(0, 1, 0), check_sum))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(tree.check().await.unwrap().is_ok());
+
+        let line = |name: &str, version: &str, check_sum: &str| {
+            Record::new(name, Version::parse(version).unwrap(), check_sum).to_string()
+        };
+        for (path, lines) in &[
+            (
+                "wr/on/other-name",
+                vec![
+                    line("other-name", "0.1.0", check_sum),
+                    line("other-name", "0.1.0", check_sum),
+                ],
+            ),
+            (
+                "so/me/some_name",
+                vec![line("Some-Name", "0.1.0", "checksum")],
+            ),
+            ("README.md", vec!["# My Index".to_string()]),
+            ("em/pt/empty", vec![]),
+        ] {
+            let path = root.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, lines.join("\n")).unwrap();
+        }
+
+        let report = tree.check().await.unwrap();
+        let problems: Vec<String> = report.problems().iter().map(ToString::to_string).collect();
+        assert_eq!(problems.len(), 7, "{:#?}", problems);
+        assert!(report.repairs().is_empty());
+
+        let problems = report.problems();
+        assert!(matches!(&problems[0], Problem::StrayFile { path } if path.ends_with("README.md")));
+        assert!(matches!(&problems[1], Problem::StrayFile { path } if path.ends_with("empty")));
+        assert!(matches!(
+            &problems[2],
+            Problem::NameMismatch { crate_name, name, .. } if crate_name == "some_name" && name == "Some-Name"
+        ));
+        assert!(
+            matches!(&problems[3], Problem::CheckSum { check_sum, .. } if check_sum == "checksum")
+        );
+        assert!(matches!(
+            &problems[4],
+            Problem::DuplicateVersion { crate_name, .. } if crate_name == "other-name"
+        ));
+        assert!(
+            matches!(&problems[5], Problem::WrongPath { expected, .. } if expected.ends_with("ot/he/other-name"))
+        );
+        assert!(
+            matches!(&problems[6], Problem::NameCollision { names } if names == &["Some-Name", "some_name"])
+        );
+    }
+
+    #[async_std::test]
+    async fn repair() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let root = temp_dir.path();
+        let download = "https://my-crates-server.com/api/v1/crates/{crate}/{version}/download";
+
+        let mut tree = Tree::initialise(root, download).build().await.unwrap();
+
+        let lines = [
+            metadata("other-name", "0.1.0").to_string(),
+            metadata("other-name", "0.2.0").to_string(),
+            metadata("other-name", "0.1.0").to_string(),
+        ];
+        let path = root.join("wr/on/other-name");
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, lines.join("\n")).unwrap();
+        std::fs::write(root.join("empty"), "").unwrap();
+        std::fs::write(root.join("README.md"), "# My Index").unwrap();
+
+        let report = tree.repair().await.unwrap();
+        let mut repairs = report.repairs().to_vec();
+        repairs.sort_by_key(|repair| format!("{:?}", repair));
+        assert_eq!(
+            repairs,
+            vec![
+                Repair::Deduplicated {
+                    path: "wr/on/other-name".into(),
+                    version: Version::new(0, 1, 0),
+                },
+                Repair::Moved {
+                    from: "wr/on/other-name".into(),
+                    to: "ot/he/other-name".into(),
+                },
+                Repair::Removed {
+                    path: "empty".into(),
+                },
+            ]
+        );
+
+        // files which aren't empty are left alone
+        assert!(root.join("README.md").exists());
+        assert!(!root.join("empty").exists());
+        assert!(!path.exists());
+
+        assert_eq!(
+            tree.get("other-name").await.unwrap().unwrap(),
+            vec![
+                metadata("other-name", "0.1.0"),
+                metadata("other-name", "0.2.0")
+            ]
+        );
+
+        // the only remaining problems can't be repaired
+        let report = tree.repair().await.unwrap();
+        assert!(report.repairs().is_empty());
+        assert!(report.problems().iter().all(|problem| matches!(
+            problem,
+            Problem::CheckSum { .. } | Problem::StrayFile { .. }
+        )));
+    }
+
+    #[async_std::test]
+    async fn repair_keeps_the_last_duplicate() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let root = temp_dir.path();
+        let download = "https://my-crates-server.com/api/v1/crates/{crate}/{version}/download";
+
+        Tree::initialise(root, download).build().await.unwrap();
+
+        let mut yanked = metadata("some-name", "0.1.0");
+        yanked.yank();
+        let lines = [
+            metadata("some-name", "0.1.0").to_string(),
+            metadata("some-name", "0.2.0").to_string(),
+            yanked.to_string(),
+        ];
+        let path = root.join("so/me/some-name");
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, lines.join("\n")).unwrap();
+
+        // the index file reads the last copy of a version
+        let mut tree = Tree::open(root).await.unwrap();
+        let before = tree.get("some-name").await.unwrap().unwrap();
+        assert!(before[0].yanked());
+
+        let report = tree.repair().await.unwrap();
+        assert_eq!(report.repairs().len(), 1);
+
+        // and the repair keeps the same copy
+        assert_eq!(tree.get("some-name").await.unwrap().unwrap(), before);
+        let repaired = std::fs::read(&path).unwrap();
+        assert_eq!(String::from_utf8_lossy(&repaired).lines().count(), 2);
+
+        // in the same format as the index file writes
+        tree.file("some-name").await.unwrap().save().await.unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), repaired);
+    }
+
+    #[async_std::test]
+    async fn corrupt_config() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let root = temp_dir.path();
+        std::fs::write(root.join("config.json"), "{").unwrap();
+
+        match Tree::open(root).await {
+            Err(Error::Corruption(e)) => {
+                assert_eq!(e.path().as_os_str(), root.join("config.json").as_os_str());
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        assert!(Tree::open_lenient(root).await.is_err());
+    }
 }
@@ -0,0 +1,318 @@
+use super::{canonicalise, get_path, join_lines, CorruptionError, Record};
+use crate::{utils, validate, validate::Error as ValidationError};
+use async_std::{
+    fs,
+    path::{Path, PathBuf},
+};
+use semver::Version;
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    io::Error as IoError,
+};
+
+/// The result of checking an index [`Tree`](super::Tree) for consistency.
+///
+/// All paths are relative to the root of the index.
+#[derive(Debug, Default)]
+pub struct Report {
+    problems: Vec<Problem>,
+    repairs: Vec<Repair>,
+}
+
+impl Report {
+    /// The problems which were found in the index.
+    ///
+    /// When the index is repaired, this includes the problems which were
+    /// repaired.
+    #[must_use]
+    pub fn problems(&self) -> &[Problem] {
+        &self.problems
+    }
+
+    /// The changes which were made to the index to repair it.
+    ///
+    /// This is always empty unless the index was repaired.
+    #[must_use]
+    pub fn repairs(&self) -> &[Repair] {
+        &self.repairs
+    }
+
+    /// Returns `true` if no problems were found
+    #[must_use]
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+
+    /// The paths which were written, moved, or removed by the repairs
+    pub(crate) fn touched_paths(&self) -> impl Iterator<Item = &PathBuf> {
+        self.repairs.iter().flat_map(|repair| match repair {
+            Repair::Moved { from, to } => vec![from, to],
+            Repair::Deduplicated { path, .. } | Repair::Removed { path } => vec![path],
+        })
+    }
+}
+
+/// A problem found when checking an index [`Tree`](super::Tree).
+#[derive(Debug, thiserror::Error)]
+pub enum Problem {
+    /// A line of an index file cannot be parsed
+    #[error(transparent)]
+    Corrupt(CorruptionError),
+
+    /// An index file is not in the directory given by its crate name
+    #[error("index file {path:?} should be at {expected:?}")]
+    WrongPath {
+        /// the path of the file
+        path: PathBuf,
+        /// the path at which the file should be
+        expected: PathBuf,
+    },
+
+    /// A record is in the index file for a different crate
+    #[error("record for {name} ({version}) is in the index file for {crate_name}")]
+    NameMismatch {
+        /// the crate name of the index file
+        crate_name: String,
+        /// the name in the record
+        name: String,
+        /// the version in the record
+        version: Version,
+    },
+
+    /// A version appears more than once in an index file
+    #[error("{crate_name} ({version}) appears more than once")]
+    DuplicateVersion {
+        /// the crate name of the index file
+        crate_name: String,
+        /// the duplicated version
+        version: Version,
+    },
+
+    /// A checksum is not 64 hexadecimal characters
+    #[error("{crate_name} ({version}) has an invalid checksum '{check_sum}'")]
+    CheckSum {
+        /// the crate name of the index file
+        crate_name: String,
+        /// the version in the record
+        version: Version,
+        /// the invalid checksum
+        check_sum: String,
+    },
+
+    /// The name of an index file is not a valid crate name
+    #[error(transparent)]
+    InvalidName(ValidationError),
+
+    /// Several crates have names which are the same once capitalisation,
+    /// hyphens and underscores are ignored
+    #[error("crate names are too similar: {}", .names.join(", "))]
+    NameCollision {
+        /// the colliding crate names
+        names: Vec<String>,
+    },
+
+    /// A file which contains no records, but would be treated as a crate
+    #[error("{path:?} is not an index file")]
+    StrayFile {
+        /// the path of the file
+        path: PathBuf,
+    },
+}
+
+/// A change made to an index [`Tree`](super::Tree) to repair a [`Problem`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Repair {
+    /// An index file was moved to the directory given by its crate name
+    Moved {
+        /// the previous path of the file
+        from: PathBuf,
+        /// the new path of the file
+        to: PathBuf,
+    },
+
+    /// All but the last occurrence of a version was removed from an index
+    /// file
+    Deduplicated {
+        /// the path of the file
+        path: PathBuf,
+        /// the duplicated version
+        version: Version,
+    },
+
+    /// An empty file was removed
+    Removed {
+        /// the path of the file
+        path: PathBuf,
+    },
+}
+
+/// Check every file in the index at `root`, optionally repairing the problems
+/// which can be fixed without losing information.
+///
+/// Misplaced files are moved (unless a file already exists at the correct
+/// path), all but the last copy of a duplicated version are removed, and
+/// empty files are deleted. All other problems are only reported.
+pub(crate) async fn run(root: &Path, repair: bool) -> Result<Report, IoError> {
+    let mut report = Report::default();
+    let mut crate_names = BTreeSet::new();
+
+    for path in utils::index_files(root).await? {
+        let relative = path.strip_prefix(root).unwrap().to_path_buf();
+        let crate_name = relative
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .into_owned();
+
+        let bytes = fs::read(&path).await?;
+        let contents = String::from_utf8_lossy(&bytes);
+
+        let FileCheck {
+            problems,
+            duplicates,
+            kept_lines,
+            records,
+        } = check_lines(&path, &crate_name, &contents);
+
+        // a file without a single record isn't an index file, so its lines
+        // aren't reported as corrupt
+        if records == 0 {
+            report.problems.push(Problem::StrayFile {
+                path: relative.clone(),
+            });
+
+            if repair && contents.trim().is_empty() {
+                fs::remove_file(&path).await?;
+                report.repairs.push(Repair::Removed { path: relative });
+            }
+
+            continue;
+        }
+
+        report.problems.extend(problems);
+
+        if let Err(e) = validate::name(&crate_name) {
+            report.problems.push(Problem::InvalidName(e));
+        }
+
+        if repair && !duplicates.is_empty() {
+            utils::write_atomic(&path, join_lines(&kept_lines)).await?;
+            report
+                .repairs
+                .extend(duplicates.into_iter().map(|version| Repair::Deduplicated {
+                    path: relative.clone(),
+                    version,
+                }));
+        }
+
+        let expected = get_path(&crate_name);
+        if relative != expected {
+            report.problems.push(Problem::WrongPath {
+                path: relative.clone(),
+                expected: expected.clone(),
+            });
+
+            let destination = root.join(&expected);
+            if repair && !destination.exists().await {
+                fs::create_dir_all(destination.parent().unwrap()).await?;
+                fs::rename(&path, &destination).await?;
+                report.repairs.push(Repair::Moved {
+                    from: relative,
+                    to: expected,
+                });
+            }
+        }
+
+        crate_names.insert(crate_name);
+    }
+
+    let mut canonical_names: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for crate_name in crate_names {
+        canonical_names
+            .entry(canonicalise(&crate_name))
+            .or_default()
+            .push(crate_name);
+    }
+
+    report.problems.extend(
+        canonical_names
+            .into_iter()
+            .map(|(_, names)| names)
+            .filter(|names| names.len() > 1)
+            .map(|names| Problem::NameCollision { names }),
+    );
+
+    Ok(report)
+}
+
+/// The result of checking the lines of a single index file
+struct FileCheck<'a> {
+    problems: Vec<Problem>,
+    duplicates: Vec<Version>,
+    kept_lines: Vec<&'a str>,
+    records: usize,
+}
+
+fn check_lines<'a>(path: &Path, crate_name: &str, contents: &'a str) -> FileCheck<'a> {
+    let mut problems = Vec::new();
+    let mut versions = HashMap::new();
+    let mut duplicates = Vec::new();
+    let mut kept_lines = Vec::new();
+    let mut records = 0;
+
+    for (index, line) in contents.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let record = match serde_json::from_str::<Record>(line) {
+            Ok(record) => record,
+            Err(e) => {
+                problems.push(Problem::Corrupt(CorruptionError::new(path, index + 1, e)));
+                kept_lines.push(line);
+                continue;
+            }
+        };
+        records += 1;
+
+        if record.name() != crate_name {
+            problems.push(Problem::NameMismatch {
+                crate_name: crate_name.to_string(),
+                name: record.name().clone(),
+                version: record.version().clone(),
+            });
+        }
+
+        if !is_check_sum(record.check_sum()) {
+            problems.push(Problem::CheckSum {
+                crate_name: crate_name.to_string(),
+                version: record.version().clone(),
+                check_sum: record.check_sum().clone(),
+            });
+        }
+
+        // like `IndexFile`, the last occurrence of a version wins
+        if let Some(&kept) = versions.get(record.version()) {
+            kept_lines[kept] = line;
+            problems.push(Problem::DuplicateVersion {
+                crate_name: crate_name.to_string(),
+                version: record.version().clone(),
+            });
+            duplicates.push(record.version().clone());
+        } else {
+            versions.insert(record.version().clone(), kept_lines.len());
+            kept_lines.push(line);
+        }
+    }
+
+    FileCheck {
+        problems,
+        duplicates,
+        kept_lines,
+        records,
+    }
+}
+
+fn is_check_sum(check_sum: &str) -> bool {
+    check_sum.len() == 64 && check_sum.bytes().all(|byte| byte.is_ascii_hexdigit())
+}
@@ -1,12 +1,12 @@
+use super::{CorruptionError, Error};
+use crate::{download::DownloadTemplate, utils};
 use async_std::{
     fs::File,
-    io::{
-        prelude::{ReadExt, WriteExt},
-        BufReader,
-    },
+    io::{prelude::ReadExt, BufReader},
     path::Path,
 };
 use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
 use std::{fmt, io::Error as IoError};
 use url::Url;
 
@@ -14,13 +14,18 @@ use url::Url;
 #[derive(Debug, Serialize, Deserialize, PartialEq)]
 #[serde(rename_all = "kebab-case")]
 pub(crate) struct Config {
-    dl: String,
+    dl: DownloadTemplate,
 
     #[serde(skip_serializing_if = "Option::is_none", default)]
     api: Option<Url>,
 
     #[serde(skip_serializing_if = "Vec::is_empty", default)]
     allowed_registries: Vec<Url>,
+
+    /// Fields which this library doesn't understand (such as `auth-required`),
+    /// kept so that they survive when the config is rewritten
+    #[serde(flatten)]
+    other: Map<String, Value>,
 }
 
 impl Config {
@@ -31,27 +36,24 @@ impl Config {
     //
     // # Example
     // ```
-    // use crate_index::{Url, index::Config};
+    // use crate_index::{Url, download::DownloadTemplate, index::Config};
     //
     // let download = "https://my-crates-server.com/api/v1/crates/{crate}/{version}/download";
     //
     // // Create a new Config struct, setting the url for downloading .crate files
-    // let config = Config::new(download)
+    // let config = Config::new(DownloadTemplate::parse(download).unwrap())
     //
     //     // Optionally set the URL that cargo should use to publish, yank, etc.
     //     .with_api(Url::parse("https://my-crates-server.com/").unwrap())
     //
     //     // Set registries that crates within this registry are allowed to depend on
     //     .with_allowed_registry(Url::parse("https://github.com/rust-lang/crates.io-index").unwrap());
-    pub fn new(crate_download: impl Into<String>) -> Self {
-        let crate_download = crate_download.into();
-
-        debug_assert!(Url::parse(&crate_download).is_ok());
-
+    pub fn new(crate_download: DownloadTemplate) -> Self {
         Self {
             dl: crate_download,
             api: None,
             allowed_registries: Vec::default(),
+            other: Map::new(),
         }
     }
 
@@ -77,7 +79,7 @@ impl Config {
     }
 
     /// The Url for downloading .crate files
-    pub fn download(&self) -> &String {
+    pub fn download(&self) -> &DownloadTemplate {
         &self.dl
     }
 
@@ -93,22 +95,18 @@ impl Config {
     }
 
     pub(crate) async fn to_file(&self, path: impl AsRef<Path>) -> Result<(), IoError> {
-        let mut file = File::create(path).await?;
-        file.write_all(self.to_string().as_bytes()).await?;
-
-        Ok(())
+        utils::write_atomic(path.as_ref(), self.to_string()).await
     }
 
-    pub(crate) async fn from_file(path: impl AsRef<Path>) -> Result<Self, IoError> {
+    pub(crate) async fn from_file(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let path = path.as_ref();
         let file = File::open(path).await?;
         let mut reader = BufReader::new(file);
 
         let mut bytes = Vec::new();
         reader.read_to_end(&mut bytes).await?;
 
-        let metadata = serde_json::from_slice(&bytes).expect("malformed json");
-
-        Ok(metadata)
+        serde_json::from_slice(&bytes).map_err(|e| CorruptionError::new(path, e.line(), e).into())
     }
 }
 
@@ -120,27 +118,34 @@ impl fmt::Display for Config {
 
 #[cfg(test)]
 mod tests {
-    use super::Config;
+    use super::{Config, Error};
+    use crate::download::DownloadTemplate;
     use url::Url;
 
     #[test]
     fn new() {
         let url = "https://crates.io/api/v1/crates/{crate}/{version}/download";
 
-        Config::new(url);
+        Config::new(DownloadTemplate::parse(url).unwrap());
     }
 
     #[test]
     fn allow_crates_io() {
-        let config1 =
-            Config::new("https://my-crates-server.com/api/v1/crates/{crate}/{version}/download")
-                .with_allowed_registry(
-                    Url::parse("https://github.com/rust-lang/crates.io-index").unwrap(),
-                );
+        let config1 = Config::new(
+            DownloadTemplate::parse(
+                "https://my-crates-server.com/api/v1/crates/{crate}/{version}/download",
+            )
+            .unwrap(),
+        )
+        .with_allowed_registry(Url::parse("https://github.com/rust-lang/crates.io-index").unwrap());
 
-        let config2 =
-            Config::new("https://my-crates-server.com/api/v1/crates/{crate}/{version}/download")
-                .with_crates_io_registry();
+        let config2 = Config::new(
+            DownloadTemplate::parse(
+                "https://my-crates-server.com/api/v1/crates/{crate}/{version}/download",
+            )
+            .unwrap(),
+        )
+        .with_crates_io_registry();
 
         assert_eq!(config1, config2);
     }
@@ -154,12 +159,12 @@ mod tests {
             Url::parse("https://my-intranet:8080/index").unwrap(),
         ];
 
-        let config = Config::new(url)
+        let config = Config::new(DownloadTemplate::parse(url).unwrap())
             .with_api(api.clone())
             .with_allowed_registry(registries[0].clone())
             .with_allowed_registry(registries[1].clone());
 
-        assert_eq!(config.download(), &url);
+        assert_eq!(config.download().as_str(), url);
         assert_eq!(config.api(), Some(&api));
         assert_eq!(config.allowed_registries(), &registries);
     }
@@ -168,7 +173,7 @@ mod tests {
     fn format_simple() {
         let url = "https://crates.io/api/v1/crates/{crate}/{version}/download";
 
-        let config = Config::new(url);
+        let config = Config::new(DownloadTemplate::parse(url).unwrap());
 
         let expected = r#"{
   "dl": "https://crates.io/api/v1/crates/{crate}/{version}/download"
@@ -185,7 +190,7 @@ mod tests {
         let url = "https://my-crates-server.com/api/v1/crates/{crate}/{version}/download";
         let api = Url::parse("https://my-crates-server.com/").unwrap();
 
-        let config = Config::new(url)
+        let config = Config::new(DownloadTemplate::parse(url).unwrap())
             .with_api(api)
             .with_allowed_registry(
                 Url::parse("https://github.com/rust-lang/crates.io-index").unwrap(),
@@ -212,11 +217,15 @@ mod tests {
 
     #[async_std::test]
     async fn to_and_from_file() {
-        let config =
-            Config::new("https://my-crates-server.com/api/v1/crates/{crate}/{version}/download")
-                .with_api(Url::parse("https://my-crates-server.com/").unwrap())
-                .with_crates_io_registry()
-                .with_allowed_registry(Url::parse("https://my-intranet:8080/index").unwrap());
+        let config = Config::new(
+            DownloadTemplate::parse(
+                "https://my-crates-server.com/api/v1/crates/{crate}/{version}/download",
+            )
+            .unwrap(),
+        )
+        .with_api(Url::parse("https://my-crates-server.com/").unwrap())
+        .with_crates_io_registry()
+        .with_allowed_registry(Url::parse("https://my-intranet:8080/index").unwrap());
 
         let temp_dir = tempfile::tempdir().unwrap();
         let path = temp_dir.path().join("config.json");
@@ -227,4 +236,84 @@ mod tests {
 
         assert_eq!(config, config2);
     }
+
+    #[async_std::test]
+    async fn unknown_fields() {
+        let original = r#"{
+  "dl": "https://my-crates-server.com/api/v1/crates",
+  "api": "https://my-crates-server.com/",
+  "auth-required": true,
+  "future": {
+    "b": [1, 2],
+    "a": null
+  }
+}"#;
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("config.json");
+        async_std::fs::write(&path, original).await.unwrap();
+
+        let config = Config::from_file(&path).await.unwrap();
+        config.to_file(&path).await.unwrap();
+
+        let rewritten: serde_json::Value =
+            serde_json::from_str(&async_std::fs::read_to_string(&path).await.unwrap()).unwrap();
+        let original: serde_json::Value = serde_json::from_str(original).unwrap();
+
+        assert_eq!(rewritten, original);
+        assert_eq!(
+            Config::from_file(&path).await.unwrap().to_string(),
+            config.to_string()
+        );
+    }
+
+    #[async_std::test]
+    async fn failed_write() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("config.json");
+        let original = r#"{"dl":"https://my-crates-server.com/api/v1/crates"}"#;
+        async_std::fs::write(&path, original).await.unwrap();
+
+        // a directory in the way of the temporary file makes the write fail
+        async_std::fs::create_dir(temp_dir.path().join(".config.json.tmp"))
+            .await
+            .unwrap();
+
+        let config = Config::from_file(&path)
+            .await
+            .unwrap()
+            .with_crates_io_registry();
+        assert!(config.to_file(&path).await.is_err());
+
+        assert_eq!(
+            async_std::fs::read_to_string(&path).await.unwrap(),
+            original
+        );
+    }
+
+    #[async_std::test]
+    async fn malformed_file() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("config.json");
+
+        async_std::fs::write(&path, "{\n  \"dl\": ,\n}")
+            .await
+            .unwrap();
+        match Config::from_file(&path).await {
+            Err(Error::Corruption(e)) => {
+                assert_eq!(e.path().as_os_str(), path.as_os_str());
+                assert_eq!(e.line(), 2);
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        // an invalid download template is also corruption
+        async_std::fs::write(&path, r#"{"dl":"not a url"}"#)
+            .await
+            .unwrap();
+        assert!(matches!(
+            Config::from_file(&path).await,
+            Err(Error::Corruption(_))
+        ));
+    }
 }
@@ -1,16 +1,18 @@
-use super::Record;
-use crate::{validate, validate::Error as ValidationError, WrappedResult};
+use super::{CorruptionError, Error, Record};
+use crate::{utils, validate, validate::Error as ValidationError, WrappedResult};
 use async_std::{
-    fs::{File, OpenOptions},
-    io::{
-        prelude::{BufReadExt, SeekExt, WriteExt},
-        BufReader, SeekFrom,
-    },
+    fs::{self, File},
+    io::{prelude::BufReadExt, BufReader},
     path::{Path, PathBuf},
     stream::StreamExt,
 };
 use semver::Version;
-use std::{collections::BTreeMap, fmt, io::Error as IoError};
+use std::{
+    borrow::Borrow,
+    collections::BTreeMap,
+    fmt,
+    io::{Error as IoError, ErrorKind},
+};
 
 /// A file in an index.
 ///
@@ -19,6 +21,9 @@ use std::{collections::BTreeMap, fmt, io::Error as IoError};
 /// Inserting a [`Record`] into the `IndexFile` is performed by updating the
 /// cache, and writing to the underlying file.
 ///
+/// The file is replaced atomically whenever it is written, so a crash part of
+/// the way through a write never leaves the file truncated.
+///
 /// # Warning
 ///
 /// This object makes no attempt to *lock* the underlying file. It is the
@@ -27,41 +32,104 @@ use std::{collections::BTreeMap, fmt, io::Error as IoError};
 #[allow(clippy::module_name_repetitions)]
 pub struct IndexFile {
     crate_name: String,
-    file: File,
+    path: PathBuf,
     entries: BTreeMap<Version, Record>,
+
+    /// Lines which couldn't be parsed when the file was opened leniently.
+    /// These are written back after the records when the file is saved.
+    corrupt_lines: Vec<String>,
 }
 
 impl IndexFile {
-    /// Open an existing file, or create a new one if it doesn't exist.
+    /// Open an existing file, or start a new one if it doesn't exist.
     ///
-    /// For convenience, this method will also create the parent folders in the
-    /// index if they don't yet exist.
+    /// A new file (and its parent folders in the index) is only created when
+    /// it is first written to.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if the file cannot be read. If a line of the file
+    /// cannot be parsed, the error is [`Error::Corruption`].
     pub async fn open(
         root: impl AsRef<Path>,
         crate_name: impl Into<String>,
-    ) -> Result<Self, IoError> {
-        let crate_name = crate_name.into();
-        let path = root.as_ref().join(get_path(&crate_name));
+    ) -> Result<Self, Error> {
+        let (index_file, mut errors) = Self::read(root.as_ref(), crate_name.into(), false).await?;
 
-        create_parents(&path).await?;
+        match errors.pop() {
+            Some(error) => Err(error.into()),
+            None => Ok(index_file),
+        }
+    }
 
-        let file = open_file(&path).await?;
+    /// Open an existing file, or start a new one if it doesn't exist,
+    /// skipping any lines which cannot be parsed.
+    ///
+    /// The skipped lines are returned alongside the file. They are preserved
+    /// when the file is written to.
+    pub async fn open_lenient(
+        root: impl AsRef<Path>,
+        crate_name: impl Into<String>,
+    ) -> Result<(Self, Vec<CorruptionError>), IoError> {
+        Self::read(root.as_ref(), crate_name.into(), true).await
+    }
 
-        let mut lines = BufReader::new(&file).lines();
+    async fn read(
+        root: &Path,
+        crate_name: String,
+        lenient: bool,
+    ) -> Result<(Self, Vec<CorruptionError>), IoError> {
+        let path = root.join(get_path(&crate_name));
 
         let mut entries = BTreeMap::new();
+        let mut corrupt_lines = Vec::new();
+        let mut errors = Vec::new();
+        let mut line_number = 0;
+
+        let mut lines = match File::open(&path).await {
+            Ok(file) => BufReader::new(file).lines(),
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                let index_file = Self {
+                    crate_name,
+                    path,
+                    entries,
+                    corrupt_lines,
+                };
+                return Ok((index_file, errors));
+            }
+            Err(e) => return Err(e),
+        };
 
         while let Some(line) = lines.next().await {
             let line = line?;
-            let metadata: Record = serde_json::from_str(&line).expect("JSON encoding error");
-            entries.insert(metadata.version().clone(), metadata);
+            line_number += 1;
+
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            match serde_json::from_str::<Record>(&line) {
+                Ok(metadata) => {
+                    entries.insert(metadata.version().clone(), metadata);
+                }
+                Err(e) => {
+                    errors.push(CorruptionError::new(&path, line_number, e));
+                    if !lenient {
+                        break;
+                    }
+                    corrupt_lines.push(line);
+                }
+            }
         }
 
-        Ok(Self {
+        let index_file = Self {
             crate_name,
-            file,
+            path,
             entries,
-        })
+            corrupt_lines,
+        };
+
+        Ok((index_file, errors))
     }
 
     /// Insert a [`Record`] into the `IndexFile`.
@@ -85,19 +153,34 @@ impl IndexFile {
         &mut self,
         metadata: Record,
     ) -> WrappedResult<(), ValidationError, IoError> {
-        if let Err(e) = self.validate(&metadata) {
+        if let Err(e) = self.add(metadata) {
             return Ok(Err(e));
         }
 
-        self.entries.insert(metadata.version().clone(), metadata);
-
         self.save().await?;
 
         Ok(Ok(()))
     }
 
-    fn get_mut(&mut self, version: &Version) -> Option<&mut Record> {
-        self.entries.get_mut(version)
+    /// Insert a [`Record`] into the cached entries, without writing to the
+    /// file.
+    ///
+    /// The changes are written by the next call to [`IndexFile::save`].
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the version of the incoming
+    /// metadata is not later than the all existing entries.
+    pub fn add(&mut self, metadata: Record) -> Result<(), ValidationError> {
+        self.validate(&metadata)?;
+        self.entries.insert(metadata.version().clone(), metadata);
+
+        Ok(())
+    }
+
+    /// Get the [`Record`] for a specific version of the crate, if it exists
+    pub fn get(&self, version: &Version) -> Option<&Record> {
+        self.entries.get(version)
     }
 
     /// Mark a selected version of the crate as 'yanked'.
@@ -110,17 +193,13 @@ impl IndexFile {
         &mut self,
         version: &Version,
     ) -> WrappedResult<(), VersionNotFoundError, IoError> {
-        match self.get_mut(version) {
-            Some(record) => {
-                record.yank();
-                self.save().await?;
-                Ok(Ok(()))
-            }
-            None => Ok(Err(VersionNotFoundError {
-                crate_name: self.crate_name.clone(),
-                version: version.clone(),
-            })),
+        if let Err(e) = self.set_yanked(version, true) {
+            return Ok(Err(e));
         }
+
+        self.save().await?;
+
+        Ok(Ok(()))
     }
 
     /// Mark a selected version of the crate as 'unyanked'.
@@ -133,16 +212,64 @@ impl IndexFile {
         &mut self,
         version: &Version,
     ) -> WrappedResult<(), VersionNotFoundError, IoError> {
-        match self.get_mut(version) {
-            Some(record) => {
-                record.unyank();
-                self.save().await?;
-                Ok(Ok(()))
+        if let Err(e) = self.set_yanked(version, false) {
+            return Ok(Err(e));
+        }
+
+        self.save().await?;
+
+        Ok(Ok(()))
+    }
+
+    /// Mark a selected version of the crate as 'yanked' or 'unyanked' in the
+    /// cached entries, without writing to the file.
+    ///
+    /// The changes are written by the next call to [`IndexFile::save`].
+    ///
+    /// # Errors
+    ///
+    /// This function will return [`VersionNotFoundError`] if the selected
+    /// version does not exist in the index.
+    pub fn set_yanked(
+        &mut self,
+        version: &Version,
+        yanked: bool,
+    ) -> Result<(), VersionNotFoundError> {
+        match self.entries.get_mut(version) {
+            Some(record) if yanked => record.yank(),
+            Some(record) => record.unyank(),
+            None => {
+                return Err(VersionNotFoundError {
+                    crate_name: self.crate_name.clone(),
+                    version: version.clone(),
+                })
             }
-            None => Ok(Err(VersionNotFoundError {
-                crate_name: self.crate_name.clone(),
-                version: version.clone(),
-            })),
+        }
+
+        Ok(())
+    }
+
+    /// Rewrite every [`Record`] in the file into the canonical layout used by
+    /// crates.io.
+    ///
+    /// Returns `true` if the contents of the file changed.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the file cannot be read or
+    /// written to.
+    pub async fn normalise(&mut self) -> Result<bool, IoError> {
+        let contents = fs::read_to_string(&self.path).await?;
+
+        for record in self.entries.values_mut() {
+            record.normalise();
+        }
+
+        if contents == self.contents() {
+            Ok(false)
+        } else {
+            self.save().await?;
+            Ok(true)
         }
     }
 
@@ -192,10 +319,39 @@ impl IndexFile {
         self.entries.range(min..max).next_back()
     }
 
-    async fn save(&mut self) -> Result<(), IoError> {
-        self.file.seek(SeekFrom::Start(0)).await?;
-        self.file.set_len(0).await?;
-        self.file.write_all(self.to_string().as_bytes()).await
+    /// Write the cached entries to the file, creating it if it doesn't exist.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the file cannot be written to.
+    pub async fn save(&mut self) -> Result<(), IoError> {
+        create_parents(&self.path).await?;
+        utils::write_atomic(&self.path, self.contents()).await
+    }
+
+    /// Prepare to write the cached entries to the file together with other
+    /// files, by creating its parent directories.
+    ///
+    /// Returns the path of the file, and the contents to write to it.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the directories cannot be
+    /// created.
+    pub async fn prepare_save(&self) -> Result<(PathBuf, Vec<u8>), IoError> {
+        create_parents(&self.path).await?;
+        Ok((self.path.clone(), self.contents().into_bytes()))
+    }
+
+    /// The contents of the file on disk, including any corrupt lines
+    fn contents(&self) -> String {
+        let mut lines: Vec<String> = self
+            .entries
+            .values()
+            .map(std::string::ToString::to_string)
+            .collect();
+        lines.extend(self.corrupt_lines.iter().cloned());
+        join_lines(&lines)
     }
 }
 
@@ -211,6 +367,12 @@ impl fmt::Display for IndexFile {
     }
 }
 
+/// Join the lines of an index file the way they are written to disk, with no
+/// newline after the last line
+pub(crate) fn join_lines<S: Borrow<str>>(lines: &[S]) -> String {
+    lines.join("\n")
+}
+
 /// Create all parent directories for the given filepath
 async fn create_parents(path: &Path) -> Result<(), IoError> {
     async_std::fs::DirBuilder::new()
@@ -219,16 +381,7 @@ async fn create_parents(path: &Path) -> Result<(), IoError> {
         .await
 }
 
-async fn open_file(path: &Path) -> Result<File, IoError> {
-    OpenOptions::new()
-        .write(true)
-        .read(true)
-        .create(true)
-        .open(path)
-        .await
-}
-
-fn get_path(name: impl AsRef<str>) -> PathBuf {
+pub(crate) fn get_path(name: impl AsRef<str>) -> PathBuf {
     let name = name.as_ref();
     let canonical_name = name.to_ascii_lowercase().replace('_', "-");
     let mut path = PathBuf::new();
@@ -300,8 +453,9 @@ impl VersionNotFoundError {
 }
 
 #[cfg(test)]
+#[allow(clippy::manual_assert)]
 mod tests {
-    use super::IndexFile;
+    use super::{Error, IndexFile};
     use crate::Record;
     use semver::Version;
     use test_case::test_case;
@@ -419,4 +573,112 @@ mod tests {
             index_file.unyank(&version).await.unwrap().unwrap();
         });
     }
+
+    #[async_std::test]
+    async fn lossless_rewrite() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let root = temp_dir.path();
+
+        let lines: Vec<&str> = include_str!("../../../tests/fixtures/corpus.jsonl")
+            .lines()
+            .filter(|line| line.starts_with(r#"{"name":"log","#))
+            .collect();
+        let original = lines.join("\n");
+
+        let path = root.join(super::get_path("log"));
+        async_std::fs::create_dir_all(path.parent().unwrap())
+            .await
+            .unwrap();
+        async_std::fs::write(&path, &original).await.unwrap();
+
+        let mut index_file = IndexFile::open(root, "log").await.unwrap();
+        let version = Version::new(0, 4, 34);
+
+        // only the yanked line changes
+        index_file.yank(&version).await.unwrap().unwrap();
+        let yanked = async_std::fs::read_to_string(&path).await.unwrap();
+        assert_eq!(yanked.lines().count(), lines.len());
+        for (line, original) in yanked.lines().zip(&lines) {
+            if line.contains(r#""vers":"0.4.34""#) {
+                assert_eq!(
+                    line,
+                    original.replace(r#""yanked":false"#, r#""yanked":true"#)
+                );
+            } else {
+                assert_eq!(&line, original);
+            }
+        }
+
+        index_file.unyank(&version).await.unwrap().unwrap();
+        let unyanked = async_std::fs::read_to_string(&path).await.unwrap();
+        assert_eq!(unyanked, original);
+    }
+
+    #[async_std::test]
+    async fn corrupt_line() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let root = temp_dir.path();
+
+        let path = root.join(super::get_path("some-name"));
+        async_std::fs::create_dir_all(path.parent().unwrap())
+            .await
+            .unwrap();
+        let lines = [
+            metadata("0.1.0").to_string(),
+            String::new(),
+            r#"{"name":"Some-Name","vers":"#.to_string(),
+            metadata("0.2.0").to_string(),
+        ];
+        async_std::fs::write(&path, lines.join("\n")).await.unwrap();
+
+        // a corrupt line is an error
+        match IndexFile::open(root, "some-name").await {
+            Err(Error::Corruption(e)) => {
+                assert_eq!(e.line(), 3);
+                assert_eq!(e.path().as_os_str(), path.as_os_str());
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        // unless the file is opened leniently
+        let (mut index_file, errors) = IndexFile::open_lenient(root, "some-name").await.unwrap();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].line(), 3);
+        assert_eq!(index_file.entries.len(), 2);
+
+        // the corrupt line is preserved when the file is written
+        index_file
+            .yank(&Version::new(0, 1, 0))
+            .await
+            .unwrap()
+            .unwrap();
+        let contents = async_std::fs::read_to_string(&path).await.unwrap();
+        assert!(contents.ends_with(&lines[2]));
+        assert!(contents.contains(r#""yanked":true"#));
+    }
+
+    #[async_std::test]
+    async fn failed_save() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let root = temp_dir.path();
+
+        let mut index_file = IndexFile::open(root, "Some-Name").await.unwrap();
+        index_file.insert(metadata("0.1.0")).await.unwrap().unwrap();
+
+        let path = root.join(super::get_path("Some-Name"));
+        let original = async_std::fs::read_to_string(&path).await.unwrap();
+
+        // a directory in the way of the temporary file makes the write fail
+        async_std::fs::create_dir(path.with_file_name(".Some-Name.tmp"))
+            .await
+            .unwrap();
+
+        assert!(index_file.insert(metadata("0.2.0")).await.is_err());
+        assert!(index_file.yank(&Version::new(0, 1, 0)).await.is_err());
+
+        assert_eq!(
+            async_std::fs::read_to_string(&path).await.unwrap(),
+            original
+        );
+    }
 }
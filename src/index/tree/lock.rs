@@ -0,0 +1,179 @@
+use super::Error;
+use async_std::{
+    path::{Path, PathBuf},
+    task,
+};
+use fs2::FileExt;
+use std::{
+    fs::{File, OpenOptions},
+    time::{Duration, Instant},
+};
+
+/// The name of the lock file at the root of an index.
+///
+/// This is a hidden file, so it is never mistaken for a crate.
+pub(crate) const LOCK_FILE: &str = ".index.lock";
+
+/// How long to wait between attempts to take a lock which is held by another
+/// process.
+const RETRY_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Options for locking the root of an index when it is opened.
+///
+/// The lock is advisory, so it only protects against other processes which
+/// also use this library. It is held until the [`Tree`](super::Tree) is
+/// dropped.
+///
+/// By default, an exclusive lock is taken, and opening fails immediately if
+/// another process holds a lock.
+///
+/// # Example
+///
+/// ```no_run
+/// use crate_index::tree::{LockOptions, Tree};
+/// use std::time::Duration;
+/// # use crate_index::Error;
+/// # async {
+///
+/// let options = LockOptions::shared().timeout(Duration::from_secs(5));
+/// let tree = Tree::open_with("/index", options).await?;
+/// # Ok::<(), Error>(())
+/// # };
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LockOptions {
+    shared: bool,
+    timeout: Option<Duration>,
+}
+
+impl LockOptions {
+    /// Take an exclusive lock, which allows the index to be modified.
+    ///
+    /// No other process can open the index while the lock is held.
+    #[must_use]
+    pub fn exclusive() -> Self {
+        Self {
+            shared: false,
+            timeout: Some(Duration::from_secs(0)),
+        }
+    }
+
+    /// Take a shared lock, which only allows the index to be read.
+    ///
+    /// Other processes can also open the index with a shared lock, but not
+    /// with an exclusive lock.
+    #[must_use]
+    pub fn shared() -> Self {
+        Self {
+            shared: true,
+            ..Self::exclusive()
+        }
+    }
+
+    /// Wait up to the given time for another process to release its lock.
+    #[must_use]
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Wait as long as it takes for another process to release its lock.
+    #[must_use]
+    pub fn wait(mut self) -> Self {
+        self.timeout = None;
+        self
+    }
+
+    /// Returns `true` if the lock only allows the index to be read
+    #[must_use]
+    pub fn is_shared(&self) -> bool {
+        self.shared
+    }
+}
+
+impl Default for LockOptions {
+    fn default() -> Self {
+        Self::exclusive()
+    }
+}
+
+/// An advisory lock on the root of an index, released when dropped.
+#[derive(Debug)]
+pub(crate) struct Lock {
+    /// The open lock file. The lock is released when this is closed.
+    _file: File,
+    shared: bool,
+}
+
+impl Lock {
+    /// Take a lock on the index at the given root.
+    ///
+    /// If another process holds a conflicting lock, this retries until the
+    /// timeout in the options has passed, and then fails with
+    /// [`Error::Locked`].
+    pub async fn acquire(root: &Path, options: LockOptions) -> Result<Self, Error> {
+        let path = root.join(LOCK_FILE);
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)?;
+
+        let started = Instant::now();
+
+        loop {
+            let result = if options.shared {
+                FileExt::try_lock_shared(&file)
+            } else {
+                FileExt::try_lock_exclusive(&file)
+            };
+
+            match result {
+                Ok(()) => {
+                    return Ok(Self {
+                        _file: file,
+                        shared: options.shared,
+                    })
+                }
+                Err(e) if e.kind() != fs2::lock_contended_error().kind() => return Err(e.into()),
+                Err(_) => (),
+            }
+
+            match options.timeout {
+                Some(timeout) if started.elapsed() >= timeout => {
+                    return Err(LockedError { root: root.into() }.into())
+                }
+                _ => task::sleep(RETRY_INTERVAL).await,
+            }
+        }
+    }
+
+    /// Returns [`Error::ReadOnly`] if the lock doesn't allow the index to be
+    /// modified
+    pub fn check_writable(&self) -> Result<(), Error> {
+        if self.shared {
+            Err(Error::ReadOnly)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// The error raised when an index is locked by another process.
+///
+/// Operations on a [`Tree`](super::Tree) return this as
+/// [`Error::Locked`].
+#[derive(Debug, thiserror::Error)]
+#[error("the index at {root:?} is locked by another process")]
+pub struct LockedError {
+    root: PathBuf,
+}
+
+impl LockedError {
+    /// The root of the locked index
+    #[must_use]
+    pub fn root(&self) -> &PathBuf {
+        &self.root
+    }
+}
@@ -0,0 +1,251 @@
+use super::{canonicalise, Error, IndexFile, NotFoundError, Tree};
+use crate::{
+    index::Record,
+    utils,
+    validate::{self, Error as ValidationError},
+    WrappedResult,
+};
+use semver::Version;
+use std::collections::{BTreeMap, BTreeSet};
+
+/// A change to an index [`Tree`], applied as part of a batch by
+/// [`Tree::apply`].
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Clone, PartialEq)]
+pub enum Operation {
+    /// Insert a crate [`Record`]
+    Insert(Record),
+
+    /// Mark a version of a crate as 'yanked'
+    Yank {
+        /// the name of the crate
+        crate_name: String,
+        /// the version to yank
+        version: Version,
+    },
+
+    /// Mark a version of a crate as 'unyanked'
+    Unyank {
+        /// the name of the crate
+        crate_name: String,
+        /// the version to unyank
+        version: Version,
+    },
+}
+
+/// The error raised when an [`Operation`] in a batch cannot be applied.
+///
+/// If any operation fails, none of the operations in the batch are applied.
+#[derive(Debug, thiserror::Error)]
+pub enum TransactionError {
+    /// An inserted [`Record`] is not valid
+    #[error(transparent)]
+    Validation(#[from] ValidationError),
+
+    /// A yanked or unyanked crate version does not exist
+    #[error(transparent)]
+    NotFound(#[from] NotFoundError),
+}
+
+/// The in-progress state of a set of changes to a [`Tree`].
+///
+/// Index files are read the first time they are used, and changes are made
+/// in memory, so that each operation is validated against the changes before
+/// it. Nothing is written until [`Batch::save`] is called.
+pub(crate) struct Batch<'a> {
+    tree: &'a Tree,
+    files: BTreeMap<String, IndexFile>,
+    touched: BTreeSet<String>,
+}
+
+impl<'a> Batch<'a> {
+    pub fn new(tree: &'a Tree) -> Self {
+        Self {
+            tree,
+            files: BTreeMap::new(),
+            touched: BTreeSet::new(),
+        }
+    }
+
+    pub async fn apply(
+        &mut self,
+        operation: Operation,
+    ) -> WrappedResult<(), TransactionError, Error> {
+        Ok(match operation {
+            Operation::Insert(record) => self.insert(record).await?.map_err(TransactionError::from),
+            Operation::Yank {
+                crate_name,
+                version,
+            } => self
+                .set_yanked(crate_name, &version, true)
+                .await?
+                .map_err(TransactionError::from),
+            Operation::Unyank {
+                crate_name,
+                version,
+            } => self
+                .set_yanked(crate_name, &version, false)
+                .await?
+                .map_err(TransactionError::from),
+        })
+    }
+
+    pub async fn insert(&mut self, record: Record) -> WrappedResult<(), ValidationError, Error> {
+        if let Err(e) = validate::name(record.name()) {
+            return Ok(Err(e));
+        }
+
+        if let Err(e) = self.validate_name(record.name()) {
+            return Ok(Err(e));
+        }
+
+        if self.tree.validate_dependencies {
+            if let Err(e) = self.check_dependencies(&record).await? {
+                return Ok(Err(e));
+            }
+        }
+
+        let crate_name = record.name().clone();
+
+        if let Err(e) = self.load(&crate_name).await?.add(record) {
+            return Ok(Err(e));
+        }
+
+        self.touched.insert(crate_name);
+
+        Ok(Ok(()))
+    }
+
+    pub async fn set_yanked(
+        &mut self,
+        crate_name: String,
+        version: &Version,
+        yanked: bool,
+    ) -> WrappedResult<(), NotFoundError, Error> {
+        let index_file = match self.file(&crate_name).await? {
+            Some(index_file) => index_file,
+            None => return Ok(Err(NotFoundError::no_crate(crate_name))),
+        };
+
+        if let Err(e) = index_file.set_yanked(version, yanked) {
+            return Ok(Err(e.into()));
+        }
+
+        self.touched.insert(crate_name);
+
+        Ok(Ok(()))
+    }
+
+    /// Write each changed index file once.
+    ///
+    /// Either every file is written, or none of them are (see
+    /// [`utils::write_all_atomic`]).
+    ///
+    /// Returns the names of the crates whose files were written, in
+    /// alphabetical order.
+    pub async fn save(self) -> Result<Vec<String>, Error> {
+        let mut files = Vec::new();
+        for crate_name in &self.touched {
+            files.push(self.files[crate_name].prepare_save().await?);
+        }
+
+        utils::write_all_atomic(files).await?;
+
+        Ok(self.touched.into_iter().collect())
+    }
+
+    /// Returns `true` if the crate exists in the tree, or has been inserted by
+    /// this batch
+    fn contains_crate(&self, crate_name: &str) -> bool {
+        self.tree.contains_crate(crate_name) || self.touched.contains(crate_name)
+    }
+
+    /// Find the name under which a crate is stored in the tree, or was inserted
+    /// by this batch, ignoring case and treating `-` and `_` as the same, as
+    /// cargo does when it looks up a dependency
+    fn resolve_name(&self, crate_name: &str) -> Option<String> {
+        if self.contains_crate(crate_name) {
+            return Some(crate_name.to_string());
+        }
+
+        let canonical_name = canonicalise(crate_name);
+        self.tree
+            .crates()
+            .chain(&self.touched)
+            .find(|existing| canonicalise(existing) == canonical_name)
+            .cloned()
+    }
+
+    /// Get the in-progress index file for a crate, if the crate exists
+    async fn file(&mut self, crate_name: &str) -> Result<Option<&mut IndexFile>, Error> {
+        if self.contains_crate(crate_name) {
+            Ok(Some(self.load(crate_name).await?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Get the in-progress index file for a crate, reading it from the tree
+    /// the first time it is used
+    async fn load(&mut self, crate_name: &str) -> Result<&mut IndexFile, Error> {
+        if !self.files.contains_key(crate_name) {
+            let index_file = self.tree.file(crate_name).await?;
+            self.files.insert(crate_name.to_string(), index_file);
+        }
+
+        Ok(self.files.get_mut(crate_name).unwrap())
+    }
+
+    async fn check_dependencies(
+        &mut self,
+        record: &Record,
+    ) -> WrappedResult<(), ValidationError, Error> {
+        for dependency in record.dependencies() {
+            if let Some(registry) = &dependency.registry {
+                if !self.tree.allowed_registries().contains(registry) {
+                    return Ok(Err(ValidationError::registry(
+                        &dependency.name,
+                        registry.clone(),
+                    )));
+                }
+                continue;
+            }
+
+            let crate_name = dependency.package.as_ref().unwrap_or(&dependency.name);
+            let satisfied = match self.resolve_name(crate_name) {
+                Some(existing) => (&*self.load(&existing).await?)
+                    .into_iter()
+                    .any(|record| !record.yanked() && dependency.req.matches(record.version())),
+                None => false,
+            };
+
+            if !satisfied {
+                return Ok(Err(ValidationError::unresolved_dependency(
+                    crate_name,
+                    dependency.req.clone(),
+                )));
+            }
+        }
+
+        Ok(Ok(()))
+    }
+
+    fn validate_name(&self, name: &str) -> Result<(), ValidationError> {
+        let canonical_name = canonicalise(name);
+        let similar = !self.contains_crate(name)
+            && self
+                .tree
+                .crates()
+                .chain(&self.touched)
+                .any(|crate_name| canonicalise(crate_name) == canonical_name);
+
+        if similar {
+            Err(ValidationError::invalid_name(
+                name,
+                "name is too similar to existing crate",
+            ))
+        } else {
+            Ok(())
+        }
+    }
+}
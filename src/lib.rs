@@ -38,10 +38,16 @@
 
 pub mod record;
 #[doc(inline)]
-pub use record::Record;
+pub use record::{Dependency, Record};
+
+pub mod download;
 
 mod index;
-pub use index::{git, tree, Builder, Error, Index};
+pub use index::{git, sparse, tree, Builder, Error, Index, OpenOptions, Transaction};
+
+pub mod package;
+pub mod publish;
+pub mod storage;
 
 mod utils;
 pub mod validate;
@@ -52,6 +58,9 @@ pub use url::Url;
 #[cfg(feature = "blocking")]
 pub mod blocking;
 
+#[cfg(feature = "server")]
+pub mod server;
+
 /// A 'double-wrapped' result type
 ///
 /// This pattern is inspired by [this blog post](http://sled.rs/errors).
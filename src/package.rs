@@ -0,0 +1,550 @@
+//! Read crate metadata directly from a packaged `.crate` file.
+//!
+//! A `.crate` file is a gzipped tarball, created by `cargo package`, containing
+//! the crate's sources in a single top-level directory named
+//! `{name}-{version}`. Cargo normalises the packaged `Cargo.toml`, so it
+//! contains everything needed to build the [`Record`] for the index.
+//!
+//! # Example
+//!
+//! ```no_run
+//! use crate_index::{package, Url};
+//! # let crate_file: Vec<u8> = Vec::new();
+//!
+//! let registry = Url::parse("https://my-intranet:8080/git/index").unwrap();
+//! let record = package::read(&crate_file, &registry).expect("invalid crate file");
+//!
+//! assert_eq!(record.check_sum(), &package::check_sum(&crate_file));
+//! ```
+
+use crate::{
+    record::{Dependency, DependencyKind},
+    validate::{self, Error as ValidationError},
+    Record,
+};
+use flate2::read::GzDecoder;
+use semver::{Version, VersionReq};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    io::{Error as IoError, Read},
+    path::Component,
+};
+use tar::Archive;
+use url::Url;
+
+/// The URL of the crates.io index, as recorded in the `registry` of
+/// dependencies on crates.io from other registries
+const CRATES_IO_INDEX: &str = "https://github.com/rust-lang/crates.io-index";
+
+/// The error returned when a `.crate` file cannot be read
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// The file is not a valid gzipped tarball
+    #[error("unable to read crate file: {0}")]
+    Io(#[from] IoError),
+
+    /// There is no `Cargo.toml` in the package's top-level directory
+    #[error("crate file does not contain a Cargo.toml")]
+    MissingManifest,
+
+    /// The packaged `Cargo.toml` could not be parsed
+    #[error("invalid Cargo.toml: {0}")]
+    Manifest(#[from] toml::de::Error),
+
+    /// The tarball contains a top-level directory which doesn't match the
+    /// package name and version
+    #[error("unexpected directory in crate file (expected: {expected}, found: {found})")]
+    Directory {
+        /// The directory name derived from the package name and version
+        expected: String,
+
+        /// The directory name found in the crate file
+        found: String,
+    },
+
+    /// The package metadata is invalid
+    #[error(transparent)]
+    Validation(#[from] ValidationError),
+}
+
+/// Compute the checksum of a `.crate` file.
+///
+/// This is the hex-encoded SHA256 hash of the file, as stored in the `cksum`
+/// field of a [`Record`].
+#[must_use]
+pub fn check_sum(crate_file: &[u8]) -> String {
+    hex::encode(Sha256::digest(crate_file))
+}
+
+/// Build a [`Record`] from the contents of a `.crate` file.
+///
+/// The checksum is computed from the file, and the name, version,
+/// dependencies, features, `links` value and `rust-version` are taken from the
+/// packaged `Cargo.toml`.
+///
+/// `registry` is the index URL of the registry the crate is published to.
+/// Cargo only adds a `registry-index` to dependencies which were given an
+/// explicit `registry` in the original `Cargo.toml`. Dependencies without one
+/// are from crates.io, so their `registry` is the crates.io index, unless the
+/// crate is published to crates.io itself. Dependencies whose
+/// `registry-index` is `registry` have a `registry` of `None`, since they are
+/// from the same registry as the crate.
+///
+/// # Errors
+///
+/// An [`Error`] is returned if the file is not a gzipped tarball, the
+/// `Cargo.toml` is missing or malformed, the crate name is invalid, or the
+/// tarball contains anything other than the `{name}-{version}` directory.
+pub fn read(crate_file: &[u8], registry: &Url) -> Result<Record, Error> {
+    let mut archive = Archive::new(GzDecoder::new(crate_file));
+
+    let mut directories = BTreeSet::new();
+    let mut manifest = None;
+
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.into_owned();
+        let components: Vec<_> = path.components().collect();
+
+        if let Some(Component::Normal(directory)) = components.first() {
+            directories.insert(directory.to_string_lossy().into_owned());
+        } else {
+            directories.insert(path.to_string_lossy().into_owned());
+        }
+
+        if let [Component::Normal(_), Component::Normal(file_name)] = components.as_slice() {
+            if *file_name == "Cargo.toml" {
+                let mut contents = String::new();
+                entry.read_to_string(&mut contents)?;
+                manifest = Some(contents);
+            }
+        }
+    }
+
+    let manifest: Manifest = toml::from_str(&manifest.ok_or(Error::MissingManifest)?)?;
+
+    let expected = format!("{}-{}", manifest.package.name, manifest.package.version);
+    if let Some(found) = directories.into_iter().find(|found| found != &expected) {
+        return Err(Error::Directory { expected, found });
+    }
+
+    manifest.into_record(check_sum(crate_file), registry)
+}
+
+/// The subset of a normalised `Cargo.toml` which is stored in the index
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct Manifest {
+    package: Package,
+
+    #[serde(default)]
+    dependencies: BTreeMap<String, ManifestDependency>,
+
+    #[serde(default, alias = "dev_dependencies")]
+    dev_dependencies: BTreeMap<String, ManifestDependency>,
+
+    #[serde(default, alias = "build_dependencies")]
+    build_dependencies: BTreeMap<String, ManifestDependency>,
+
+    #[serde(default)]
+    target: BTreeMap<String, Platform>,
+
+    #[serde(default)]
+    features: BTreeMap<String, Vec<String>>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct Package {
+    name: String,
+    version: Version,
+    links: Option<String>,
+    rust_version: Option<String>,
+}
+
+/// The dependencies of a package which are specific to a target platform
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct Platform {
+    #[serde(default)]
+    dependencies: BTreeMap<String, ManifestDependency>,
+
+    #[serde(default, alias = "dev_dependencies")]
+    dev_dependencies: BTreeMap<String, ManifestDependency>,
+
+    #[serde(default, alias = "build_dependencies")]
+    build_dependencies: BTreeMap<String, ManifestDependency>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum ManifestDependency {
+    Simple(VersionReq),
+    Detailed(DetailedDependency),
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct DetailedDependency {
+    #[serde(default = "any_version")]
+    version: VersionReq,
+
+    #[serde(default)]
+    features: Vec<String>,
+
+    #[serde(default)]
+    optional: bool,
+
+    #[serde(default = "enabled", alias = "default_features")]
+    default_features: bool,
+
+    package: Option<String>,
+
+    registry_index: Option<Url>,
+
+    public: Option<bool>,
+
+    artifact: Option<Artifact>,
+
+    target: Option<String>,
+
+    #[serde(default)]
+    lib: bool,
+}
+
+/// The kinds of artifact of an artifact dependency, which can be given as a
+/// single string or an array
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Artifact {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl From<Artifact> for Vec<String> {
+    fn from(artifact: Artifact) -> Self {
+        match artifact {
+            Artifact::One(kind) => vec![kind],
+            Artifact::Many(kinds) => kinds,
+        }
+    }
+}
+
+fn any_version() -> VersionReq {
+    VersionReq::STAR
+}
+
+fn enabled() -> bool {
+    true
+}
+
+impl Manifest {
+    fn into_record(self, check_sum: String, registry: &Url) -> Result<Record, Error> {
+        validate::name(&self.package.name)?;
+
+        if let Some(rust_version) = &self.package.rust_version {
+            validate::rust_version(rust_version)?;
+        }
+
+        let mut record = Record::new(self.package.name, self.package.version, check_sum);
+
+        let mut deps = Vec::new();
+        push_dependencies(
+            &mut deps,
+            self.dependencies,
+            &DependencyKind::Normal,
+            None,
+            registry,
+        )?;
+        push_dependencies(
+            &mut deps,
+            self.dev_dependencies,
+            &DependencyKind::Dev,
+            None,
+            registry,
+        )?;
+        push_dependencies(
+            &mut deps,
+            self.build_dependencies,
+            &DependencyKind::Build,
+            None,
+            registry,
+        )?;
+
+        for (target, platform) in self.target {
+            push_dependencies(
+                &mut deps,
+                platform.dependencies,
+                &DependencyKind::Normal,
+                Some(&target),
+                registry,
+            )?;
+            push_dependencies(
+                &mut deps,
+                platform.dev_dependencies,
+                &DependencyKind::Dev,
+                Some(&target),
+                registry,
+            )?;
+            push_dependencies(
+                &mut deps,
+                platform.build_dependencies,
+                &DependencyKind::Build,
+                Some(&target),
+                registry,
+            )?;
+        }
+
+        record.deps = deps;
+        record.features = self.features.into_iter().collect();
+        record.links = self.package.links;
+        record.rust_version = self.package.rust_version;
+
+        Ok(record)
+    }
+}
+
+fn push_dependencies(
+    deps: &mut Vec<Dependency>,
+    dependencies: BTreeMap<String, ManifestDependency>,
+    kind: &DependencyKind,
+    target: Option<&str>,
+    registry: &Url,
+) -> Result<(), Error> {
+    for (name, dependency) in dependencies {
+        let dependency = match dependency {
+            ManifestDependency::Simple(version) => DetailedDependency {
+                version,
+                features: Vec::new(),
+                optional: false,
+                default_features: true,
+                package: None,
+                registry_index: None,
+                public: None,
+                artifact: None,
+                target: None,
+                lib: false,
+            },
+            ManifestDependency::Detailed(dependency) => dependency,
+        };
+
+        if let Some(package) = &dependency.package {
+            validate::name(package)?;
+        }
+
+        let dependency = Dependency {
+            name,
+            req: dependency.version,
+            features: dependency.features,
+            optional: dependency.optional,
+            default_features: dependency.default_features,
+            target: target.map(ToString::to_string),
+            kind: kind.clone(),
+            registry: dependency_registry(dependency.registry_index, registry),
+            package: dependency.package,
+            public: dependency.public,
+            artifact: dependency.artifact.map(Into::into),
+            bindep_target: dependency.target,
+            lib: dependency.lib,
+        };
+
+        validate::artifact(&dependency)?;
+        deps.push(dependency);
+    }
+
+    Ok(())
+}
+
+/// The `registry` of a dependency, given its `registry-index` and the index
+/// URL of the registry the crate is published to
+fn dependency_registry(registry_index: Option<Url>, registry: &Url) -> Option<Url> {
+    let crates_io = Url::parse(CRATES_IO_INDEX).unwrap();
+
+    match registry_index.unwrap_or(crates_io) {
+        index if &index == registry => None,
+        index => Some(index),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_sum, read, Error, CRATES_IO_INDEX};
+    use crate::record::DependencyKind;
+    use flate2::{write::GzEncoder, Compression};
+    use semver::{Version, VersionReq};
+    use url::Url;
+
+    const MANIFEST: &str = r#"
+[package]
+edition = "2018"
+name = "foo"
+version = "0.1.0"
+links = "libfoo"
+rust-version = "1.56"
+
+[dependencies.rand]
+version = "0.8"
+features = ["small_rng"]
+optional = true
+default-features = false
+
+[dependencies.my-serde]
+version = "1.0"
+package = "serde"
+
+[dependencies.private]
+version = "^2.1"
+registry-index = "https://my-intranet:8080/git/index"
+
+[dev-dependencies.tempfile]
+version = "3"
+
+[build-dependencies]
+cc = "1.0"
+
+[build-dependencies.tool]
+version = "0.3"
+artifact = ["bin", "cdylib"]
+target = "wasm32-unknown-unknown"
+lib = true
+
+[target."cfg(unix)".dependencies.libc]
+version = "0.2"
+
+[features]
+default = ["std"]
+std = []
+"#;
+
+    const PRIVATE_INDEX: &str = "https://my-intranet:8080/git/index";
+
+    fn read_for(crate_file: &[u8], registry: &str) -> Result<crate::Record, Error> {
+        read(crate_file, &Url::parse(registry).unwrap())
+    }
+
+    fn crate_file(files: &[(&str, &str)]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(GzEncoder::new(Vec::new(), Compression::default()));
+
+        for (path, contents) in files {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(contents.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder
+                .append_data(&mut header, path, contents.as_bytes())
+                .unwrap();
+        }
+
+        builder.into_inner().unwrap().finish().unwrap()
+    }
+
+    #[test]
+    fn read_record() {
+        let crate_file = crate_file(&[
+            ("foo-0.1.0/Cargo.toml", MANIFEST),
+            ("foo-0.1.0/Cargo.toml.orig", "[package]"),
+            ("foo-0.1.0/src/lib.rs", ""),
+        ]);
+
+        let record = read_for(&crate_file, PRIVATE_INDEX).unwrap();
+
+        assert_eq!(record.name(), "foo");
+        assert_eq!(record.version(), &Version::new(0, 1, 0));
+        assert_eq!(record.check_sum(), &check_sum(&crate_file));
+        assert_eq!(record.links(), Some(&"libfoo".to_string()));
+        assert_eq!(record.rust_version(), Some(&"1.56".to_string()));
+        assert_eq!(record.features()["default"], vec!["std".to_string()]);
+        assert!(record.features()["std"].is_empty());
+
+        let deps = record.dependencies();
+        assert_eq!(deps.len(), 7);
+
+        let my_serde = deps.iter().find(|dep| dep.name == "my-serde").unwrap();
+        assert_eq!(my_serde.package, Some("serde".to_string()));
+        assert_eq!(my_serde.req, VersionReq::parse("1.0").unwrap());
+
+        let rand = deps.iter().find(|dep| dep.name == "rand").unwrap();
+        assert!(rand.optional);
+        assert!(!rand.default_features);
+        assert_eq!(rand.features, vec!["small_rng".to_string()]);
+        // dependencies without a registry-index are from crates.io
+        assert_eq!(rand.registry, Some(Url::parse(CRATES_IO_INDEX).unwrap()));
+
+        // and dependencies from the same registry have no registry
+        let private = deps.iter().find(|dep| dep.name == "private").unwrap();
+        assert_eq!(private.registry, None);
+
+        let tempfile = deps.iter().find(|dep| dep.name == "tempfile").unwrap();
+        assert_eq!(tempfile.kind, DependencyKind::Dev);
+
+        let cc = deps.iter().find(|dep| dep.name == "cc").unwrap();
+        assert_eq!(cc.kind, DependencyKind::Build);
+        assert!(cc.default_features);
+
+        let tool = deps.iter().find(|dep| dep.name == "tool").unwrap();
+        assert_eq!(
+            tool.artifact,
+            Some(vec!["bin".to_string(), "cdylib".to_string()])
+        );
+        assert_eq!(
+            tool.bindep_target,
+            Some("wasm32-unknown-unknown".to_string())
+        );
+        assert!(tool.lib);
+        assert_eq!(tool.target, None);
+
+        let libc = deps.iter().find(|dep| dep.name == "libc").unwrap();
+        assert_eq!(libc.kind, DependencyKind::Normal);
+        assert_eq!(libc.target, Some("cfg(unix)".to_string()));
+    }
+
+    #[test]
+    fn read_record_for_crates_io() {
+        let crate_file = crate_file(&[("foo-0.1.0/Cargo.toml", MANIFEST)]);
+
+        let record = read_for(&crate_file, CRATES_IO_INDEX).unwrap();
+        let deps = record.dependencies();
+
+        let rand = deps.iter().find(|dep| dep.name == "rand").unwrap();
+        assert_eq!(rand.registry, None);
+
+        let private = deps.iter().find(|dep| dep.name == "private").unwrap();
+        assert_eq!(private.registry, Some(Url::parse(PRIVATE_INDEX).unwrap()));
+    }
+
+    #[test]
+    fn wrong_directory() {
+        let crate_file = crate_file(&[
+            ("foo-0.1.0/Cargo.toml", MANIFEST),
+            ("foo-0.2.0/src/lib.rs", ""),
+        ]);
+
+        match read_for(&crate_file, PRIVATE_INDEX) {
+            Err(Error::Directory { expected, found }) => {
+                assert_eq!(expected, "foo-0.1.0");
+                assert_eq!(found, "foo-0.2.0");
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn missing_manifest() {
+        let crate_file = crate_file(&[("foo-0.1.0/src/lib.rs", "")]);
+
+        assert!(matches!(
+            read_for(&crate_file, PRIVATE_INDEX),
+            Err(Error::MissingManifest)
+        ));
+    }
+
+    #[test]
+    fn not_a_tarball() {
+        assert!(matches!(
+            read_for(b"not a crate file", PRIVATE_INDEX),
+            Err(Error::Io(_))
+        ));
+    }
+}
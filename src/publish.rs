@@ -0,0 +1,551 @@
+//! Crate metadata, as sent by Cargo when publishing a crate.
+//!
+//! The JSON metadata which Cargo sends to the
+//! [publish endpoint](https://doc.rust-lang.org/cargo/reference/registry-web-api.html#publish)
+//! differs from the format of a [`Record`] in the index. This module handles
+//! the translation between the two.
+//!
+//! # Example
+//!
+//! ```no_run
+//! use crate_index::publish;
+//! # use crate_index::Url;
+//! # let body: Vec<u8> = Vec::new();
+//! # let check_sum = "d867001db0e2b6e0496f9fac96930e2d42233ecd3ca0413e0753d4c7695d289c";
+//! # let allowed_registries: Vec<Url> = Vec::new();
+//!
+//! // split the body of the publish request into metadata and '.crate' file
+//! let (request, crate_file) = publish::parse_body(&body).expect("malformed request");
+//!
+//! // convert the metadata into a record for the index
+//! let record = request
+//!     .into_record(check_sum, &allowed_registries)
+//!     .expect("invalid metadata");
+//! ```
+
+use crate::{
+    record::{Dependency, DependencyKind},
+    validate::{self, Error as ValidationError},
+    Record,
+};
+use semver::{Version, VersionReq};
+use serde::Deserialize;
+use std::{collections::BTreeMap, convert::TryInto};
+use url::Url;
+
+/// The crate metadata sent by Cargo when publishing
+///
+/// *[See the documentation for details](https://doc.rust-lang.org/cargo/reference/registry-web-api.html#publish)*
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct PublishRequest {
+    name: String,
+
+    vers: Version,
+
+    #[serde(default)]
+    deps: Vec<PublishDependency>,
+
+    #[serde(default)]
+    features: BTreeMap<String, Vec<String>>,
+
+    #[serde(default)]
+    authors: Vec<String>,
+
+    #[serde(default)]
+    description: Option<String>,
+
+    #[serde(default)]
+    documentation: Option<String>,
+
+    #[serde(default)]
+    homepage: Option<String>,
+
+    #[serde(default)]
+    readme: Option<String>,
+
+    #[serde(default)]
+    readme_file: Option<String>,
+
+    #[serde(default)]
+    keywords: Vec<String>,
+
+    #[serde(default)]
+    categories: Vec<String>,
+
+    #[serde(default)]
+    license: Option<String>,
+
+    #[serde(default)]
+    license_file: Option<String>,
+
+    #[serde(default)]
+    repository: Option<String>,
+
+    #[serde(default)]
+    links: Option<String>,
+
+    #[serde(default)]
+    rust_version: Option<String>,
+}
+
+impl PublishRequest {
+    /// The name of the crate
+    #[must_use]
+    pub fn name(&self) -> &String {
+        &self.name
+    }
+
+    /// The version of the crate
+    #[must_use]
+    pub fn version(&self) -> &Version {
+        &self.vers
+    }
+
+    /// The dependencies of the crate
+    #[must_use]
+    pub fn dependencies(&self) -> &Vec<PublishDependency> {
+        &self.deps
+    }
+
+    /// Set of features defined for the package.
+    #[must_use]
+    pub fn features(&self) -> &BTreeMap<String, Vec<String>> {
+        &self.features
+    }
+
+    /// The authors of the crate
+    #[must_use]
+    pub fn authors(&self) -> &Vec<String> {
+        &self.authors
+    }
+
+    /// The description from the package's manifest
+    #[must_use]
+    pub fn description(&self) -> Option<&String> {
+        self.description.as_ref()
+    }
+
+    /// The documentation URL from the package's manifest
+    #[must_use]
+    pub fn documentation(&self) -> Option<&String> {
+        self.documentation.as_ref()
+    }
+
+    /// The homepage URL from the package's manifest
+    #[must_use]
+    pub fn homepage(&self) -> Option<&String> {
+        self.homepage.as_ref()
+    }
+
+    /// The contents of the package's README file
+    #[must_use]
+    pub fn readme(&self) -> Option<&String> {
+        self.readme.as_ref()
+    }
+
+    /// The path to the README file, relative to the package root
+    #[must_use]
+    pub fn readme_file(&self) -> Option<&String> {
+        self.readme_file.as_ref()
+    }
+
+    /// The keywords from the package's manifest
+    #[must_use]
+    pub fn keywords(&self) -> &Vec<String> {
+        &self.keywords
+    }
+
+    /// The categories from the package's manifest
+    #[must_use]
+    pub fn categories(&self) -> &Vec<String> {
+        &self.categories
+    }
+
+    /// The license expression from the package's manifest
+    #[must_use]
+    pub fn license(&self) -> Option<&String> {
+        self.license.as_ref()
+    }
+
+    /// The path to the license file, relative to the package root
+    #[must_use]
+    pub fn license_file(&self) -> Option<&String> {
+        self.license_file.as_ref()
+    }
+
+    /// The repository URL from the package's manifest
+    #[must_use]
+    pub fn repository(&self) -> Option<&String> {
+        self.repository.as_ref()
+    }
+
+    /// The `links` string value from the package's manifest
+    #[must_use]
+    pub fn links(&self) -> Option<&String> {
+        self.links.as_ref()
+    }
+
+    /// The minimum supported Rust version from the package's manifest
+    #[must_use]
+    pub fn rust_version(&self) -> Option<&String> {
+        self.rust_version.as_ref()
+    }
+
+    /// Convert the metadata into a [`Record`] for inserting into the index.
+    ///
+    /// The checksum is the SHA256 hash of the `.crate` file. Dependencies from
+    /// other registries are checked against the given list of allowed
+    /// registries.
+    ///
+    /// # Errors
+    ///
+    /// A [`ValidationError`] is returned if the crate or a renamed dependency
+    /// has an invalid name, if a dependency is from a registry which is not
+    /// allowed, or if a dependency has invalid artifact fields.
+    pub fn into_record(
+        self,
+        check_sum: impl Into<String>,
+        allowed_registries: &[Url],
+    ) -> Result<Record, ValidationError> {
+        validate::name(&self.name)?;
+
+        if let Some(rust_version) = &self.rust_version {
+            validate::rust_version(rust_version)?;
+        }
+
+        let mut record = Record::new(self.name, self.vers, check_sum);
+
+        record.deps = self
+            .deps
+            .into_iter()
+            .map(|dependency| dependency.into_dependency(allowed_registries))
+            .collect::<Result<_, _>>()?;
+        record.features = self.features.into_iter().collect();
+        record.links = self.links;
+        record.rust_version = self.rust_version;
+
+        Ok(record)
+    }
+}
+
+/// A dependency on another crate, as sent by Cargo when publishing
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct PublishDependency {
+    name: String,
+
+    version_req: VersionReq,
+
+    #[serde(default)]
+    features: Vec<String>,
+
+    optional: bool,
+
+    default_features: bool,
+
+    #[serde(default)]
+    target: Option<String>,
+
+    kind: DependencyKind,
+
+    #[serde(default)]
+    registry: Option<Url>,
+
+    #[serde(default)]
+    explicit_name_in_toml: Option<String>,
+
+    #[serde(default)]
+    public: Option<bool>,
+
+    #[serde(default)]
+    artifact: Option<Vec<String>>,
+
+    #[serde(default)]
+    bindep_target: Option<String>,
+
+    #[serde(default)]
+    lib: bool,
+}
+
+impl PublishDependency {
+    /// The name of the package which is depended on.
+    ///
+    /// If the dependency is renamed, this is the original package name.
+    #[must_use]
+    pub fn name(&self) -> &String {
+        &self.name
+    }
+
+    /// The semver requirement for this dependency
+    #[must_use]
+    pub fn version_req(&self) -> &VersionReq {
+        &self.version_req
+    }
+
+    /// The registry this dependency is from, or `None` if it is from the same
+    /// registry as the crate being published
+    #[must_use]
+    pub fn registry(&self) -> Option<&Url> {
+        self.registry.as_ref()
+    }
+
+    /// The name used for the dependency in the package's manifest, if it has
+    /// been renamed
+    #[must_use]
+    pub fn explicit_name_in_toml(&self) -> Option<&String> {
+        self.explicit_name_in_toml.as_ref()
+    }
+
+    /// Convert the dependency into the format used in the index.
+    ///
+    /// # Errors
+    ///
+    /// A [`ValidationError`] is returned if the dependency is renamed to an
+    /// invalid name, is from a registry which is not allowed, or has invalid
+    /// artifact fields.
+    pub fn into_dependency(
+        self,
+        allowed_registries: &[Url],
+    ) -> Result<Dependency, ValidationError> {
+        if let Some(registry) = &self.registry {
+            if !allowed_registries.contains(registry) {
+                return Err(ValidationError::registry(&self.name, registry.clone()));
+            }
+        }
+
+        // if the dependency is renamed, the index records the new name, and the
+        // original package name
+        let (name, package) = match self.explicit_name_in_toml {
+            Some(explicit_name) if explicit_name != self.name => {
+                validate::name(&explicit_name)?;
+                (explicit_name, Some(self.name))
+            }
+            _ => (self.name, None),
+        };
+
+        let dependency = Dependency {
+            name,
+            req: self.version_req,
+            features: self.features,
+            optional: self.optional,
+            default_features: self.default_features,
+            target: self.target,
+            kind: self.kind,
+            registry: self.registry,
+            package,
+            public: self.public,
+            artifact: self.artifact,
+            bindep_target: self.bindep_target,
+            lib: self.lib,
+        };
+
+        validate::artifact(&dependency)?;
+
+        Ok(dependency)
+    }
+}
+
+/// The error returned when the body of a publish request is malformed
+#[derive(Debug, thiserror::Error)]
+pub enum BodyError {
+    /// The body is shorter than its length prefixes claim
+    #[error("publish request body is truncated")]
+    Truncated,
+
+    /// The JSON metadata could not be parsed
+    #[error("invalid publish metadata: {0}")]
+    Json(#[from] serde_json::Error),
+}
+
+/// Split the body of a publish request into the crate metadata, and the
+/// `.crate` file.
+///
+/// The body consists of-
+/// - a 32-bit little-endian length, followed by that many bytes of JSON metadata
+/// - a 32-bit little-endian length, followed by that many bytes of `.crate` file
+///
+/// # Errors
+///
+/// A [`BodyError`] is returned if the body is truncated, or the metadata is not
+/// valid.
+pub fn parse_body(body: &[u8]) -> Result<(PublishRequest, &[u8]), BodyError> {
+    let (json, rest) = split_length_prefixed(body)?;
+    let (crate_file, _) = split_length_prefixed(rest)?;
+
+    let request = serde_json::from_slice(json)?;
+
+    Ok((request, crate_file))
+}
+
+fn split_length_prefixed(bytes: &[u8]) -> Result<(&[u8], &[u8]), BodyError> {
+    if bytes.len() < 4 {
+        return Err(BodyError::Truncated);
+    }
+
+    let (length, rest) = bytes.split_at(4);
+    let length = u32::from_le_bytes(length.try_into().unwrap()) as usize;
+
+    if rest.len() < length {
+        return Err(BodyError::Truncated);
+    }
+
+    Ok(rest.split_at(length))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_body, BodyError, PublishRequest};
+    use crate::{validate::Error as ValidationError, Url};
+    use std::convert::TryFrom;
+
+    const EXAMPLE: &str = r#"
+    {
+        "name": "foo",
+        "vers": "0.1.0",
+        "deps": [
+            {
+                "name": "rand",
+                "version_req": "^0.6",
+                "features": ["i128_support"],
+                "optional": false,
+                "default_features": true,
+                "target": null,
+                "kind": "normal",
+                "registry": null,
+                "explicit_name_in_toml": "random"
+            },
+            {
+                "name": "serde",
+                "version_req": "^1.0",
+                "features": [],
+                "optional": true,
+                "default_features": true,
+                "target": "cfg(unix)",
+                "kind": "normal",
+                "registry": "https://github.com/rust-lang/crates.io-index",
+                "explicit_name_in_toml": null
+            }
+        ],
+        "features": {
+            "extras": ["random/simd_support"]
+        },
+        "authors": ["Alice <a@example.com>"],
+        "description": "a crate",
+        "documentation": null,
+        "homepage": null,
+        "readme": "the foo crate",
+        "readme_file": "README.md",
+        "keywords": ["foo"],
+        "categories": [],
+        "license": "MIT",
+        "license_file": null,
+        "repository": "https://github.com/foo/foo",
+        "badges": {},
+        "links": null,
+        "rust_version": "1.56"
+    }
+    "#;
+
+    fn crates_io() -> Url {
+        Url::parse("https://github.com/rust-lang/crates.io-index").unwrap()
+    }
+
+    #[test]
+    fn deserialize() {
+        let request: PublishRequest = serde_json::from_str(EXAMPLE).unwrap();
+
+        assert_eq!(request.name(), "foo");
+        assert_eq!(request.readme().unwrap(), "the foo crate");
+        assert_eq!(request.dependencies().len(), 2);
+        assert_eq!(
+            request.dependencies()[0].explicit_name_in_toml().unwrap(),
+            "random"
+        );
+    }
+
+    #[test]
+    fn into_record() {
+        let request: PublishRequest = serde_json::from_str(EXAMPLE).unwrap();
+        let record = request.into_record("checksum", &[crates_io()]).unwrap();
+
+        let expected: serde_json::Value = serde_json::from_str(
+            r#"
+            {
+                "name": "foo",
+                "vers": "0.1.0",
+                "deps": [
+                    {
+                        "name": "random",
+                        "req": "^0.6",
+                        "features": ["i128_support"],
+                        "optional": false,
+                        "default_features": true,
+                        "target": null,
+                        "kind": "normal",
+                        "package": "rand"
+                    },
+                    {
+                        "name": "serde",
+                        "req": "^1.0",
+                        "features": [],
+                        "optional": true,
+                        "default_features": true,
+                        "target": "cfg(unix)",
+                        "kind": "normal",
+                        "registry": "https://github.com/rust-lang/crates.io-index"
+                    }
+                ],
+                "cksum": "checksum",
+                "features": {
+                    "extras": ["random/simd_support"]
+                },
+                "yanked": false,
+                "rust_version": "1.56"
+            }"#,
+        )
+        .unwrap();
+
+        let actual: serde_json::Value = serde_json::from_str(&record.to_string()).unwrap();
+
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn disallowed_registry() {
+        let request: PublishRequest = serde_json::from_str(EXAMPLE).unwrap();
+
+        match request.into_record("checksum", &[]) {
+            Err(ValidationError::Registry {
+                dependency,
+                registry,
+            }) => {
+                assert_eq!(dependency, "serde");
+                assert_eq!(registry, crates_io());
+            }
+            other => panic!("expected a registry error, got {:?}", other),
+        }
+    }
+
+    fn body(json: &str, crate_file: &[u8]) -> Vec<u8> {
+        let mut body = Vec::new();
+        body.extend_from_slice(&u32::try_from(json.len()).unwrap().to_le_bytes());
+        body.extend_from_slice(json.as_bytes());
+        body.extend_from_slice(&u32::try_from(crate_file.len()).unwrap().to_le_bytes());
+        body.extend_from_slice(crate_file);
+        body
+    }
+
+    #[test]
+    fn parse() {
+        let body = body(EXAMPLE, b"crate file");
+
+        let (request, crate_file) = parse_body(&body).unwrap();
+        assert_eq!(request.name(), "foo");
+        assert_eq!(crate_file, b"crate file");
+
+        assert!(matches!(
+            parse_body(&body[..body.len() - 1]),
+            Err(BodyError::Truncated)
+        ));
+        assert!(matches!(parse_body(&[1, 0]), Err(BodyError::Truncated)));
+    }
+}
@@ -1,39 +1,79 @@
 //! Representations of crate metadata in an index
 
+use crate::validate::{self, Error as ValidationError};
 use semver::{Version, VersionReq};
-use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, fmt};
+use serde::{
+    de::Error as _, ser::SerializeStruct, Deserialize, Deserializer, Serialize, Serializer,
+};
+use serde_json::{Map, Value};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fmt,
+};
 use url::Url;
 
+/// The highest version of the index schema (the `v` field) which this library
+/// understands.
+///
+/// Version 2 adds the `features2` map, for features which use the `dep:` or
+/// `?/` syntax.
+pub const SCHEMA_VERSION: u32 = 2;
+
 /// Rust crate metadata, as stored in the crate index.
 ///
+/// Features are stored as a single map. When serialised, features which older
+/// versions of Cargo can't parse are split out into the `features2` map, and
+/// the schema version is set accordingly. When deserialised, the two maps are
+/// merged back together.
+///
+/// Records with a schema version higher than [`SCHEMA_VERSION`] are preserved
+/// as they are.
+///
+/// A record which was deserialised remembers the JSON it was read from. When it
+/// is serialised again, fields which this library doesn't understand (such as
+/// the `pubtime` field written by crates.io) are kept, along with the original
+/// order of the fields and any explicit `null`, `false` or empty values. A
+/// record which is deserialised and serialised again without being modified is
+/// reproduced byte-for-byte.
+///
+/// Otherwise, records are serialised in the canonical layout used by
+/// crates.io- fields in the same order, features sorted by name, and
+/// dependencies sorted by name and kind. Existing index files can be rewritten
+/// into this layout with [`Tree::normalise`](crate::tree::Tree::normalise).
+///
 /// *[See the documentation for details](https://doc.rust-lang.org/cargo/reference/registries.html)*
-#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Record {
-    name: String,
+    pub(crate) name: String,
 
-    vers: Version,
+    pub(crate) vers: Version,
 
-    #[serde(skip_serializing_if = "Vec::is_empty", default)]
-    deps: Vec<Dependency>,
+    pub(crate) deps: Vec<Dependency>,
 
-    cksum: String,
+    pub(crate) cksum: String,
 
-    #[serde(skip_serializing_if = "HashMap::is_empty", default)]
-    features: HashMap<String, Vec<String>>,
+    pub(crate) features: HashMap<String, Vec<String>>,
 
-    #[serde(skip_serializing_if = "std::ops::Not::not", default)]
-    yanked: bool,
+    pub(crate) yanked: bool,
 
-    #[serde(skip_serializing_if = "Option::is_none", default)]
-    links: Option<String>,
+    pub(crate) links: Option<String>,
+
+    pub(crate) rust_version: Option<String>,
+
+    pub(crate) v: Option<u32>,
+
+    /// The `features2` map of a record with an unsupported schema version
+    unsupported_features2: Option<HashMap<String, Vec<String>>>,
+
+    /// The JSON object the record was deserialised from
+    original: Original,
 }
 
 impl Record {
     /// Create a new metadata object.
     ///
     /// The method parameters are all required, optional parameters can be set
-    /// using the builder API.
+    /// using the builder API (see [`Record::builder`]).
     pub fn new(name: impl Into<String>, version: Version, check_sum: impl Into<String>) -> Self {
         let name = name.into();
         let vers = version;
@@ -42,6 +82,10 @@ impl Record {
         let features = HashMap::new();
         let yanked = false;
         let links = None;
+        let rust_version = None;
+        let v = None;
+        let unsupported_features2 = None;
+        let original = Original::default();
 
         Self {
             name,
@@ -51,7 +95,51 @@ impl Record {
             features,
             yanked,
             links,
+            rust_version,
+            v,
+            unsupported_features2,
+            original,
+        }
+    }
+
+    /// Create a builder for a new metadata object, with dependencies, features
+    /// and a `links` value.
+    ///
+    /// Each value is validated as it is set, and the first error is returned
+    /// by [`Builder::build`].
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use crate_index::{record::DependencyKind, Record, Dependency, Version};
+    /// # use crate_index::validate::Error;
+    ///
+    /// let rand = Dependency::builder("rand", "0.8".parse().unwrap())
+    ///     .optional(true)
+    ///     .build()?;
+    ///
+    /// let cc = Dependency::builder("cc", "1.0".parse().unwrap())
+    ///     .kind(DependencyKind::Build)
+    ///     .build()?;
+    ///
+    /// let record = Record::builder("foo", Version::new(0, 1, 0), "checksum")
+    ///     .dependency(rand)
+    ///     .dependency(cc)
+    ///     .feature("random", &["rand"])
+    ///     .links("foo")
+    ///     .build()?;
+    /// # Ok::<(), Error>(())
+    /// ```
+    pub fn builder(
+        name: impl Into<String>,
+        version: Version,
+        check_sum: impl Into<String>,
+    ) -> Builder {
+        Builder {
+            record: Self::new(name, version, check_sum),
+            error: None,
         }
+        .check(|builder| validate::name(&builder.record.name))
     }
 
     /// The name of the crate
@@ -98,6 +186,118 @@ impl Record {
         self.links.as_ref()
     }
 
+    /// The minimum supported Rust version of the crate, from the package's
+    /// manifest
+    #[must_use]
+    pub fn rust_version(&self) -> Option<&String> {
+        self.rust_version.as_ref()
+    }
+
+    /// Whether this crate version can be built with the given version of
+    /// `rustc`.
+    ///
+    /// Crates which don't specify a minimum supported Rust version are
+    /// compatible with every version. Pre-release versions of `rustc` (such as
+    /// nightlies) are treated as the corresponding release.
+    #[must_use]
+    pub fn is_compatible_with(&self, rustc: &Version) -> bool {
+        let rust_version = match &self.rust_version {
+            Some(rust_version) => rust_version,
+            None => return true,
+        };
+
+        let rustc = Version::new(rustc.major, rustc.minor, rustc.patch);
+
+        VersionReq::parse(&format!(">={}", rust_version))
+            .map_or(false, |required| required.matches(&rustc))
+    }
+
+    /// The version of the index schema this record is serialised with.
+    ///
+    /// This is `2` if any features use the `dep:` or `?/` syntax, otherwise
+    /// it is the version the record was deserialised with (defaulting to `1`).
+    #[must_use]
+    pub fn schema_version(&self) -> u32 {
+        self.serialised_version().unwrap_or(1)
+    }
+
+    fn serialised_version(&self) -> Option<u32> {
+        if self.unsupported_features2.is_none()
+            && self
+                .features
+                .values()
+                .any(|enables| uses_v2_syntax(enables))
+        {
+            Some(self.v.unwrap_or(1).max(2))
+        } else {
+            self.v
+        }
+    }
+
+    /// Split the features into those which can be read by any version of
+    /// Cargo, and those which go in the `features2` map.
+    ///
+    /// Features which use the new syntax are moved to `features2`, along with
+    /// any features which (transitively) enable them.
+    #[allow(clippy::type_complexity)]
+    fn split_features(
+        &self,
+    ) -> (
+        BTreeMap<&String, &Vec<String>>,
+        BTreeMap<&String, &Vec<String>>,
+    ) {
+        if let Some(features2) = &self.unsupported_features2 {
+            return (self.features.iter().collect(), features2.iter().collect());
+        }
+
+        let mut v2: BTreeSet<&String> = self
+            .features
+            .iter()
+            .filter(|(_, enables)| uses_v2_syntax(enables))
+            .map(|(name, _)| name)
+            .collect();
+
+        loop {
+            let dependents: Vec<_> = self
+                .features
+                .iter()
+                .filter(|(name, enables)| {
+                    !v2.contains(name) && enables.iter().any(|entry| v2.contains(entry))
+                })
+                .map(|(name, _)| name)
+                .collect();
+
+            if dependents.is_empty() {
+                break;
+            }
+            v2.extend(dependents);
+        }
+
+        self.features
+            .iter()
+            .partition(|(name, _)| !v2.contains(name))
+    }
+
+    /// Rewrite the record into the canonical layout used by crates.io.
+    ///
+    /// Fields which this library doesn't understand are kept, but the original
+    /// field order and any redundant `null` values are discarded.
+    pub(crate) fn normalise(&mut self) {
+        if let Some(original) = &self.original.0 {
+            let canonical = canonicalise(&self.fields(), original);
+            self.original = Original(Some(canonical));
+        }
+    }
+
+    /// The fields of the record which this library understands, in the
+    /// canonical layout
+    fn fields(&self) -> Map<String, Value> {
+        match serde_json::to_value(Fields(self)) {
+            Ok(Value::Object(fields)) => fields,
+            _ => unreachable!("records are serialised as objects"),
+        }
+    }
+
     /// Set the 'yanked' status of the crate version to 'true'
     pub fn yank(&mut self) {
         self.yanked = true;
@@ -127,12 +327,499 @@ impl PartialEq<Version> for Record {
     }
 }
 
+fn uses_v2_syntax(enables: &[String]) -> bool {
+    enables
+        .iter()
+        .any(|entry| entry.starts_with("dep:") || entry.contains("?/"))
+}
+
+/// The serialised layout of a [`Record`]
+#[derive(Deserialize)]
+struct RawRecord {
+    name: String,
+
+    vers: Version,
+
+    #[serde(default)]
+    deps: Vec<Dependency>,
+
+    cksum: String,
+
+    #[serde(default)]
+    features: HashMap<String, Vec<String>>,
+
+    #[serde(default)]
+    features2: Option<HashMap<String, Vec<String>>>,
+
+    #[serde(default)]
+    yanked: bool,
+
+    #[serde(default)]
+    links: Option<String>,
+
+    #[serde(default)]
+    rust_version: Option<String>,
+
+    #[serde(default)]
+    v: Option<u32>,
+}
+
+impl From<RawRecord> for Record {
+    fn from(raw: RawRecord) -> Self {
+        let mut features = raw.features;
+        let mut unsupported_features2 = None;
+
+        if raw.v.unwrap_or(1) > SCHEMA_VERSION {
+            unsupported_features2 = raw.features2;
+        } else if let Some(features2) = raw.features2 {
+            features.extend(features2);
+        }
+
+        Self {
+            name: raw.name,
+            vers: raw.vers,
+            deps: raw.deps,
+            cksum: raw.cksum,
+            features,
+            yanked: raw.yanked,
+            links: raw.links,
+            rust_version: raw.rust_version,
+            v: raw.v,
+            unsupported_features2,
+            original: Original::default(),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Record {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let original = Map::deserialize(deserializer)?;
+        let raw =
+            RawRecord::deserialize(Value::Object(original.clone())).map_err(D::Error::custom)?;
+
+        let mut record = Self::from(raw);
+        record.original = Original(Some(original));
+        Ok(record)
+    }
+}
+
+impl Serialize for Record {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        match &self.original.0 {
+            Some(original) => merge_record(self.fields(), original).serialize(serializer),
+            None => Fields(self).serialize(serializer),
+        }
+    }
+}
+
+/// The fields of a [`Record`] which this library understands, in the canonical
+/// layout used by crates.io.
+///
+/// Fields are written in the same order as crates.io, features are sorted by
+/// name, and dependencies are sorted by name and kind.
+struct Fields<'a>(&'a Record);
+
+impl Serialize for Fields<'_> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let record = self.0;
+        let (features, features2) = record.split_features();
+        let v = record.serialised_version();
+
+        let mut deps: Vec<&Dependency> = record.deps.iter().collect();
+        deps.sort_by_key(|dep| dep.sort_key());
+
+        let mut state = serializer.serialize_struct("Record", 10)?;
+
+        state.serialize_field("name", &record.name)?;
+        state.serialize_field("vers", &record.vers)?;
+        state.serialize_field("deps", &deps)?;
+        state.serialize_field("cksum", &record.cksum)?;
+        state.serialize_field("features", &features)?;
+        if features2.is_empty() {
+            state.skip_field("features2")?;
+        } else {
+            state.serialize_field("features2", &features2)?;
+        }
+        state.serialize_field("yanked", &record.yanked)?;
+        match &record.links {
+            Some(links) => state.serialize_field("links", links)?,
+            None => state.skip_field("links")?,
+        }
+        match &record.rust_version {
+            Some(rust_version) => state.serialize_field("rust_version", rust_version)?,
+            None => state.skip_field("rust_version")?,
+        }
+        match v {
+            Some(v) => state.serialize_field("v", &v)?,
+            None => state.skip_field("v")?,
+        }
+
+        state.end()
+    }
+}
+
+/// The JSON object a [`Record`] was deserialised from, if any.
+///
+/// This is only used to preserve the layout of the record when it is
+/// rewritten, so it is ignored when comparing records.
+#[derive(Debug, Clone, Default)]
+struct Original(Option<Map<String, Value>>);
+
+impl PartialEq for Original {
+    fn eq(&self, _other: &Self) -> bool {
+        true
+    }
+}
+
+/// The fields of a record which this library understands
+const RECORD_FIELDS: &[&str] = &[
+    "name",
+    "vers",
+    "deps",
+    "cksum",
+    "features",
+    "features2",
+    "yanked",
+    "links",
+    "rust_version",
+    "v",
+];
+
+/// The fields of a dependency which this library understands
+const DEPENDENCY_FIELDS: &[&str] = &[
+    "name",
+    "req",
+    "features",
+    "optional",
+    "default_features",
+    "target",
+    "kind",
+    "registry",
+    "package",
+    "public",
+    "artifact",
+    "bindep_target",
+    "lib",
+];
+
+/// Merge the serialised fields of a record into the object it was
+/// deserialised from.
+fn merge_record(
+    mut fields: Map<String, Value>,
+    original: &Map<String, Value>,
+) -> Map<String, Value> {
+    if let (Some(Value::Array(deps)), Some(Value::Array(original_deps))) =
+        (fields.get_mut("deps"), original.get("deps"))
+    {
+        *deps = merge_dependencies(std::mem::take(deps), original_deps);
+    }
+
+    merge(&fields, original, RECORD_FIELDS)
+}
+
+/// Merge serialised dependencies into the dependencies they were
+/// deserialised from.
+///
+/// If every original dependency is matched, they keep their original order.
+fn merge_dependencies(deps: Vec<Value>, original: &[Value]) -> Vec<Value> {
+    let matches = match_dependencies(&deps, original);
+    let complete = deps.len() == original.len() && matches.iter().all(Option::is_some);
+
+    let mut merged: Vec<(Option<usize>, Value)> = deps
+        .into_iter()
+        .zip(matches)
+        .map(
+            |(dep, index)| match (dep, index.map(|index| &original[index])) {
+                (Value::Object(dep), Some(Value::Object(original))) => (
+                    index,
+                    Value::Object(merge(&dep, original, DEPENDENCY_FIELDS)),
+                ),
+                (dep, _) => (index, dep),
+            },
+        )
+        .collect();
+
+    if complete {
+        merged.sort_by_key(|(index, _)| *index);
+    }
+
+    merged.into_iter().map(|(_, dep)| dep).collect()
+}
+
+/// Find the original dependency which each serialised dependency came from.
+///
+/// Dependencies are matched by name, package, kind and target.
+fn match_dependencies(deps: &[Value], original: &[Value]) -> Vec<Option<usize>> {
+    let identity = |dep: &Value| {
+        ["name", "package", "kind", "target"]
+            .iter()
+            .map(|key| dep.get(key).cloned().unwrap_or(Value::Null))
+            .collect::<Vec<_>>()
+    };
+
+    let mut used = vec![false; original.len()];
+
+    deps.iter()
+        .map(|dep| {
+            let key = identity(dep);
+            let index = (0..original.len())
+                .find(|&index| !used[index] && identity(&original[index]) == key)?;
+            used[index] = true;
+            Some(index)
+        })
+        .collect()
+}
+
+/// Merge serialised fields into an original object.
+///
+/// Fields keep their original position. Unknown fields are kept as they are,
+/// and known fields which are no longer serialised are replaced with an empty
+/// value (so that an explicit `"links":null` survives). Values which are
+/// unchanged, or equivalent to the original (such as a version requirement
+/// written without the `^`), keep their original text. New fields are placed
+/// after the field which precedes them in the canonical layout, unless they
+/// are empty.
+fn merge(
+    fields: &Map<String, Value>,
+    original: &Map<String, Value>,
+    known: &[&str],
+) -> Map<String, Value> {
+    let mut merged = Map::new();
+    let mut new_fields = fields
+        .iter()
+        .filter(|(key, value)| !original.contains_key(*key) && empty(value).as_ref() != Some(value))
+        .peekable();
+    let position = |key: &String| fields.keys().position(|field| field == key);
+
+    for (key, original_value) in original {
+        if let Some(index) = position(key) {
+            while let Some((new_key, new_value)) = new_fields.peek() {
+                if position(new_key) > Some(index) {
+                    break;
+                }
+                merged.insert((*new_key).clone(), (*new_value).clone());
+                new_fields.next();
+            }
+        }
+
+        let value = match fields.get(key) {
+            Some(value) if equivalent(key, value, original_value) => original_value.clone(),
+            Some(value) => value.clone(),
+            None if known.contains(&key.as_str()) => match empty(original_value) {
+                Some(value) => value,
+                None => continue,
+            },
+            None => original_value.clone(),
+        };
+        merged.insert(key.clone(), value);
+    }
+
+    for (key, value) in new_fields {
+        merged.insert(key.clone(), value.clone());
+    }
+
+    merged
+}
+
+/// Rewrite a record into the canonical layout, keeping unknown fields.
+///
+/// Unknown fields of the record are placed before the schema version (as
+/// crates.io does with `pubtime`), and unknown fields of a dependency are
+/// placed at the end.
+fn canonicalise(fields: &Map<String, Value>, original: &Map<String, Value>) -> Map<String, Value> {
+    let mut canonical = Map::new();
+
+    for (key, value) in fields {
+        if key == "v" {
+            continue;
+        }
+
+        let value = match (key.as_str(), value, original.get(key)) {
+            ("deps", Value::Array(deps), Some(Value::Array(original_deps))) => {
+                let matches = match_dependencies(deps, original_deps);
+                let deps = deps
+                    .iter()
+                    .zip(matches)
+                    .map(
+                        |(dep, index)| match (dep, index.map(|index| &original_deps[index])) {
+                            (Value::Object(dep), Some(Value::Object(original_dep))) => {
+                                let mut dep = dep.clone();
+                                dep.extend(unknown(original_dep, DEPENDENCY_FIELDS));
+                                Value::Object(dep)
+                            }
+                            (dep, _) => dep.clone(),
+                        },
+                    )
+                    .collect();
+                Value::Array(deps)
+            }
+            _ => value.clone(),
+        };
+        canonical.insert(key.clone(), value);
+    }
+
+    canonical.extend(unknown(original, RECORD_FIELDS));
+
+    if let Some(v) = fields.get("v") {
+        canonical.insert("v".to_string(), v.clone());
+    }
+
+    canonical
+}
+
+/// The fields of an object which are not known
+fn unknown<'a>(
+    object: &'a Map<String, Value>,
+    known: &'a [&str],
+) -> impl Iterator<Item = (String, Value)> + 'a {
+    object
+        .iter()
+        .filter(move |(key, _)| !known.contains(&key.as_str()))
+        .map(|(key, value)| (key.clone(), value.clone()))
+}
+
+/// Whether a serialised value means the same as the original value
+fn equivalent(key: &str, value: &Value, original: &Value) -> bool {
+    if value == original {
+        return true;
+    }
+
+    match (key, value.as_str(), original.as_str()) {
+        ("req", Some(value), Some(original)) => {
+            VersionReq::parse(value).ok() == VersionReq::parse(original).ok()
+        }
+        _ => false,
+    }
+}
+
+/// The empty value of the same type as a given value, if there is one
+fn empty(value: &Value) -> Option<Value> {
+    match value {
+        Value::Null | Value::String(_) => Some(Value::Null),
+        Value::Bool(_) => Some(Value::Bool(false)),
+        Value::Array(_) => Some(Value::Array(Vec::new())),
+        Value::Object(_) => Some(Value::Object(Map::new())),
+        Value::Number(_) => None,
+    }
+}
+
 impl fmt::Display for Record {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "{}", &serde_json::to_string(self).unwrap())
     }
 }
 
+/// A builder for a [`Record`]
+#[derive(Debug)]
+#[must_use]
+pub struct Builder {
+    record: Record,
+    error: Option<ValidationError>,
+}
+
+impl Builder {
+    /// Add a [`Dependency`]
+    pub fn dependency(mut self, dependency: Dependency) -> Self {
+        self.record.deps.push(dependency);
+        self
+    }
+
+    /// Add a feature, and the features or dependencies it enables.
+    ///
+    /// Each entry may be the name of another feature, the name of an optional
+    /// dependency (optionally with a `dep:` prefix), or a feature of a
+    /// dependency (`dep/feature`, or `dep?/feature` for an optional
+    /// dependency). The features and dependencies which are enabled must be
+    /// added first.
+    pub fn feature<I, S>(self, name: impl Into<String>, enables: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let name = name.into();
+        let enables: Vec<String> = enables
+            .into_iter()
+            .map(|entry| entry.as_ref().to_string())
+            .collect();
+
+        let mut builder = self.check(|builder| {
+            match enables
+                .iter()
+                .find(|entry| !builder.is_valid_reference(entry))
+            {
+                Some(entry) => Err(ValidationError::feature(&name, entry)),
+                None => Ok(()),
+            }
+        });
+        builder.record.features.insert(name, enables);
+        builder
+    }
+
+    /// Set the `links` value from the package's manifest
+    pub fn links(mut self, links: impl Into<String>) -> Self {
+        self.record.links = Some(links.into());
+        self
+    }
+
+    /// Set the minimum supported Rust version, such as `1`, `1.56` or `1.56.1`
+    pub fn rust_version(mut self, rust_version: impl Into<String>) -> Self {
+        let rust_version = rust_version.into();
+        self = self.check(|_| validate::rust_version(&rust_version));
+        self.record.rust_version = Some(rust_version);
+        self
+    }
+
+    /// Construct the [`Record`].
+    ///
+    /// # Errors
+    ///
+    /// A [`ValidationError`] is returned if the crate name or minimum
+    /// supported Rust version is invalid, or a feature enables something which
+    /// is neither a feature nor a dependency. If more than one value is
+    /// invalid, the error is for the first one which was set.
+    pub fn build(self) -> Result<Record, ValidationError> {
+        match self.error {
+            Some(error) => Err(error),
+            None => Ok(self.record),
+        }
+    }
+
+    /// Validate the builder with the given check, unless an earlier check has
+    /// already failed
+    fn check(mut self, check: impl FnOnce(&Self) -> Result<(), ValidationError>) -> Self {
+        if self.error.is_none() {
+            self.error = check(&self).err();
+        }
+        self
+    }
+
+    fn is_valid_reference(&self, entry: &str) -> bool {
+        let dependency = |name: &str| self.record.deps.iter().find(|dep| dep.name == name);
+
+        if let Some(name) = entry.strip_prefix("dep:") {
+            dependency(name).map_or(false, |dep| dep.optional)
+        } else if let Some(index) = entry.find('/') {
+            let name = &entry[..index];
+            match name.strip_suffix('?') {
+                Some(name) => dependency(name).map_or(false, |dep| dep.optional),
+                None => dependency(name).is_some(),
+            }
+        } else {
+            self.record.features.contains_key(entry)
+                || dependency(entry).map_or(false, |dep| dep.optional)
+        }
+    }
+}
+
 /// A dependency on another crate
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Dependency {
@@ -140,42 +827,309 @@ pub struct Dependency {
     /// If the dependency is renamed from the original package name,
     /// this is the new name. The original package name is stored in
     /// the `package` field.
-    name: String,
+    pub(crate) name: String,
 
     /// The semver requirement for this dependency.
-    req: VersionReq,
+    pub(crate) req: VersionReq,
 
     /// Array of features (as strings) enabled for this dependency.
-    #[serde(skip_serializing_if = "Vec::is_empty", default)]
-    features: Vec<String>,
+    #[serde(default)]
+    pub(crate) features: Vec<String>,
 
     /// Boolean of whether or not this is an optional dependency.
-    optional: bool,
+    pub(crate) optional: bool,
 
     /// Boolean of whether or not default features are enabled.
-    default_features: bool,
+    pub(crate) default_features: bool,
 
     /// The target platform for the dependency.
     /// null if not a target dependency.
     /// Otherwise, a string such as "cfg(windows)".
-    #[serde(skip_serializing_if = "Option::is_none", default)]
-    target: Option<String>,
+    #[serde(default)]
+    pub(crate) target: Option<String>,
 
     /// The dependency kind.
     /// "dev", "build", or "normal".
-    kind: DependencyKind,
+    pub(crate) kind: DependencyKind,
 
     /// The URL of the index of the registry where this dependency is
     /// from as a string. If not specified or null, it is assumed the
     /// dependency is in the current registry.
     #[serde(skip_serializing_if = "Option::is_none", default)]
-    registry: Option<Url>,
+    pub(crate) registry: Option<Url>,
 
     /// If the dependency is renamed, this is a string of the actual
     /// package name. If not specified or null, this dependency is not
     /// renamed.
     #[serde(skip_serializing_if = "Option::is_none", default)]
-    package: Option<String>,
+    pub(crate) package: Option<String>,
+
+    /// Whether or not this is a public dependency (see RFC 1977).
+    /// If not specified or null, the dependency is private.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub(crate) public: Option<bool>,
+
+    /// For artifact dependencies, the kinds of artifact which are depended
+    /// on. Each is `bin`, `cdylib`, `staticlib`, or `bin:<name>`.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub(crate) artifact: Option<Vec<String>>,
+
+    /// For artifact dependencies, the target platform to build the
+    /// artifact for.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub(crate) bindep_target: Option<String>,
+
+    /// For artifact dependencies, whether the library of the dependency
+    /// is also depended on.
+    #[serde(skip_serializing_if = "std::ops::Not::not", default)]
+    pub(crate) lib: bool,
+}
+
+impl Dependency {
+    /// Create a builder for a dependency on the given package.
+    ///
+    /// By default this is a normal, non-optional dependency from the same
+    /// registry, with default features enabled.
+    ///
+    /// Each value is validated as it is set, and the first error is returned
+    /// by [`DependencyBuilder::build`].
+    pub fn builder(name: impl Into<String>, req: VersionReq) -> DependencyBuilder {
+        DependencyBuilder {
+            dependency: Self {
+                name: name.into(),
+                req,
+                features: Vec::new(),
+                optional: false,
+                default_features: true,
+                target: None,
+                kind: DependencyKind::Normal,
+                registry: None,
+                package: None,
+                public: None,
+                artifact: None,
+                bindep_target: None,
+                lib: false,
+            },
+            error: None,
+        }
+        .check(|builder| validate::name(&builder.dependency.name))
+    }
+
+    /// The name of the dependency.
+    ///
+    /// If the dependency is renamed, this is the new name.
+    #[must_use]
+    pub fn name(&self) -> &String {
+        &self.name
+    }
+
+    /// The semver requirement for this dependency
+    #[must_use]
+    pub fn version_req(&self) -> &VersionReq {
+        &self.req
+    }
+
+    /// The features enabled for this dependency
+    #[must_use]
+    pub fn features(&self) -> &Vec<String> {
+        &self.features
+    }
+
+    /// Whether or not this is an optional dependency
+    #[must_use]
+    pub fn optional(&self) -> bool {
+        self.optional
+    }
+
+    /// Whether or not default features are enabled
+    #[must_use]
+    pub fn default_features(&self) -> bool {
+        self.default_features
+    }
+
+    /// The target platform for the dependency, if it is platform-specific
+    #[must_use]
+    pub fn target(&self) -> Option<&String> {
+        self.target.as_ref()
+    }
+
+    /// The dependency kind
+    #[must_use]
+    pub fn kind(&self) -> &DependencyKind {
+        &self.kind
+    }
+
+    /// The registry this dependency is from, or `None` if it is from the same
+    /// registry
+    #[must_use]
+    pub fn registry(&self) -> Option<&Url> {
+        self.registry.as_ref()
+    }
+
+    /// The original package name, if the dependency is renamed
+    #[must_use]
+    pub fn package(&self) -> Option<&String> {
+        self.package.as_ref()
+    }
+
+    /// Whether or not this is a public dependency
+    #[must_use]
+    pub fn public(&self) -> bool {
+        self.public.unwrap_or(false)
+    }
+
+    /// The kinds of artifact depended on, if this is an artifact dependency
+    #[must_use]
+    pub fn artifact(&self) -> Option<&Vec<String>> {
+        self.artifact.as_ref()
+    }
+
+    /// The target platform to build the artifact for, if this is an artifact
+    /// dependency
+    #[must_use]
+    pub fn bindep_target(&self) -> Option<&String> {
+        self.bindep_target.as_ref()
+    }
+
+    /// Whether the library of an artifact dependency is also depended on
+    #[must_use]
+    pub fn lib(&self) -> bool {
+        self.lib
+    }
+
+    /// The key dependencies are sorted by when serialised.
+    ///
+    /// This matches the order used by crates.io- by name, then by kind.
+    /// Dependencies with the same name and kind (such as platform-specific
+    /// variants) keep the order they were declared in.
+    fn sort_key(&self) -> (&String, u8) {
+        let kind = match self.kind {
+            DependencyKind::Normal => 0,
+            DependencyKind::Build => 1,
+            DependencyKind::Dev => 2,
+        };
+
+        (&self.name, kind)
+    }
+}
+
+/// A builder for a [`Dependency`]
+#[derive(Debug)]
+#[must_use]
+pub struct DependencyBuilder {
+    dependency: Dependency,
+    error: Option<ValidationError>,
+}
+
+impl DependencyBuilder {
+    /// Set the dependency kind (normal, build, or dev)
+    pub fn kind(mut self, kind: DependencyKind) -> Self {
+        self.dependency.kind = kind;
+        self
+    }
+
+    /// Make the dependency specific to a target platform, such as
+    /// `cfg(windows)`
+    pub fn target(mut self, target: impl Into<String>) -> Self {
+        self.dependency.target = Some(target.into());
+        self
+    }
+
+    /// Set whether the dependency is optional
+    pub fn optional(mut self, optional: bool) -> Self {
+        self.dependency.optional = optional;
+        self
+    }
+
+    /// Set whether the default features of the dependency are enabled
+    pub fn default_features(mut self, default_features: bool) -> Self {
+        self.dependency.default_features = default_features;
+        self
+    }
+
+    /// Enable a feature of the dependency
+    pub fn feature(mut self, feature: impl Into<String>) -> Self {
+        self.dependency.features.push(feature.into());
+        self
+    }
+
+    /// Rename the dependency.
+    ///
+    /// The dependency is referred to by the new name in the crate. The
+    /// original package name is kept in the `package` field.
+    pub fn rename(mut self, name: impl Into<String>) -> Self {
+        let package = std::mem::replace(&mut self.dependency.name, name.into());
+        if self.dependency.package.is_none() {
+            self.dependency.package = Some(package);
+        }
+        self.check(|builder| validate::name(&builder.dependency.name))
+    }
+
+    /// Set the URL of the index of the registry this dependency is from
+    pub fn registry(mut self, registry: Url) -> Self {
+        self.dependency.registry = Some(registry);
+        self
+    }
+
+    /// Set whether this is a public dependency
+    pub fn public(mut self, public: bool) -> Self {
+        self.dependency.public = Some(public);
+        self
+    }
+
+    /// Make this an artifact dependency, on the given kinds of artifact.
+    ///
+    /// Each kind is `bin`, `cdylib`, `staticlib`, or `bin:<name>`.
+    pub fn artifact<I, S>(mut self, kinds: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.dependency.artifact = Some(kinds.into_iter().map(Into::into).collect());
+        self.check(|builder| validate::artifact(&builder.dependency))
+    }
+
+    /// Set the target platform to build an artifact dependency for
+    pub fn bindep_target(mut self, target: impl Into<String>) -> Self {
+        self.dependency.bindep_target = Some(target.into());
+        self
+    }
+
+    /// Set whether the library of an artifact dependency is also depended on
+    pub fn lib(mut self, lib: bool) -> Self {
+        self.dependency.lib = lib;
+        self
+    }
+
+    /// Construct the [`Dependency`].
+    ///
+    /// # Errors
+    ///
+    /// A [`ValidationError`] is returned if the package name or the new name
+    /// of a renamed dependency is invalid, or the artifact fields are not
+    /// valid. If more than one value is invalid, the error is for the first
+    /// one which was set.
+    ///
+    /// `lib` and `bindep_target` may be set before the artifact kinds, so
+    /// they are checked here rather than when they are set.
+    pub fn build(self) -> Result<Dependency, ValidationError> {
+        if let Some(error) = self.error {
+            return Err(error);
+        }
+
+        validate::artifact(&self.dependency)?;
+
+        Ok(self.dependency)
+    }
+
+    /// Validate the builder with the given check, unless an earlier check has
+    /// already failed
+    fn check(mut self, check: impl FnOnce(&Self) -> Result<(), ValidationError>) -> Self {
+        if self.error.is_none() {
+            self.error = check(&self).err();
+        }
+        self
+    }
 }
 
 /// Type of crate dependency
@@ -194,8 +1148,12 @@ pub enum DependencyKind {
 
 #[cfg(test)]
 mod tests {
-    use super::Record;
+    use super::{Dependency, DependencyKind, Record};
+    use crate::validate::Error as ValidationError;
     use semver::Version;
+    use serde_json::Value;
+    use test_case::test_case;
+    use url::Url;
 
     #[test]
     fn serialize() {
@@ -205,7 +1163,7 @@ mod tests {
 
         let metadata = Record::new(name, version, check_sum);
 
-        let expected = r#"{"name":"foo","vers":"0.1.0","cksum":"d867001db0e2b6e0496f9fac96930e2d42233ecd3ca0413e0753d4c7695d289c"}"#.to_string();
+        let expected = r#"{"name":"foo","vers":"0.1.0","deps":[],"cksum":"d867001db0e2b6e0496f9fac96930e2d42233ecd3ca0413e0753d4c7695d289c","features":{},"yanked":false}"#.to_string();
         let actual = metadata.to_string();
 
         assert_eq!(expected, actual);
@@ -292,4 +1250,389 @@ mod tests {
         metadata.unyank();
         assert!(!metadata.yanked());
     }
+
+    #[test]
+    fn build_dependency() {
+        let registry = Url::parse("https://my-intranet:8080/index").unwrap();
+
+        let dependency = Dependency::builder("serde", "1.0".parse().unwrap())
+            .rename("my_serde")
+            .kind(DependencyKind::Dev)
+            .target("cfg(unix)")
+            .optional(true)
+            .default_features(false)
+            .feature("derive")
+            .registry(registry.clone())
+            .build()
+            .unwrap();
+
+        assert_eq!(dependency.name(), "my_serde");
+        assert_eq!(dependency.package(), Some(&"serde".to_string()));
+        assert_eq!(dependency.kind(), &DependencyKind::Dev);
+        assert_eq!(dependency.target(), Some(&"cfg(unix)".to_string()));
+        assert!(dependency.optional());
+        assert!(!dependency.default_features());
+        assert_eq!(dependency.features(), &vec!["derive".to_string()]);
+        assert_eq!(dependency.registry(), Some(&registry));
+
+        let expected = r#"{"name":"my_serde","req":"^1.0","features":["derive"],"optional":true,"default_features":false,"target":"cfg(unix)","kind":"dev","registry":"https://my-intranet:8080/index","package":"serde"}"#;
+        assert_eq!(serde_json::to_string(&dependency).unwrap(), expected);
+    }
+
+    #[test_case("serde", "my_serde"; "when names are valid")]
+    #[test_case("serde", "1serde" => panics "invalid"; "when new name is invalid")]
+    #[test_case("nul", "serde" => panics "invalid"; "when package name is invalid")]
+    fn rename_dependency(package: &str, name: &str) {
+        Dependency::builder(package, "1.0".parse().unwrap())
+            .rename(name)
+            .build()
+            .expect("invalid");
+    }
+
+    #[test_case("rand"; "when enabling an optional dependency")]
+    #[test_case("dep:rand"; "when enabling an optional dependency explicitly")]
+    #[test_case("std"; "when enabling another feature")]
+    #[test_case("serde/derive"; "when enabling a dependency feature")]
+    #[test_case("rand?/small_rng"; "when weakly enabling a dependency feature")]
+    #[test_case("serde?/derive" => panics "invalid"; "when weakly enabling a feature of a required dependency")]
+    #[test_case("serde" => panics "invalid"; "when enabling a required dependency")]
+    #[test_case("dep:serde" => panics "invalid"; "when explicitly enabling a required dependency")]
+    #[test_case("missing" => panics "invalid"; "when enabling a missing feature")]
+    #[test_case("missing/derive" => panics "invalid"; "when enabling a feature of a missing dependency")]
+    fn build_record(enables: &str) {
+        let rand = Dependency::builder("rand", "0.8".parse().unwrap())
+            .optional(true)
+            .build()
+            .unwrap();
+        let serde = Dependency::builder("serde", "1.0".parse().unwrap())
+            .build()
+            .unwrap();
+
+        let record = Record::builder("foo", Version::new(0, 1, 0), "checksum")
+            .dependency(rand)
+            .dependency(serde)
+            .feature("std", &[] as &[&str])
+            .feature("extra", &[enables])
+            .links("foo")
+            .build()
+            .expect("invalid");
+
+        assert_eq!(record.dependencies().len(), 2);
+        assert_eq!(record.links(), Some(&"foo".to_string()));
+        assert_eq!(record.features()["extra"], vec![enables.to_string()]);
+    }
+
+    #[test]
+    fn validate_as_fields_are_set() {
+        // the error is for the first invalid value
+        match Record::builder("1foo", Version::new(0, 1, 0), "checksum")
+            .rust_version("latest")
+            .build()
+        {
+            Err(ValidationError::InvalidName { .. }) => (),
+            other => panic!("expected an invalid name, got {:?}", other),
+        }
+
+        // dependencies must be added before the features which enable them
+        let rand = Dependency::builder("rand", "0.8".parse().unwrap())
+            .optional(true)
+            .build()
+            .unwrap();
+        assert!(Record::builder("foo", Version::new(0, 1, 0), "checksum")
+            .feature("random", &["rand"])
+            .dependency(rand)
+            .build()
+            .is_err());
+
+        // the artifact kinds are checked when they are set
+        match Dependency::builder("tool", "0.3".parse().unwrap())
+            .artifact(vec!["rlib"])
+            .rename("1tool")
+            .build()
+        {
+            Err(ValidationError::Artifact { .. }) => (),
+            other => panic!("expected an invalid artifact, got {:?}", other),
+        }
+    }
+
+    /// Records taken from the crates.io index
+    const FIXTURES: &str = include_str!("../tests/fixtures/features2.jsonl");
+
+    /// A sample of lines from the crates.io index, including unknown fields
+    const CORPUS: &str = include_str!("../tests/fixtures/corpus.jsonl");
+
+    #[test]
+    fn lossless_round_trip() {
+        for line in CORPUS.lines() {
+            let record: Record = serde_json::from_str(line).unwrap();
+            assert_eq!(record.to_string(), line);
+        }
+    }
+
+    #[test]
+    fn normalise_corpus() {
+        // lines from crates.io are already in the canonical layout
+        for line in CORPUS.lines() {
+            let mut record: Record = serde_json::from_str(line).unwrap();
+            record.normalise();
+            assert_eq!(record.to_string(), line);
+        }
+    }
+
+    #[test]
+    fn normalise() {
+        let line = r#"{"vers":"0.1.0","name":"foo","future":true,"features":{"b":[],"a":["b"]},"v":1,"deps":[{"kind":"dev","name":"serde","req":"1.0","optional":false,"default_features":true,"extra":1},{"name":"anyhow","req":"^1","optional":false,"default_features":true,"kind":"normal","registry":null}],"cksum":"checksum","links":null}"#;
+
+        let mut record: Record = serde_json::from_str(line).unwrap();
+        assert_eq!(record.to_string(), line);
+
+        record.normalise();
+        let expected = r#"{"name":"foo","vers":"0.1.0","deps":[{"name":"anyhow","req":"^1","features":[],"optional":false,"default_features":true,"target":null,"kind":"normal"},{"name":"serde","req":"^1.0","features":[],"optional":false,"default_features":true,"target":null,"kind":"dev","extra":1}],"cksum":"checksum","features":{"a":["b"],"b":[]},"yanked":false,"future":true,"v":1}"#;
+        assert_eq!(record.to_string(), expected);
+
+        // normalising is idempotent
+        let mut record: Record = serde_json::from_str(expected).unwrap();
+        record.normalise();
+        assert_eq!(record.to_string(), expected);
+    }
+
+    #[test]
+    fn dependency_order() {
+        let dependencies = [
+            Dependency::builder("serde", "1.0".parse().unwrap())
+                .kind(DependencyKind::Dev)
+                .build()
+                .unwrap(),
+            Dependency::builder("serde", "1.0".parse().unwrap())
+                .build()
+                .unwrap(),
+            Dependency::builder("anyhow", "1.0".parse().unwrap())
+                .build()
+                .unwrap(),
+        ];
+
+        let serialised: Vec<String> = (0..dependencies.len())
+            .map(|start| {
+                let mut builder = Record::builder("foo", Version::new(0, 1, 0), "checksum");
+                for dependency in dependencies.iter().cycle().skip(start).take(3) {
+                    builder = builder.dependency(dependency.clone());
+                }
+                builder.build().unwrap().to_string()
+            })
+            .collect();
+
+        assert!(serialised.iter().all(|line| line == &serialised[0]));
+
+        let value: Value = serde_json::from_str(&serialised[0]).unwrap();
+        let order: Vec<(&str, &str)> = value["deps"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|dep| (dep["name"].as_str().unwrap(), dep["kind"].as_str().unwrap()))
+            .collect();
+        assert_eq!(
+            order,
+            vec![("anyhow", "normal"), ("serde", "normal"), ("serde", "dev")]
+        );
+    }
+
+    #[test]
+    fn lossless_yank() {
+        for line in CORPUS.lines() {
+            let mut record: Record = serde_json::from_str(line).unwrap();
+            let yanked = record.yanked();
+
+            record.yank();
+            let serialised: Value = serde_json::from_str(&record.to_string()).unwrap();
+            assert_eq!(serialised["yanked"], true);
+            assert_eq!(
+                serialised["pubtime"],
+                serde_json::from_str::<Value>(line).unwrap()["pubtime"]
+            );
+
+            record.unyank();
+            if yanked {
+                assert!(record.to_string().contains(r#""yanked":false"#));
+                record.yank();
+            }
+            assert_eq!(record.to_string(), line);
+        }
+    }
+
+    #[test]
+    fn unknown_dependency_fields() {
+        let line = r#"{"name":"foo","vers":"0.1.0","deps":[{"name":"bar","req":"0.1","optional":false,"default_features":true,"kind":"normal","future":{"a":1}}],"cksum":"checksum","features":{},"yanked":false,"links":null,"future":[1,2]}"#;
+
+        let mut record: Record = serde_json::from_str(line).unwrap();
+        assert_eq!(record.to_string(), line);
+
+        record.yank();
+        assert_eq!(
+            record.to_string(),
+            line.replace(r#""yanked":false"#, r#""yanked":true"#)
+        );
+
+        // records built in code are unaffected
+        let built = Record::new("foo", Version::new(0, 1, 0), "checksum");
+        assert_eq!(
+            built.to_string(),
+            r#"{"name":"foo","vers":"0.1.0","deps":[],"cksum":"checksum","features":{},"yanked":false}"#
+        );
+        assert_eq!(
+            serde_json::from_str::<Record>(&built.to_string()).unwrap(),
+            built
+        );
+    }
+
+    #[test_case(0, 2; "log 0.4.24 with transitive features2")]
+    #[test_case(1, 2; "semver 1.0.27 with dep syntax")]
+    #[test_case(2, 1; "semver 1.0.4 without features2")]
+    fn features2_round_trip(line: usize, schema_version: u32) {
+        let original = FIXTURES.lines().nth(line).unwrap();
+        let record: Record = serde_json::from_str(original).unwrap();
+
+        assert_eq!(record.schema_version(), schema_version);
+
+        let original: Value = serde_json::from_str(original).unwrap();
+        let features = original["features"].as_object().unwrap();
+        let features2 = original["features2"].as_object();
+
+        // features are merged into a single map
+        assert_eq!(
+            record.features().len(),
+            features.len() + features2.map_or(0, serde_json::Map::len)
+        );
+
+        // and split back into the same maps when serialised
+        let serialised: Value = serde_json::from_str(&record.to_string()).unwrap();
+        for field in &["features", "features2", "rust_version", "v"] {
+            assert_eq!(serialised[field], original[field], "mismatched {}", field);
+        }
+    }
+
+    #[test]
+    fn unsupported_schema_version() {
+        let mut original: Value = serde_json::from_str(FIXTURES.lines().next().unwrap()).unwrap();
+        original["v"] = 3.into();
+        original["features2"]["future-syntax"] = serde_json::json!(["std", "dep+serde"]);
+        original["features"]["max_level_off"] = serde_json::json!(["dep:sval"]);
+
+        let record: Record = serde_json::from_value(original.clone()).unwrap();
+
+        assert_eq!(record.schema_version(), 3);
+        assert!(!record.features().contains_key("future-syntax"));
+
+        let serialised: Value = serde_json::from_str(&record.to_string()).unwrap();
+        for field in &["features", "features2", "v"] {
+            assert_eq!(serialised[field], original[field], "mismatched {}", field);
+        }
+    }
+
+    #[test]
+    fn split_features() {
+        let serde = Dependency::builder("serde", "1.0".parse().unwrap())
+            .optional(true)
+            .build()
+            .unwrap();
+
+        let record = Record::builder("foo", Version::new(0, 1, 0), "checksum")
+            .dependency(serde)
+            .feature("std", &[] as &[&str])
+            .feature("serde", &["dep:serde"])
+            .feature("full", &["serde", "std"])
+            .build()
+            .unwrap();
+
+        let expected = r#"{"name":"foo","vers":"0.1.0","deps":[{"name":"serde","req":"^1.0","features":[],"optional":true,"default_features":true,"target":null,"kind":"normal"}],"cksum":"checksum","features":{"std":[]},"features2":{"full":["serde","std"],"serde":["dep:serde"]},"yanked":false,"v":2}"#;
+
+        assert_eq!(record.schema_version(), 2);
+        assert_eq!(record.to_string(), expected);
+        assert_eq!(
+            serde_json::from_str::<Record>(expected)
+                .unwrap()
+                .to_string(),
+            expected
+        );
+    }
+
+    #[test_case(None, "1.40.0" => true; "when rust version is not set")]
+    #[test_case(Some("1.56"), "1.56.0" => true; "when rustc is the same version")]
+    #[test_case(Some("1.56"), "1.60.1" => true; "when rustc is newer")]
+    #[test_case(Some("1.56.1"), "1.56.0" => false; "when rustc is older")]
+    #[test_case(Some("1.56"), "1.56.0-nightly" => true; "when rustc is a nightly")]
+    #[test_case(Some("1"), "1.0.0" => true; "when rust version is major only")]
+    fn compatible(rust_version: Option<&str>, rustc: &str) -> bool {
+        let mut builder = Record::builder("foo", Version::new(0, 1, 0), "checksum");
+        if let Some(rust_version) = rust_version {
+            builder = builder.rust_version(rust_version);
+        }
+
+        builder
+            .build()
+            .unwrap()
+            .is_compatible_with(&Version::parse(rustc).unwrap())
+    }
+
+    #[test_case("1.56"; "when minor version")]
+    #[test_case("1.56.1"; "when patch version")]
+    #[test_case("1"; "when major version only")]
+    #[test_case("1." => panics "invalid"; "when minor version is empty")]
+    #[test_case("1.56.1.0" => panics "invalid"; "when there are too many components")]
+    #[test_case("1.56.0-beta" => panics "invalid"; "when pre-release version")]
+    fn rust_version(rust_version: &str) {
+        Record::builder("foo", Version::new(0, 1, 0), "checksum")
+            .rust_version(rust_version)
+            .build()
+            .expect("invalid");
+    }
+
+    #[test]
+    fn artifact_dependency_round_trip() {
+        let line = r#"{"name":"foo","vers":"0.1.0","deps":[{"name":"tool","req":"^0.3","optional":false,"default_features":true,"kind":"build","public":true,"artifact":["bin","cdylib"],"bindep_target":"wasm32-unknown-unknown","lib":true}],"cksum":"checksum"}"#;
+
+        let record: Record = serde_json::from_str(line).unwrap();
+        let dependency = &record.dependencies()[0];
+
+        assert!(dependency.public());
+        assert_eq!(
+            dependency.artifact(),
+            Some(&vec!["bin".to_string(), "cdylib".to_string()])
+        );
+        assert_eq!(
+            dependency.bindep_target(),
+            Some(&"wasm32-unknown-unknown".to_string())
+        );
+        assert!(dependency.lib());
+
+        assert_eq!(record.to_string(), line);
+    }
+
+    #[test_case(&["bin"], None, false; "when artifact is a binary")]
+    #[test_case(&["bin:tool", "staticlib"], Some("x86_64-unknown-linux-gnu"), true; "when artifact has a target and lib")]
+    #[test_case(&["rlib"], None, false => panics "invalid"; "when artifact kind is unknown")]
+    #[test_case(&["bin:"], None, false => panics "invalid"; "when binary name is empty")]
+    #[test_case(&[], None, false => panics "invalid"; "when artifact kinds are empty")]
+    fn artifact(kinds: &[&str], target: Option<&str>, lib: bool) {
+        let mut builder = Dependency::builder("tool", "0.3".parse().unwrap())
+            .artifact(kinds.iter().copied())
+            .lib(lib);
+        if let Some(target) = target {
+            builder = builder.bindep_target(target);
+        }
+
+        builder.build().expect("invalid");
+    }
+
+    #[test_case(true, None => panics "invalid"; "when lib is set without artifact")]
+    #[test_case(false, Some("wasm32-unknown-unknown") => panics "invalid"; "when bindep target is set without artifact")]
+    fn not_artifact(lib: bool, target: Option<&str>) {
+        let mut builder = Dependency::builder("tool", "0.3".parse().unwrap())
+            .lib(lib)
+            .public(true);
+        if let Some(target) = target {
+            builder = builder.bindep_target(target);
+        }
+
+        builder.build().expect("invalid");
+    }
 }
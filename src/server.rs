@@ -0,0 +1,487 @@
+//! A small HTTP server implementing the Cargo registry web API.
+//!
+//! The [`Server`] wraps an [`Index`], and provides-
+//! - the publish, yank and unyank endpoints of the
+//!   [registry web API](https://doc.rust-lang.org/cargo/reference/registry-web-api.html)
+//! - the `config.json` and crate index files of a
+//!   [sparse index](https://doc.rust-lang.org/cargo/reference/registry-index.html#sparse-protocol),
+//!   under the `/index/` path
+//!
+//! The server does not perform any authentication, and does not store the
+//! uploaded `.crate` files. Since the index has no notion of crate owners, the
+//! owners endpoints (`GET`, `PUT` and `DELETE` on
+//! `/api/v1/crates/{crate_name}/owners`) respond with a `501 Not Implemented`
+//! error, which Cargo displays to the user.
+//!
+//! *This module requires the `server` feature.*
+//!
+//! # Example
+//!
+//! ```no_run
+//! use async_std::net::TcpListener;
+//! use crate_index::{server::Server, Index};
+//! # use crate_index::Error;
+//! # async {
+//! let index = Index::open("/index").await?;
+//! let listener = TcpListener::bind("127.0.0.1:8080").await?;
+//!
+//! Server::new(index).listen(listener).await?;
+//! # Ok::<(), Error>(())
+//! # };
+//! ```
+
+use crate::{
+    package, publish,
+    sparse::{content_hash, file_path},
+    Index,
+};
+use async_std::{
+    net::TcpListener,
+    sync::{Arc, Mutex},
+};
+use semver::Version;
+use std::{fmt, io::Error as IoError};
+use tide::{http::mime, Request, Response, StatusCode};
+
+#[derive(Debug, Clone)]
+struct State {
+    index: Arc<Mutex<Index>>,
+}
+
+/// An HTTP server for a crate [`Index`]
+#[derive(Debug)]
+pub struct Server {
+    app: tide::Server<State>,
+}
+
+impl Server {
+    /// Create a new server for the given [`Index`]
+    #[must_use]
+    pub fn new(index: Index) -> Self {
+        let state = State {
+            index: Arc::new(Mutex::new(index)),
+        };
+
+        let mut app = tide::with_state(state);
+
+        app.at("/api/v1/crates/new").put(publish);
+        app.at("/api/v1/crates/:crate_name/:version/yank")
+            .delete(yank);
+        app.at("/api/v1/crates/:crate_name/:version/unyank")
+            .put(unyank);
+        app.at("/api/v1/crates/:crate_name/owners")
+            .get(owners)
+            .put(owners)
+            .delete(owners);
+        app.at("/index/config.json").get(config);
+        app.at("/index/*path").get(index_file);
+
+        Self { app }
+    }
+
+    /// Serve HTTP requests on the given listener.
+    ///
+    /// # Errors
+    ///
+    /// This method returns an error if the listener fails.
+    pub async fn listen(self, listener: TcpListener) -> Result<(), IoError> {
+        self.app.listen(listener).await
+    }
+}
+
+async fn publish(mut req: Request<State>) -> tide::Result {
+    let body = req.body_bytes().await?;
+
+    let (request, crate_file) = match publish::parse_body(&body) {
+        Ok(parts) => parts,
+        Err(e) => return Ok(error_response(StatusCode::BadRequest, e)),
+    };
+
+    let check_sum = package::check_sum(crate_file);
+
+    let mut index = req.state().index.lock().await;
+
+    let record = match request.into_record(check_sum, index.allowed_registries()) {
+        Ok(record) => record,
+        Err(e) => return Ok(error_response(StatusCode::BadRequest, e)),
+    };
+
+    Ok(match index.insert(record).await {
+        Ok(Ok(())) => json_response(&serde_json::json!({
+            "warnings": {
+                "invalid_categories": [],
+                "invalid_badges": [],
+                "other": [],
+            }
+        })),
+        Ok(Err(e)) => error_response(StatusCode::BadRequest, e),
+        Err(e) => error_response(StatusCode::InternalServerError, e),
+    })
+}
+
+async fn yank(req: Request<State>) -> tide::Result {
+    let (crate_name, version) = match crate_version(&req) {
+        Ok(params) => params,
+        Err(e) => return Ok(error_response(StatusCode::BadRequest, e)),
+    };
+
+    let mut index = req.state().index.lock().await;
+
+    Ok(match index.yank(crate_name, &version).await {
+        Ok(Ok(())) => json_response(&serde_json::json!({ "ok": true })),
+        Ok(Err(e)) => error_response(StatusCode::NotFound, e),
+        Err(e) => error_response(StatusCode::InternalServerError, e),
+    })
+}
+
+async fn unyank(req: Request<State>) -> tide::Result {
+    let (crate_name, version) = match crate_version(&req) {
+        Ok(params) => params,
+        Err(e) => return Ok(error_response(StatusCode::BadRequest, e)),
+    };
+
+    let mut index = req.state().index.lock().await;
+
+    Ok(match index.unyank(crate_name, &version).await {
+        Ok(Ok(())) => json_response(&serde_json::json!({ "ok": true })),
+        Ok(Err(e)) => error_response(StatusCode::NotFound, e),
+        Err(e) => error_response(StatusCode::InternalServerError, e),
+    })
+}
+
+async fn owners(_req: Request<State>) -> tide::Result {
+    Ok(error_response(
+        StatusCode::NotImplemented,
+        "crate owners are not supported by this registry",
+    ))
+}
+
+async fn config(req: Request<State>) -> tide::Result {
+    let index = req.state().index.lock().await;
+    let contents = index.tree().config().to_string();
+
+    Ok(sparse_response(&req, contents, mime::JSON))
+}
+
+async fn index_file(req: Request<State>) -> tide::Result {
+    let path = req.param("path")?;
+    let index = req.state().index.lock().await;
+    let tree = index.tree();
+
+    // the last component of the path is the lowercased crate name
+    let crate_name = match path.rsplit('/').next() {
+        Some(name) if file_path(name) == path => tree.find_crate(name).await?,
+        _ => None,
+    };
+
+    Ok(match crate_name {
+        Some(crate_name) => {
+            let contents = tree.file(crate_name).await?.to_string();
+            sparse_response(&req, contents, mime::PLAIN)
+        }
+        None => Response::new(StatusCode::NotFound),
+    })
+}
+
+/// Extract the crate name and version from the request path
+fn crate_version(req: &Request<State>) -> Result<(String, Version), semver::Error> {
+    let crate_name = req.param("crate_name").unwrap_or_default().to_string();
+    let version = Version::parse(req.param("version").unwrap_or_default())?;
+
+    Ok((crate_name, version))
+}
+
+/// Respond with a sparse index file, or 'not modified' if the client already
+/// has the current version.
+fn sparse_response(req: &Request<State>, contents: String, mime: mime::Mime) -> Response {
+    let etag = format!("\"{}\"", content_hash(&contents));
+
+    let not_modified = req
+        .header("If-None-Match")
+        .map_or(false, |values| values.iter().any(|value| value == &etag));
+
+    let mut response = if not_modified {
+        Response::new(StatusCode::NotModified)
+    } else {
+        let mut response = Response::new(StatusCode::Ok);
+        response.set_body(contents);
+        response.set_content_type(mime);
+        response
+    };
+
+    response.insert_header("ETag", etag);
+    response
+}
+
+fn json_response(body: &serde_json::Value) -> Response {
+    let mut response = Response::new(StatusCode::Ok);
+    response.set_body(body.to_string());
+    response.set_content_type(mime::JSON);
+    response
+}
+
+/// An error response, in the format that Cargo will display to the user
+fn error_response(status: StatusCode, detail: impl fmt::Display) -> Response {
+    let body = serde_json::json!({
+        "errors": [{ "detail": detail.to_string() }]
+    });
+
+    let mut response = Response::new(status);
+    response.set_body(body.to_string());
+    response.set_content_type(mime::JSON);
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Server;
+    use crate::Index;
+    use async_std::{
+        io::{ReadExt, WriteExt},
+        net::{SocketAddr, TcpListener, TcpStream},
+    };
+    use std::{convert::TryFrom, fmt::Write};
+
+    async fn start_server() -> (tempfile::TempDir, SocketAddr) {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let download = "https://my-crates-server.com/api/v1/crates/{crate}/{version}/download";
+
+        let index = Index::initialise(temp_dir.path(), download)
+            .identity("dummy username", "dummy@email.com")
+            .build()
+            .await
+            .unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+
+        async_std::task::spawn(Server::new(index).listen(listener));
+
+        (temp_dir, address)
+    }
+
+    /// Make an HTTP request, returning the status code, headers and body
+    async fn request(
+        address: SocketAddr,
+        method: &str,
+        path: &str,
+        headers: &[(&str, &str)],
+        body: &[u8],
+    ) -> (u16, String, String) {
+        let mut stream = TcpStream::connect(address).await.unwrap();
+
+        let mut request = format!(
+            "{} {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\nContent-Length: {}\r\n",
+            method,
+            path,
+            body.len()
+        );
+        for (name, value) in headers {
+            write!(request, "{}: {}\r\n", name, value).unwrap();
+        }
+        request.push_str("\r\n");
+
+        stream.write_all(request.as_bytes()).await.unwrap();
+        stream.write_all(body).await.unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+
+        let (head, body) = response.split_at(response.find("\r\n\r\n").unwrap());
+        let status = head[9..12].parse().unwrap();
+
+        (status, head.to_string(), body[4..].to_string())
+    }
+
+    fn publish_body(name: &str, version: &str) -> Vec<u8> {
+        let metadata = serde_json::json!({
+            "name": name,
+            "vers": version,
+            "deps": [
+                {
+                    "optional": false,
+                    "default_features": true,
+                    "name": "rand",
+                    "features": ["i128_support"],
+                    "version_req": "^0.6",
+                    "target": null,
+                    "kind": "normal",
+                    "registry": null,
+                    "explicit_name_in_toml": "random"
+                }
+            ],
+            "features": {
+                "extras": ["random/simd_support"]
+            },
+            "authors": ["Alice <a@example.com>"],
+            "description": null,
+            "documentation": null,
+            "homepage": null,
+            "readme": null,
+            "readme_file": null,
+            "keywords": [],
+            "categories": [],
+            "license": "MIT",
+            "license_file": null,
+            "repository": null,
+            "badges": {},
+            "links": null
+        })
+        .to_string();
+        let crate_file = b"not really a crate file";
+
+        let mut body = Vec::new();
+        body.extend_from_slice(&u32::try_from(metadata.len()).unwrap().to_le_bytes());
+        body.extend_from_slice(metadata.as_bytes());
+        body.extend_from_slice(&u32::try_from(crate_file.len()).unwrap().to_le_bytes());
+        body.extend_from_slice(crate_file);
+        body
+    }
+
+    #[async_std::test]
+    async fn publish_and_yank() {
+        let (_temp_dir, address) = start_server().await;
+
+        let (status, _, body) = request(
+            address,
+            "PUT",
+            "/api/v1/crates/new",
+            &[],
+            &publish_body("Some-Name", "0.1.0"),
+        )
+        .await;
+        assert_eq!(status, 200, "{}", body);
+        assert!(body.contains("warnings"));
+
+        let (status, _, body) = request(address, "GET", "/index/so/me/some-name", &[], &[]).await;
+        assert_eq!(status, 200);
+        let record: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(record["name"], "Some-Name");
+        assert_eq!(record["deps"][0]["name"], "random");
+        assert_eq!(record["deps"][0]["package"], "rand");
+        assert_eq!(record["deps"][0]["req"], "^0.6");
+
+        let (status, _, body) = request(
+            address,
+            "DELETE",
+            "/api/v1/crates/Some-Name/0.1.0/yank",
+            &[],
+            &[],
+        )
+        .await;
+        assert_eq!(status, 200);
+        assert_eq!(body, r#"{"ok":true}"#);
+
+        let (_, _, body) = request(address, "GET", "/index/so/me/some-name", &[], &[]).await;
+        assert!(body.contains(r#""yanked":true"#));
+
+        // paths which don't match the crate name are not found
+        let (status, _, _) = request(address, "GET", "/index/xx/xx/some-name", &[], &[]).await;
+        assert_eq!(status, 404);
+
+        let (status, _, _) = request(
+            address,
+            "PUT",
+            "/api/v1/crates/Some-Name/0.1.0/unyank",
+            &[],
+            &[],
+        )
+        .await;
+        assert_eq!(status, 200);
+    }
+
+    #[async_std::test]
+    async fn errors() {
+        let (_temp_dir, address) = start_server().await;
+
+        // invalid crate name
+        let (status, _, body) = request(
+            address,
+            "PUT",
+            "/api/v1/crates/new",
+            &[],
+            &publish_body("nul", "0.1.0"),
+        )
+        .await;
+        assert_eq!(status, 400);
+        let errors: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert!(errors["errors"][0]["detail"]
+            .as_str()
+            .unwrap()
+            .contains("invalid"));
+
+        // malformed body
+        let (status, _, body) = request(address, "PUT", "/api/v1/crates/new", &[], b"xx").await;
+        assert_eq!(status, 400);
+        assert!(body.contains("truncated"));
+
+        // crate not found
+        let (status, _, body) = request(
+            address,
+            "DELETE",
+            "/api/v1/crates/other-name/0.1.0/yank",
+            &[],
+            &[],
+        )
+        .await;
+        assert_eq!(status, 404);
+        assert!(body.contains("crate not found"));
+
+        let (status, _, _) = request(address, "GET", "/index/ot/he/other-name", &[], &[]).await;
+        assert_eq!(status, 404);
+
+        // owners aren't supported
+        for method in &["GET", "PUT", "DELETE"] {
+            let body = br#"{"users":["some-user"]}"#;
+            let (status, _, body) = request(
+                address,
+                method,
+                "/api/v1/crates/other-name/owners",
+                &[],
+                body,
+            )
+            .await;
+            assert_eq!(status, 501);
+            let errors: serde_json::Value = serde_json::from_str(&body).unwrap();
+            assert!(errors["errors"][0]["detail"]
+                .as_str()
+                .unwrap()
+                .contains("not supported"));
+        }
+    }
+
+    #[async_std::test]
+    async fn config_and_etag() {
+        let (_temp_dir, address) = start_server().await;
+
+        let (status, head, body) = request(address, "GET", "/index/config.json", &[], &[]).await;
+        assert_eq!(status, 200);
+        let config: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(
+            config["dl"],
+            "https://my-crates-server.com/api/v1/crates/{crate}/{version}/download"
+        );
+
+        let etag = head
+            .lines()
+            .find_map(|line| {
+                let (name, value) = line.split_at(line.find(':')?);
+                if name.eq_ignore_ascii_case("etag") {
+                    Some(value[1..].trim().to_string())
+                } else {
+                    None
+                }
+            })
+            .unwrap();
+
+        let (status, _, _) = request(
+            address,
+            "GET",
+            "/index/config.json",
+            &[("If-None-Match", &etag)],
+            &[],
+        )
+        .await;
+        assert_eq!(status, 304);
+    }
+}
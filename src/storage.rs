@@ -0,0 +1,189 @@
+//! Storage for the `.crate` files which the index points to.
+//!
+//! The index only contains crate metadata. The `.crate` files themselves are
+//! served from the `dl` URL in the index config, and need to be stored
+//! somewhere that URL can reach. The [`Storage`] trait abstracts over where
+//! that is, and [`LocalStorage`] keeps them in a directory on the local
+//! filesystem.
+//!
+//! # Example
+//!
+//! ```no_run
+//! use crate_index::{
+//!     storage::{LocalStorage, Storage},
+//!     Index,
+//! };
+//! # use crate_index::{Error, package, Url};
+//! # async {
+//! # let crate_file: Vec<u8> = Vec::new();
+//! let mut index = Index::open("/index").await?;
+//! let storage = LocalStorage::new("/var/www/crates", "{crate}/{version}/download");
+//!
+//! let registry = Url::parse("https://my-intranet:8080/git/index").unwrap();
+//! let record = package::read(&crate_file, &registry).expect("invalid crate file");
+//!
+//! index
+//!     .insert_with_artifact(record, &crate_file, &storage)
+//!     .await?
+//!     .expect("invalid crate");
+//! # Ok::<(), Error>(())
+//! # };
+//! ```
+
+use crate::{download::substitute, package, Record};
+use async_std::{fs, path::PathBuf};
+use async_trait::async_trait;
+use std::io::{Error as IoError, ErrorKind};
+
+/// A place to keep `.crate` files.
+///
+/// Artifacts are identified by their [`Record`], so that implementations can
+/// lay them out using any of the fields which the `dl` template markers refer
+/// to.
+#[async_trait]
+pub trait Storage: Send + Sync {
+    /// Store the `.crate` file for a [`Record`], replacing any existing file.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if the file cannot be stored.
+    async fn put(&self, record: &Record, crate_file: &[u8]) -> Result<(), IoError>;
+
+    /// Retrieve the `.crate` file for a [`Record`].
+    ///
+    /// Returns `None` if there is no stored file.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if the file exists but cannot be read.
+    async fn get(&self, record: &Record) -> Result<Option<Vec<u8>>, IoError>;
+
+    /// Remove the `.crate` file for a [`Record`].
+    ///
+    /// Deleting a file which isn't stored is not an error.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if the file exists but cannot be removed.
+    async fn delete(&self, record: &Record) -> Result<(), IoError>;
+
+    /// Check that the `.crate` file for a [`Record`] is stored, and that its
+    /// checksum matches the record.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if the file exists but cannot be read.
+    async fn verify(&self, record: &Record) -> Result<bool, IoError> {
+        Ok(match self.get(record).await? {
+            Some(crate_file) => &package::check_sum(&crate_file) == record.check_sum(),
+            None => false,
+        })
+    }
+}
+
+/// [`Storage`] in a directory on the local filesystem.
+///
+/// The location of each file, relative to the root directory, is given by a
+/// layout template using the same markers as the `dl` template in the index
+/// config (`{crate}`, `{version}`, `{prefix}`, `{lowerprefix}` and
+/// `{sha256-checksum}`). Using the path component of the `dl` template as the
+/// layout means the directory can be served directly by a static file server.
+#[derive(Debug, Clone)]
+pub struct LocalStorage {
+    root: PathBuf,
+    layout: String,
+}
+
+impl LocalStorage {
+    /// Create a new local storage at the given root directory, using the given
+    /// layout template.
+    ///
+    /// The directory is created when the first file is stored.
+    pub fn new(root: impl Into<PathBuf>, layout: impl Into<String>) -> Self {
+        let root = root.into();
+        let layout = layout.into();
+        Self { root, layout }
+    }
+
+    /// The location on the filesystem of the root of the storage
+    #[must_use]
+    pub fn root(&self) -> &PathBuf {
+        &self.root
+    }
+
+    /// The location on the filesystem of the `.crate` file for a [`Record`]
+    #[must_use]
+    pub fn path(&self, record: &Record) -> PathBuf {
+        let relative = substitute(&self.layout, record);
+        self.root.join(relative.trim_start_matches('/'))
+    }
+}
+
+#[async_trait]
+impl Storage for LocalStorage {
+    async fn put(&self, record: &Record, crate_file: &[u8]) -> Result<(), IoError> {
+        let path = self.path(record);
+        fs::create_dir_all(path.parent().unwrap()).await?;
+        fs::write(path, crate_file).await
+    }
+
+    async fn get(&self, record: &Record) -> Result<Option<Vec<u8>>, IoError> {
+        match fs::read(self.path(record)).await {
+            Ok(crate_file) => Ok(Some(crate_file)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn delete(&self, record: &Record) -> Result<(), IoError> {
+        match fs::remove_file(self.path(record)).await {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LocalStorage, Storage};
+    use crate::{package, Record};
+    use semver::Version;
+
+    #[async_std::test]
+    async fn put_get_delete() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let storage = LocalStorage::new(temp_dir.path(), "{prefix}/{crate}/{version}.crate");
+
+        let crate_file = b"crate file contents";
+        let record = Record::new(
+            "some-crate",
+            Version::new(0, 1, 0),
+            package::check_sum(crate_file),
+        );
+
+        assert_eq!(storage.get(&record).await.unwrap(), None);
+        assert!(!storage.verify(&record).await.unwrap());
+
+        storage.put(&record, crate_file).await.unwrap();
+
+        assert!(temp_dir
+            .path()
+            .join("so/me/some-crate/0.1.0.crate")
+            .exists());
+        assert_eq!(
+            storage.get(&record).await.unwrap(),
+            Some(crate_file.to_vec())
+        );
+        assert!(storage.verify(&record).await.unwrap());
+
+        // a corrupted file fails verification
+        storage.put(&record, b"corrupted").await.unwrap();
+        assert!(!storage.verify(&record).await.unwrap());
+
+        storage.delete(&record).await.unwrap();
+        assert_eq!(storage.get(&record).await.unwrap(), None);
+
+        // deleting a missing file is not an error
+        storage.delete(&record).await.unwrap();
+    }
+}
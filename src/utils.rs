@@ -1,36 +1,219 @@
 use async_std::{
     fs::{read_dir, DirEntry},
     path::PathBuf,
+    task,
 };
 use futures_util::{
     future, stream,
     stream::{Stream, StreamExt, TryStreamExt},
 };
-use std::{collections::HashSet, io::Error as IoError};
-
-pub async fn crate_names(path: impl Into<PathBuf>) -> Result<HashSet<String>, IoError> {
-    fn is_hidden(entry: &DirEntry) -> bool {
-        entry
-            .file_name()
-            .to_str()
-            .map_or(false, |s| s.starts_with('.'))
+use std::{
+    collections::HashSet,
+    fs::{File, OpenOptions},
+    io::{Error as IoError, ErrorKind, Write},
+    path::{Path, PathBuf as StdPathBuf},
+};
+
+/// The names of all of the files in an index which might be index files.
+///
+/// See [`index_files`] for the files which are skipped.
+pub async fn crate_names(root: impl Into<PathBuf>) -> Result<HashSet<String>, IoError> {
+    Ok(index_files(root)
+        .await?
+        .iter()
+        .filter_map(|path| path.file_name())
+        .map(|name| name.to_string_lossy().into_owned())
+        .collect())
+}
+
+/// The paths of all of the files in an index which might be index files.
+///
+/// This skips hidden directories (such as `.git`) and hidden files, as well as
+/// the config file. The paths are sorted.
+pub async fn index_files(root: impl Into<PathBuf>) -> Result<Vec<PathBuf>, IoError> {
+    let root = root.into();
+    let config = root.join("config.json");
+
+    let mut paths: Vec<PathBuf> = walk_dir(root.clone())
+        .map_ok(|entry| entry.path())
+        .try_filter(|path| {
+            let hidden = path.strip_prefix(&root).map_or(true, |relative| {
+                relative
+                    .components()
+                    .any(|component| component.as_os_str().to_string_lossy().starts_with('.'))
+            });
+            future::ready(!hidden && path != &config)
+        })
+        .try_collect()
+        .await?;
+
+    paths.sort();
+    Ok(paths)
+}
+
+/// Replace the contents of a file, such that the file is never left partly
+/// written.
+///
+/// The contents are written to a hidden temporary file in the same directory,
+/// which is synced to disk and then renamed over the original file. If
+/// anything fails before the rename, the original file is untouched.
+pub async fn write_atomic(
+    path: impl Into<PathBuf>,
+    contents: impl Into<Vec<u8>>,
+) -> Result<(), IoError> {
+    let path = path.into();
+    let contents = contents.into();
+
+    task::spawn_blocking(move || {
+        replace_file(path.as_ref(), &contents, |file, contents| {
+            file.write_all(contents)
+        })
+    })
+    .await
+}
+
+/// Replace the contents of several files, such that either every file is
+/// replaced or none of them are.
+///
+/// Each file is written to a temporary file as in [`write_atomic`], and the
+/// temporary files are only renamed over the originals once all of them have
+/// been written. If a rename fails, the files which were already replaced are
+/// restored.
+pub async fn write_all_atomic(files: Vec<(PathBuf, Vec<u8>)>) -> Result<(), IoError> {
+    task::spawn_blocking(move || {
+        let files: Vec<(&Path, &[u8])> = files
+            .iter()
+            .map(|(path, contents)| (path.as_ref(), contents.as_slice()))
+            .collect();
+        replace_files(&files, Write::write_all)
+    })
+    .await
+}
+
+/// Replace the contents of a file using the given function to write the
+/// temporary file.
+fn replace_file(
+    path: &Path,
+    contents: &[u8],
+    write: impl FnOnce(&mut File, &[u8]) -> Result<(), IoError>,
+) -> Result<(), IoError> {
+    let temp_path = stage(path, contents, write)?;
+
+    if let Err(e) = std::fs::rename(&temp_path, path) {
+        let _ = std::fs::remove_file(&temp_path);
+        return Err(e);
+    }
+
+    sync_dir(parent(path))
+}
+
+/// Replace the contents of several files using the given function to write
+/// the temporary files.
+fn replace_files(
+    files: &[(&Path, &[u8])],
+    write: impl Fn(&mut File, &[u8]) -> Result<(), IoError>,
+) -> Result<(), IoError> {
+    let remove_all = |temp_paths: &[StdPathBuf]| {
+        for temp_path in temp_paths {
+            let _ = std::fs::remove_file(temp_path);
+        }
+    };
+
+    let mut temp_paths = Vec::new();
+    let mut originals = Vec::new();
+    for (path, contents) in files {
+        let staged =
+            read_existing(path).and_then(|original| Ok((original, stage(path, contents, &write)?)));
+
+        match staged {
+            Ok((original, temp_path)) => {
+                originals.push(original);
+                temp_paths.push(temp_path);
+            }
+            Err(e) => {
+                remove_all(&temp_paths);
+                return Err(e);
+            }
+        }
     }
 
-    fn is_special_file(entry: &DirEntry) -> bool {
-        let special_files = vec!["config.json"];
+    for (index, ((path, _), temp_path)) in files.iter().zip(&temp_paths).enumerate() {
+        if let Err(e) = std::fs::rename(temp_path, path) {
+            remove_all(&temp_paths[index..]);
+            for ((path, _), original) in files[..index].iter().zip(&originals) {
+                let _ = match original {
+                    Some(original) => replace_file(path, original, Write::write_all),
+                    None => std::fs::remove_file(path),
+                };
+            }
+            return Err(e);
+        }
+    }
 
-        entry
-            .file_name()
-            .to_str()
-            .map_or(false, |s| special_files.contains(&s))
+    for (path, _) in files {
+        sync_dir(parent(path))?;
     }
 
-    walk_dir(path)
-        .try_filter(|entry| future::ready(!is_hidden(entry)))
-        .try_filter(|entry| future::ready(!is_special_file(entry)))
-        .map_ok(|entry| entry.file_name().to_string_lossy().into_owned())
-        .try_collect()
-        .await
+    Ok(())
+}
+
+/// Write the contents of a file to a hidden temporary file next to it, and
+/// sync it to disk.
+///
+/// Returns the path of the temporary file. If anything fails, the temporary
+/// file is removed.
+fn stage(
+    path: &Path,
+    contents: &[u8],
+    write: impl FnOnce(&mut File, &[u8]) -> Result<(), IoError>,
+) -> Result<StdPathBuf, IoError> {
+    let temp_path = parent(path).join(format!(
+        ".{}.tmp",
+        path.file_name().unwrap_or_default().to_string_lossy()
+    ));
+
+    let result = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&temp_path)
+        .and_then(|mut file| {
+            write(&mut file, contents)?;
+            file.sync_all()
+        });
+
+    match result {
+        Ok(()) => Ok(temp_path),
+        Err(e) => {
+            let _ = std::fs::remove_file(&temp_path);
+            Err(e)
+        }
+    }
+}
+
+/// Read the contents of a file, or `None` if it doesn't exist
+fn read_existing(path: &Path) -> Result<Option<Vec<u8>>, IoError> {
+    match std::fs::read(path) {
+        Ok(contents) => Ok(Some(contents)),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+fn parent(path: &Path) -> &Path {
+    path.parent().unwrap_or_else(|| Path::new("."))
+}
+
+/// Sync a directory, so that a rename within it survives a crash
+#[cfg(unix)]
+fn sync_dir(path: &Path) -> Result<(), IoError> {
+    File::open(path)?.sync_all()
+}
+
+#[cfg(not(unix))]
+#[allow(clippy::unnecessary_wraps)]
+fn sync_dir(_path: &Path) -> Result<(), IoError> {
+    Ok(())
 }
 
 fn walk_dir(
@@ -69,8 +252,15 @@ fn walk_dir(
 
 #[cfg(test)]
 mod tests {
-    use super::crate_names;
-    use std::{collections::HashSet, fs::File};
+    use super::{
+        crate_names, index_files, replace_file, replace_files, write_all_atomic, write_atomic,
+    };
+    use std::{
+        collections::HashSet,
+        fs::File,
+        io::{Error as IoError, ErrorKind, Write},
+        path::Path,
+    };
 
     #[test]
     fn with_flat_directory() {
@@ -137,4 +327,112 @@ mod tests {
 
         assert_eq!(file_names, result);
     }
+
+    #[test]
+    fn index_files_skips_hidden_directories() {
+        let temp_dir = tempfile::tempdir().unwrap();
+
+        for path in &[
+            "al/ph/alpha",
+            ".git/HEAD",
+            ".hidden",
+            "config.json",
+            "README.md",
+        ] {
+            let path = temp_dir.path().join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            File::create(path).unwrap();
+        }
+
+        let result = async_std::task::block_on(index_files(temp_dir.path())).unwrap();
+
+        let expected: Vec<async_std::path::PathBuf> = vec![
+            temp_dir.path().join("README.md").into(),
+            temp_dir.path().join("al/ph/alpha").into(),
+        ];
+        assert_eq!(result, expected);
+    }
+
+    #[test]
+    fn write_atomic_replaces_contents() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("alpha");
+        std::fs::write(&path, "old contents").unwrap();
+
+        async_std::task::block_on(write_atomic(path.clone(), "new")).unwrap();
+
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "new");
+        assert_eq!(std::fs::read_dir(temp_dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn failed_write_keeps_old_contents() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("alpha");
+        std::fs::write(&path, "old contents").unwrap();
+
+        // the writer fails part of the way through, like a full disk
+        let error = replace_file(&path, b"new contents", |file, contents| {
+            file.write_all(&contents[..3])?;
+            Err(IoError::new(ErrorKind::Other, "no space left on device"))
+        })
+        .unwrap_err();
+
+        assert_eq!(error.to_string(), "no space left on device");
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "old contents");
+
+        // the temporary file is cleaned up
+        assert_eq!(std::fs::read_dir(temp_dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn write_all_atomic_replaces_and_creates() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let alpha = temp_dir.path().join("alpha");
+        let beta = temp_dir.path().join("beta");
+        std::fs::write(&alpha, "old contents").unwrap();
+
+        let files = vec![
+            (alpha.clone().into(), b"new".to_vec()),
+            (beta.clone().into(), b"created".to_vec()),
+        ];
+        async_std::task::block_on(write_all_atomic(files)).unwrap();
+
+        assert_eq!(std::fs::read_to_string(&alpha).unwrap(), "new");
+        assert_eq!(std::fs::read_to_string(&beta).unwrap(), "created");
+        assert_eq!(std::fs::read_dir(temp_dir.path()).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn failed_write_keeps_old_contents_of_all_files() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let alpha = temp_dir.path().join("alpha");
+        let beta = temp_dir.path().join("beta");
+        let gamma = temp_dir.path().join("gamma");
+        std::fs::write(&alpha, "old contents").unwrap();
+        std::fs::write(&gamma, "old contents").unwrap();
+
+        // the second file fails part of the way through, like a full disk
+        let files: &[(&Path, &[u8])] = &[
+            (&alpha, b"new contents"),
+            (&beta, b"no space"),
+            (&gamma, b"new contents"),
+        ];
+        let error = replace_files(files, |file, contents| {
+            if contents == b"no space" {
+                file.write_all(&contents[..3])?;
+                return Err(IoError::new(ErrorKind::Other, "no space left on device"));
+            }
+            file.write_all(contents)
+        })
+        .unwrap_err();
+
+        assert_eq!(error.to_string(), "no space left on device");
+        assert_eq!(std::fs::read_to_string(&alpha).unwrap(), "old contents");
+        assert!(!beta.exists());
+        assert_eq!(std::fs::read_to_string(&gamma).unwrap(), "old contents");
+
+        // the temporary files are cleaned up
+        assert_eq!(std::fs::read_dir(temp_dir.path()).unwrap().count(), 2);
+    }
 }
@@ -1,8 +1,10 @@
 //! crate record validation
 
+use crate::record::Dependency;
 use lazy_static::lazy_static;
 use regex::Regex;
 use semver::{Version, VersionReq};
+use url::Url;
 
 /// The error returned when a crate record is invalid
 #[derive(thiserror::Error, Debug)]
@@ -24,6 +26,58 @@ pub enum Error {
         /// the reason the crate name is invalid
         reason: String,
     },
+
+    /// A dependency is from a registry which is not allowed
+    #[error("Dependency '{dependency}' is from a registry which is not allowed ({registry})")]
+    Registry {
+        /// the name of the dependency
+        dependency: String,
+        /// the registry of the dependency
+        registry: Url,
+    },
+
+    /// A dependency from this registry cannot be satisfied by any non-yanked
+    /// version in the index
+    #[error(
+        "Dependency '{dependency}' ({required}) cannot be satisfied by any version in the index"
+    )]
+    UnresolvedDependency {
+        /// the name of the dependency
+        dependency: String,
+        /// the version requirement of the dependency
+        required: VersionReq,
+    },
+
+    /// A feature enables something which is neither a feature nor a dependency
+    #[error("Feature '{feature}' enables '{enables}', which is not a feature or dependency")]
+    Feature {
+        /// the name of the feature
+        feature: String,
+        /// the invalid entry
+        enables: String,
+    },
+
+    /// The minimum supported Rust version is not a valid version
+    #[error("Invalid rust version '{0}' (expected a version such as '1', '1.56' or '1.56.1')")]
+    RustVersion(String),
+
+    /// The artifact fields of a dependency are not valid
+    #[error("Dependency '{dependency}' has an invalid artifact: {reason}")]
+    Artifact {
+        /// the name of the dependency
+        dependency: String,
+        /// the reason the artifact is invalid
+        reason: String,
+    },
+
+    /// The checksum of a `.crate` file doesn't match the record
+    #[error("Checksum mismatch (expected: {expected}, given: {given})")]
+    CheckSum {
+        /// the checksum in the record
+        expected: String,
+        /// the checksum of the given `.crate` file
+        given: String,
+    },
 }
 
 impl Error {
@@ -35,6 +89,44 @@ impl Error {
         Self::Version { required, given }
     }
 
+    pub(crate) fn registry(dependency: impl Into<String>, registry: Url) -> Self {
+        Self::Registry {
+            dependency: dependency.into(),
+            registry,
+        }
+    }
+
+    pub(crate) fn unresolved_dependency(
+        dependency: impl Into<String>,
+        required: VersionReq,
+    ) -> Self {
+        Self::UnresolvedDependency {
+            dependency: dependency.into(),
+            required,
+        }
+    }
+
+    pub(crate) fn feature(feature: impl Into<String>, enables: impl Into<String>) -> Self {
+        Self::Feature {
+            feature: feature.into(),
+            enables: enables.into(),
+        }
+    }
+
+    pub(crate) fn artifact(dependency: impl Into<String>, reason: impl Into<String>) -> Self {
+        Self::Artifact {
+            dependency: dependency.into(),
+            reason: reason.into(),
+        }
+    }
+
+    pub(crate) fn check_sum(expected: impl Into<String>, given: impl Into<String>) -> Self {
+        Self::CheckSum {
+            expected: expected.into(),
+            given: given.into(),
+        }
+    }
+
     pub(crate) fn invalid_name(name: impl Into<String>, reason: impl Into<String>) -> Self {
         Self::InvalidName {
             name: name.into(),
@@ -44,7 +136,7 @@ impl Error {
 }
 
 fn is_allowed_name(name: &str) -> bool {
-    let disallowed_names = vec!["nul"];
+    let disallowed_names = ["nul"];
 
     !disallowed_names.contains(&name)
 }
@@ -68,3 +160,63 @@ pub(crate) fn name(name: &str) -> Result<(), Error> {
         Ok(())
     }
 }
+
+pub(crate) fn rust_version(rust_version: &str) -> Result<(), Error> {
+    lazy_static! {
+        static ref REGEX: Regex = Regex::new(r"^\d+(\.\d+(\.\d+)?)?$").unwrap();
+    }
+
+    if REGEX.is_match(rust_version) {
+        Ok(())
+    } else {
+        Err(Error::RustVersion(rust_version.to_string()))
+    }
+}
+
+pub(crate) fn artifact(dependency: &Dependency) -> Result<(), Error> {
+    let kinds = match &dependency.artifact {
+        Some(kinds) => kinds,
+        None if dependency.lib => {
+            return Err(Error::artifact(
+                &dependency.name,
+                "'lib' is only valid for artifact dependencies",
+            ))
+        }
+        None if dependency.bindep_target.is_some() => {
+            return Err(Error::artifact(
+                &dependency.name,
+                "'bindep_target' is only valid for artifact dependencies",
+            ))
+        }
+        None => return Ok(()),
+    };
+
+    if kinds.is_empty() {
+        return Err(Error::artifact(
+            &dependency.name,
+            "at least one artifact kind is required",
+        ));
+    }
+
+    for kind in kinds {
+        let valid = match kind.as_str() {
+            "bin" | "cdylib" | "staticlib" => true,
+            kind => kind
+                .strip_prefix("bin:")
+                .map_or(false, |name| !name.is_empty()),
+        };
+
+        if !valid {
+            return Err(Error::artifact(
+                &dependency.name,
+                format!(
+                    "unknown artifact kind '{}' (expected 'bin', 'cdylib', 'staticlib', or \
+                     'bin:<name>')",
+                    kind
+                ),
+            ));
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,146 @@
+//! Tests for locking an index against other processes.
+//!
+//! The lock is held by a second process, which is this binary run again with
+//! the arguments `hold <root> [shared]`. It takes the lock, prints `locked`,
+//! and holds the lock until its stdin is closed.
+
+use async_std::task;
+use crate_index::{
+    tree::{Error, LockOptions, Tree},
+    Record, Version,
+};
+use std::{
+    io::{BufRead, BufReader, Read},
+    path::Path,
+    process::{Child, Command, Stdio},
+    time::Duration,
+};
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+
+    if args.first().map(String::as_str) == Some("hold") {
+        hold(
+            Path::new(&args[1]),
+            args.get(2).map(String::as_str) == Some("shared"),
+        );
+        return;
+    }
+
+    let tests: &[(&str, fn())] = &[
+        ("exclusive_lock", exclusive_lock),
+        ("shared_lock", shared_lock),
+        ("wait_for_lock", wait_for_lock),
+    ];
+    for (name, test) in tests {
+        test();
+        println!("test {} ... ok", name);
+    }
+}
+
+/// Take a lock on the index at the given root, and hold it until stdin is
+/// closed.
+fn hold(root: &Path, shared: bool) {
+    let options = if shared {
+        LockOptions::shared()
+    } else {
+        LockOptions::exclusive()
+    };
+
+    let _tree = task::block_on(Tree::open_with(root, options)).unwrap();
+    println!("locked");
+
+    let mut buffer = Vec::new();
+    std::io::stdin().read_to_end(&mut buffer).unwrap();
+}
+
+/// Spawn a process which holds a lock on the given root, and wait until the
+/// lock is taken.
+fn spawn_holder(root: &Path, shared: bool) -> Child {
+    let mut command = Command::new(std::env::current_exe().unwrap());
+    command
+        .arg("hold")
+        .arg(root)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped());
+    if shared {
+        command.arg("shared");
+    }
+
+    let mut child = command.spawn().unwrap();
+    let mut stdout = BufReader::new(child.stdout.take().unwrap());
+    let mut line = String::new();
+    stdout.read_line(&mut line).unwrap();
+    if line.trim_end() != "locked" {
+        child.wait().unwrap();
+        panic!("lock holder exited without taking the lock");
+    }
+    child
+}
+
+/// Close the stdin of the lock holder, so that it releases the lock
+fn release(mut child: Child) {
+    drop(child.stdin.take());
+    assert!(child.wait().unwrap().success());
+}
+
+/// Create an empty index, and return the directory which contains it
+fn index() -> tempfile::TempDir {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let download = "https://my-crates-server.com/api/v1/crates/{crate}/{version}/download";
+    task::block_on(Tree::initialise(temp_dir.path(), download).build()).unwrap();
+    temp_dir
+}
+
+fn exclusive_lock() {
+    let temp_dir = index();
+    let root = temp_dir.path();
+
+    let holder = spawn_holder(root, false);
+
+    for options in &[LockOptions::exclusive(), LockOptions::shared()] {
+        match task::block_on(Tree::open_with(root, *options)) {
+            Err(Error::Locked(e)) => assert_eq!(e.root().as_os_str(), root.as_os_str()),
+            result => panic!("expected a locked error, got {:?}", result),
+        }
+    }
+
+    release(holder);
+
+    task::block_on(Tree::open(root)).unwrap();
+}
+
+fn shared_lock() {
+    let temp_dir = index();
+    let root = temp_dir.path();
+
+    let holder = spawn_holder(root, true);
+
+    let mut tree = task::block_on(Tree::open_with(root, LockOptions::shared())).unwrap();
+    let record = Record::new("some-name", Version::new(0, 1, 0), "checksum");
+    match task::block_on(tree.insert(record)) {
+        Err(Error::ReadOnly) => (),
+        result => panic!("expected a read-only error, got {:?}", result),
+    }
+
+    assert!(task::block_on(Tree::open(root)).is_err());
+
+    release(holder);
+}
+
+fn wait_for_lock() {
+    let temp_dir = index();
+    let root = temp_dir.path();
+
+    let holder = spawn_holder(root, false);
+
+    let releaser = std::thread::spawn(move || {
+        std::thread::sleep(Duration::from_millis(200));
+        release(holder);
+    });
+
+    let options = LockOptions::exclusive().timeout(Duration::from_secs(30));
+    task::block_on(Tree::open_with(root, options)).unwrap();
+
+    releaser.join().unwrap();
+}